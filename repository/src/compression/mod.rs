@@ -0,0 +1,131 @@
+use failure::Error;
+use flate2::Compression;
+use flate2::read::{DeflateDecoder, DeflateEncoder as DeflateReadEncoder};
+use flate2::write::DeflateEncoder;
+use pb::file::CompressionType;
+use std::io::{self, Read, Take, Write};
+
+/// Upper bound of the content of a file that is read into memory, streamed contents have no limit.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 1024 * 1024 * 1024;
+
+pub trait Compressor {
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, Error>;
+    /// Fails if the decompressed data is longer than `limit`.
+    fn decompress(&self, input: &[u8], limit: u64) -> Result<Vec<u8>, Error>;
+    /// Wraps the input so that reading from the result yields the compressed data.
+    fn compressing_reader<'a, R: Read + 'a>(&self, input: R) -> Box<dyn Read + 'a>;
+    /// Wraps the compressed input so that reading from the result yields the decompressed data.
+    fn decompressing_reader<'a, R: Read + 'a>(&self, input: R) -> Box<dyn Read + 'a>;
+}
+
+/// Stops a reader that yields more than `limit` bytes, e.g. a decompressor of a crafted deflate stream.
+/// Reading fails with `InvalidData` once more than `limit` bytes were read.
+pub(crate) struct LimitedReader<R> {
+    inner: Take<R>,
+    limit: u64,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub(crate) fn new(inner: R, limit: u64) -> Self {
+        LimitedReader { inner: inner.take(limit.saturating_add(1)), limit }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if self.inner.limit() == 0 {
+            let message = format!("data exceeds {} bytes", self.limit);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(read)
+    }
+}
+
+impl Compressor for CompressionType {
+    fn compress(&self, input: &[u8]) -> Result<Vec<u8>, Error> {
+        match *self {
+            CompressionType::DeflateZip => {
+                let mut encoder = DeflateEncoder::new(Vec::with_capacity(input.len() / 2), Compression::default());
+                encoder.write_all(input)?;
+                Ok(encoder.finish()?)
+            }
+            CompressionType::None => Ok(input.to_vec()),
+        }
+    }
+
+    fn decompress(&self, input: &[u8], limit: u64) -> Result<Vec<u8>, Error> {
+        match *self {
+            CompressionType::DeflateZip => {
+                let mut output = Vec::with_capacity(input.len() * 2);
+                LimitedReader::new(DeflateDecoder::new(input), limit).read_to_end(&mut output)?;
+                Ok(output)
+            }
+            CompressionType::None => Ok(input.to_vec()),
+        }
+    }
+
+    fn compressing_reader<'a, R: Read + 'a>(&self, input: R) -> Box<dyn Read + 'a> {
+        match *self {
+            CompressionType::DeflateZip => Box::new(DeflateReadEncoder::new(input, Compression::default())),
+            CompressionType::None => Box::new(input),
+        }
+    }
+
+    fn decompressing_reader<'a, R: Read + 'a>(&self, input: R) -> Box<dyn Read + 'a> {
+        match *self {
+            CompressionType::DeflateZip => Box::new(DeflateDecoder::new(input)),
+            CompressionType::None => Box::new(input),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_deflate_roundtrip() {
+        let input = "Some highly compressible text. ".repeat(100);
+        let compressed = CompressionType::DeflateZip.compress(input.as_bytes()).unwrap();
+        assert!(compressed.len() < input.len() / 10);
+        assert_eq!(input.as_bytes(), CompressionType::DeflateZip.decompress(&compressed, MAX_DECOMPRESSED_SIZE).unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_none_roundtrip() {
+        let input = b"already compressed";
+        let compressed = CompressionType::None.compress(input).unwrap();
+        assert_eq!(input.as_ref(), compressed.as_slice());
+        assert_eq!(input.as_ref(), CompressionType::None.decompress(&compressed, MAX_DECOMPRESSED_SIZE).unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_streaming_matches_buffered() {
+        let input = "Some highly compressible text. ".repeat(100);
+        let mut compressed = Vec::new();
+        CompressionType::DeflateZip.compressing_reader(input.as_bytes()).read_to_end(&mut compressed).unwrap();
+        assert_eq!(input.as_bytes(), CompressionType::DeflateZip.decompress(&compressed, MAX_DECOMPRESSED_SIZE).unwrap().as_slice());
+
+        let mut decompressed = Vec::new();
+        CompressionType::DeflateZip.decompressing_reader(compressed.as_slice()).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(input.as_bytes(), decompressed.as_slice());
+    }
+
+    #[test]
+    fn test_deflate_invalid_input() {
+        assert!(CompressionType::DeflateZip.decompress(&[0xff; 16], MAX_DECOMPRESSED_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_deflate_limit() {
+        let input = vec![0u8; 100 * 1024];
+        let compressed = CompressionType::DeflateZip.compress(&input).unwrap();
+        assert_eq!(input, CompressionType::DeflateZip.decompress(&compressed, input.len() as u64).unwrap());
+        assert!(CompressionType::DeflateZip.decompress(&compressed, input.len() as u64 - 1).is_err());
+
+        let mut decompressed = Vec::new();
+        let mut reader = LimitedReader::new(CompressionType::DeflateZip.decompressing_reader(compressed.as_slice()), 1024);
+        assert_eq!(io::ErrorKind::InvalidData, reader.read_to_end(&mut decompressed).unwrap_err().kind());
+    }
+}
@@ -1,40 +1,56 @@
 use chacha20_poly1305_aead::{decrypt, encrypt};
 use error::ErrorKind;
 use failure::Error;
+use pb::file::{EncryptionType, PasswordHashParameters, PasswordHashType};
 use std::ops::Deref;
+use zeroize::Zeroize;
+pub use self::secret::SecretVec;
+pub use self::stream::{DecryptingReader, EncryptingWriter};
+
+pub mod secret;
+pub mod stream;
 
 pub type Nonce = [u8];
 pub type AAD = [u8];
 pub type Salt = [u8];
 pub type VerificationTag = [u8];
 pub type VerificationTagVec = Vec<u8>;
-pub type PlaintextVec = Vec<u8>;
+pub type PlaintextVec = SecretVec;
 pub type CipherTextVec = Vec<u8>;
 pub type Plaintext = [u8];
 pub type CipherText = [u8];
 
-#[derive(Clone)]
+/// Nonce length of ChaCha20-Poly1305 and AES-256-GCM
+const NONCE_LENGTH: usize = 12;
+/// Nonce length of XChaCha20-Poly1305, long enough to pick nonces at random without worrying about collisions
+const XNONCE_LENGTH: usize = 24;
+pub const SALT_LENGTH: usize = 32;
+pub const KEY_LENGTH: usize = 32;
+pub const TAG_LENGTH: usize = 16;
+
+/// Keys are wiped from memory when dropped, see `SecretVec`.
+#[derive(Clone, Debug)]
 pub struct HashedPw {
-    pub content: Vec<u8>
+    pub content: SecretVec
 }
 
 #[derive(Clone, Debug)]
 pub struct DoubleHashedPw {
-    pub content: Vec<u8>
+    pub content: SecretVec
 }
 
 impl Deref for HashedPw {
-    type Target = Vec<u8>;
+    type Target = SecretVec;
 
-    fn deref(&self) -> &Vec<u8> {
+    fn deref(&self) -> &SecretVec {
         &self.content
     }
 }
 
 impl Deref for DoubleHashedPw {
-    type Target = Vec<u8>;
+    type Target = SecretVec;
 
-    fn deref(&self) -> &Vec<u8> {
+    fn deref(&self) -> &SecretVec {
         &self.content
     }
 }
@@ -42,7 +58,7 @@ impl Deref for DoubleHashedPw {
 impl<'a> From<&'a [u8]> for HashedPw {
     fn from(data: &'a [u8]) -> Self {
         HashedPw {
-            content: Vec::from(data),
+            content: SecretVec::from(data),
         }
     }
 }
@@ -51,7 +67,7 @@ impl<'a> From<&'a [u8]> for HashedPw {
 impl<'a> From<&'a [u8]> for DoubleHashedPw {
     fn from(data: &'a [u8]) -> Self {
         DoubleHashedPw {
-            content: Vec::from(data),
+            content: SecretVec::from(data),
         }
     }
 }
@@ -59,11 +75,47 @@ impl<'a> From<&'a [u8]> for DoubleHashedPw {
 pub trait DeEncrypter {
     fn encrypt(&self, key: &HashedPw, nonce: &Nonce, aad: &AAD, input: &Plaintext) -> Result<(CipherTextVec, VerificationTagVec), Error>;
     fn decrypt(&self, key: &HashedPw, nonce: &Nonce, aad: &AAD, tag: &VerificationTag, input: &CipherText) -> Result<PlaintextVec, Error>;
+    /// Length of the nonces `encrypt` and `decrypt` expect, new nonces have to be created with this length.
+    fn nonce_length(&self) -> usize;
 }
 
 pub trait Hasher {
-    fn hash_pw(&self, bytes: &Plaintext, salt: &Salt) -> HashedPw;
-    fn double_hash_pw(&self, bytes: &Plaintext, salt: &Salt) -> DoubleHashedPw;
+    fn hash_pw(&self, bytes: &Plaintext, salt: &Salt) -> Result<HashedPw, Error>;
+    fn double_hash_pw(&self, bytes: &Plaintext, salt: &Salt) -> Result<DoubleHashedPw, Error>;
+}
+
+/// Password hash algorithm together with its cost parameters as stored in the repository file.
+/// Repositories created before the parameters were stored have none and use the former fixed settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PasswordHasher {
+    pub hash_type: PasswordHashType,
+    pub parameters: Option<PasswordHashParameters>,
+}
+
+impl PasswordHasher {
+    pub fn new(hash_type: PasswordHashType, parameters: PasswordHashParameters) -> Self {
+        PasswordHasher { hash_type, parameters: Some(parameters) }
+    }
+}
+
+/// Parameters used for new repositories.
+/// For Argon2 these are iterations, memory costs in KiB and lanes,
+/// for scrypt the iterations are log2(N), the memory costs the block size r and the parallelism p.
+pub fn default_hash_parameters(hash_type: PasswordHashType) -> PasswordHashParameters {
+    match hash_type {
+        PasswordHashType::Argon2i | PasswordHashType::Argon2id => PasswordHashParameters { iterations: 3, memory_costs: 65536, parallelism: 4 },
+        PasswordHashType::SCrypt => PasswordHashParameters { iterations: 15, memory_costs: 8, parallelism: 1 },
+    }
+}
+
+/// The settings of `argon2rs::Argon2::default` which were used before the parameters were stored.
+fn legacy_hash_parameters() -> PasswordHashParameters {
+    PasswordHashParameters { iterations: 3, memory_costs: 4096, parallelism: 1 }
+}
+
+#[cfg(test)]
+pub fn fast_hash_parameters() -> PasswordHashParameters {
+    PasswordHashParameters { iterations: 1, memory_costs: 64, parallelism: 1 }
 }
 
 pub trait AuthTagProvider {
@@ -71,55 +123,190 @@ pub trait AuthTagProvider {
 }
 
 
-fn check_nonce(nonce: &Nonce) -> Result<(), Error> {
-    if nonce.len() != 12 {
-        let error_kind = ErrorKind::InvalidNonceLength { expected_size: 12, real_size: nonce.len() };
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, Error> {
+    use rand::{OsRng, Rng};
+
+    let mut rng = OsRng::new()?;
+    let mut bytes = vec![0u8; len];
+    rng.fill_bytes(&mut bytes);
+    Ok(bytes)
+}
+
+fn check_nonce(nonce: &Nonce, expected_size: usize) -> Result<(), Error> {
+    if nonce.len() != expected_size {
+        let error_kind = ErrorKind::InvalidNonceLength { expected_size, real_size: nonce.len() };
         return Err(Error::from(error_kind));
     }
     Ok(())
 }
 
-impl DeEncrypter for ::pb::file::EncryptionType {
+fn check_key(key: &HashedPw) -> Result<(), Error> {
+    if key.len() != KEY_LENGTH {
+        let error_kind = ErrorKind::InvalidKeyLength { expected_size: KEY_LENGTH, real_size: key.len() };
+        return Err(Error::from(error_kind));
+    }
+    Ok(())
+}
+
+/// Copies a slice whose length was already checked into the fixed size array the cipher crates expect.
+fn to_array<A: Default + AsMut<[u8]>>(slice: &[u8]) -> A {
+    let mut array = A::default();
+    array.as_mut().copy_from_slice(slice);
+    array
+}
+
+impl DeEncrypter for EncryptionType {
     fn encrypt(&self, key: &HashedPw, nonce: &Nonce, aad: &AAD, input: &Plaintext) -> Result<(CipherTextVec, VerificationTagVec), Error> {
-        check_nonce(nonce)?;
-        let mut output = Vec::with_capacity(input.len());
-        let verification_tag = encrypt(key.content.as_ref(), nonce, aad, input, &mut output)?;
-        Ok((output, verification_tag.to_vec()))
+        check_nonce(nonce, self.nonce_length())?;
+        check_key(key)?;
+        match *self {
+            EncryptionType::ChachaPoly1305 => {
+                let mut output = Vec::with_capacity(input.len());
+                let verification_tag = encrypt(key.content.as_ref(), nonce, aad, input, &mut output)?;
+                Ok((output, verification_tag.to_vec()))
+            }
+            EncryptionType::AesGcm256 => {
+                use aes_gcm::{AeadInPlace, Aes256Gcm, NewAead};
+
+                let cipher = Aes256Gcm::new(&to_array::<[u8; KEY_LENGTH]>(key).into());
+                let mut output = input.to_vec();
+                let verification_tag = cipher.encrypt_in_place_detached(&to_array::<[u8; NONCE_LENGTH]>(nonce).into(), aad, &mut output)
+                    .map_err(|_| ErrorKind::EncryptionFailed)?;
+                Ok((output, verification_tag.to_vec()))
+            }
+            EncryptionType::XChachaPoly1305 => {
+                use chacha20poly1305::XChaCha20Poly1305;
+                use chacha20poly1305::aead::{AeadInPlace, NewAead};
+
+                let cipher = XChaCha20Poly1305::new(&to_array::<[u8; KEY_LENGTH]>(key).into());
+                let mut output = input.to_vec();
+                let verification_tag = cipher.encrypt_in_place_detached(&to_array::<[u8; XNONCE_LENGTH]>(nonce).into(), aad, &mut output)
+                    .map_err(|_| ErrorKind::EncryptionFailed)?;
+                Ok((output, verification_tag.to_vec()))
+            }
+        }
     }
 
     fn decrypt(&self, key: &HashedPw, nonce: &Nonce, aad: &AAD, tag: &VerificationTag, input: &CipherText) -> Result<PlaintextVec, Error> {
-        check_nonce(nonce)?;
-        let mut output = Vec::with_capacity(input.len());
-        decrypt(key.content.as_ref(), nonce, aad, input, tag, &mut output)?;
-        Ok(output)
+        check_nonce(nonce, self.nonce_length())?;
+        check_key(key)?;
+        match *self {
+            EncryptionType::ChachaPoly1305 => {
+                let mut output = Vec::with_capacity(input.len());
+                decrypt(key.content.as_ref(), nonce, aad, input, tag, &mut output)
+                    .map_err(|_| ErrorKind::DecryptionFailed)?;
+                Ok(output.into())
+            }
+            EncryptionType::AesGcm256 => {
+                use aes_gcm::{AeadInPlace, Aes256Gcm, NewAead};
+
+                if tag.len() != TAG_LENGTH {
+                    return Err(Error::from(ErrorKind::DecryptionFailed));
+                }
+                let cipher = Aes256Gcm::new(&to_array::<[u8; KEY_LENGTH]>(key).into());
+                let mut output = input.to_vec();
+                let nonce = to_array::<[u8; NONCE_LENGTH]>(nonce).into();
+                cipher.decrypt_in_place_detached(&nonce, aad, &mut output, &to_array::<[u8; TAG_LENGTH]>(tag).into())
+                    .map_err(|_| ErrorKind::DecryptionFailed)?;
+                Ok(output.into())
+            }
+            EncryptionType::XChachaPoly1305 => {
+                use chacha20poly1305::XChaCha20Poly1305;
+                use chacha20poly1305::aead::{AeadInPlace, NewAead};
+
+                if tag.len() != TAG_LENGTH {
+                    return Err(Error::from(ErrorKind::DecryptionFailed));
+                }
+                let cipher = XChaCha20Poly1305::new(&to_array::<[u8; KEY_LENGTH]>(key).into());
+                let mut output = input.to_vec();
+                let nonce = to_array::<[u8; XNONCE_LENGTH]>(nonce).into();
+                cipher.decrypt_in_place_detached(&nonce, aad, &mut output, &to_array::<[u8; TAG_LENGTH]>(tag).into())
+                    .map_err(|_| ErrorKind::DecryptionFailed)?;
+                Ok(output.into())
+            }
+        }
+    }
+
+    fn nonce_length(&self) -> usize {
+        match *self {
+            EncryptionType::ChachaPoly1305 | EncryptionType::AesGcm256 => NONCE_LENGTH,
+            EncryptionType::XChachaPoly1305 => XNONCE_LENGTH,
+        }
     }
 }
 
-impl AuthTagProvider for ::pb::file::EncryptionType {
+impl AuthTagProvider for EncryptionType {
     fn get_auth_tag<'a, 'b>(&'b self, data: &'a [u8]) -> Result<(&'a [u8], &'a VerificationTag), Error> {
-        use error::ErrorKind;
-        let tag_length = 16;
-        if data.len() > tag_length {
+        let tag_length = match *self {
+            EncryptionType::ChachaPoly1305 | EncryptionType::AesGcm256 | EncryptionType::XChachaPoly1305 => TAG_LENGTH,
+        };
+        // an empty plaintext is encrypted to the tag alone
+        if data.len() >= tag_length {
             Ok(data.split_at(data.len() - tag_length))
         } else {
-            Err(Error::from(ErrorKind::DataTooShort { msg: "provided data for auth tag".into(), expected_size: 16, real_size: data.len() }))
+            Err(Error::from(ErrorKind::DataTooShort { msg: "provided data for auth tag".into(), expected_size: tag_length, real_size: data.len() }))
         }
     }
 }
 
-impl Hasher for ::pb::file::PasswordHashType {
-    fn hash_pw(&self, bytes: &Plaintext, salt: &Salt) -> HashedPw {
-        use argon2rs::{Argon2, Variant};
+impl PasswordHasher {
+    fn hash_argon2(&self, bytes: &Plaintext, salt: &Salt) -> Result<HashedPw, Error> {
+        use argon2::{hash_raw, Config, ThreadMode, Variant, Version};
+
+        let variant = match self.hash_type {
+            PasswordHashType::Argon2id => Variant::Argon2id,
+            _ => Variant::Argon2i,
+        };
+        let (version, parameters) = match self.parameters {
+            Some(ref parameters) => (Version::Version13, parameters.clone()),
+            None => (Version::Version10, legacy_hash_parameters()),
+        };
+        let config = Config {
+            variant,
+            version,
+            time_cost: parameters.iterations,
+            mem_cost: parameters.memory_costs,
+            lanes: parameters.parallelism,
+            thread_mode: ThreadMode::from_threads(parameters.parallelism),
+            hash_length: KEY_LENGTH as u32,
+            ..Config::default()
+        };
+        let hash = hash_raw(bytes, salt, &config)?;
+        Ok(HashedPw { content: SecretVec::from(hash) })
+    }
+
+    fn hash_scrypt(&self, bytes: &Plaintext, salt: &Salt) -> Result<HashedPw, Error> {
+        use scrypt::{scrypt, ScryptParams};
+        use std::convert::TryFrom;
+
+        let parameters = match self.parameters {
+            Some(ref parameters) => parameters.clone(),
+            None => return Err(Error::from(ErrorKind::InvalidHashParameters("scrypt requires stored parameters".into()))),
+        };
+        let log_n = u8::try_from(parameters.iterations)
+            .map_err(|_| ErrorKind::InvalidHashParameters(format!("scrypt log2(N) of {} is too large", parameters.iterations)))?;
+        let params = ScryptParams::new(log_n, parameters.memory_costs, parameters.parallelism)
+            .map_err(|_| ErrorKind::InvalidHashParameters(format!("{:?}", parameters)))?;
+
+        let mut out = [0u8; KEY_LENGTH];
+        scrypt(bytes, salt, &params, &mut out)?;
+        let hash = HashedPw::from(out.as_ref());
+        out.zeroize();
+        Ok(hash)
+    }
+}
 
-        let mut out = [0; 32];
-        let a2 = Argon2::default(Variant::Argon2i);
-        a2.hash(&mut out, bytes, salt, &[], &[]);
-        HashedPw::from(out.as_ref())
+impl Hasher for PasswordHasher {
+    fn hash_pw(&self, bytes: &Plaintext, salt: &Salt) -> Result<HashedPw, Error> {
+        match self.hash_type {
+            PasswordHashType::Argon2i | PasswordHashType::Argon2id => self.hash_argon2(bytes, salt),
+            PasswordHashType::SCrypt => self.hash_scrypt(bytes, salt),
+        }
     }
-    fn double_hash_pw(&self, bytes: &Plaintext, salt: &Salt) -> DoubleHashedPw {
-        let hash = self.hash_pw(bytes, salt);
-        let hash = self.hash_pw(hash.as_ref(), salt);
-        DoubleHashedPw{content: hash.content}
+    fn double_hash_pw(&self, bytes: &Plaintext, salt: &Salt) -> Result<DoubleHashedPw, Error> {
+        let hash = self.hash_pw(bytes, salt)?;
+        let hash = self.hash_pw(hash.as_ref(), salt)?;
+        Ok(DoubleHashedPw{content: hash.content})
     }
 }
 
@@ -136,6 +323,10 @@ mod test {
         let (data, tag) = EncryptionType::ChachaPoly1305.get_auth_tag(&data).unwrap();
         assert_eq!(4, data.len());
         assert_eq!(16, tag.len());
+
+        let (data, tag) = EncryptionType::ChachaPoly1305.get_auth_tag(&[3u8; 16]).unwrap();
+        assert!(data.is_empty());
+        assert_eq!(16, tag.len());
     }
 
     #[test]
@@ -143,4 +334,116 @@ mod test {
         let data = [3u8, 12];
         assert!(EncryptionType::ChachaPoly1305.get_auth_tag(&data).is_err());
     }
+
+    #[test]
+    fn test_roundtrip_all_encryption_types() {
+        let key = HashedPw::from([7u8; KEY_LENGTH].as_ref());
+        let other_key = HashedPw::from([8u8; KEY_LENGTH].as_ref());
+        for enc_type in [EncryptionType::ChachaPoly1305, EncryptionType::AesGcm256, EncryptionType::XChachaPoly1305].iter() {
+            let nonce = vec![1u8; enc_type.nonce_length()];
+            let (encrypted, tag) = enc_type.encrypt(&key, &nonce, b"aad", b"hello world").unwrap();
+            assert_eq!(TAG_LENGTH, tag.len());
+            assert_ne!(b"hello world".as_ref(), encrypted.as_slice());
+            assert_eq!(b"hello world".to_vec(), enc_type.decrypt(&key, &nonce, b"aad", &tag, &encrypted).unwrap());
+
+            assert!(enc_type.decrypt(&other_key, &nonce, b"aad", &tag, &encrypted).is_err());
+            assert!(enc_type.decrypt(&key, &nonce, b"other aad", &tag, &encrypted).is_err());
+        }
+    }
+
+    #[test]
+    fn test_aes_gcm_known_vector() {
+        // NIST GCM test case 13 (256 bit key, all zero)
+        let key = HashedPw::from([0u8; KEY_LENGTH].as_ref());
+        let (encrypted, tag) = EncryptionType::AesGcm256.encrypt(&key, &[0u8; NONCE_LENGTH], &[], &[]).unwrap();
+        assert!(encrypted.is_empty());
+        assert_eq!([0x53, 0x0f, 0x8a, 0xfb, 0xc7, 0x45, 0x36, 0xb9, 0xa9, 0x63, 0xb4, 0xf1, 0xc4, 0xcb, 0x73, 0x8b].as_ref(), tag.as_slice());
+    }
+
+    #[test]
+    fn test_xchacha_known_vector() {
+        // draft-irtf-cfrg-xchacha-03, appendix A.3.1
+        let key: Vec<u8> = (0x80u8..0xa0).collect();
+        let nonce: Vec<u8> = (0x40u8..0x58).collect();
+        let aad = [0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7];
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+        let (encrypted, tag) = EncryptionType::XChachaPoly1305.encrypt(&HashedPw::from(key.as_ref()), &nonce, &aad, plaintext).unwrap();
+        assert_eq!([0xbd, 0x6d, 0x17, 0x9d, 0x3e, 0x83, 0xd4, 0x3b].as_ref(), &encrypted[..8]);
+        assert_eq!([0xc0, 0x87, 0x59, 0x24, 0xc1, 0xc7, 0x98, 0x79, 0x47, 0xde, 0xaf, 0xd8, 0x78, 0x0a, 0xcf, 0x49].as_ref(), tag.as_slice());
+    }
+
+    #[test]
+    fn test_nonce_length_per_type() {
+        let key = HashedPw::from([7u8; KEY_LENGTH].as_ref());
+        assert!(EncryptionType::XChachaPoly1305.encrypt(&key, &[1u8; NONCE_LENGTH], &[], b"data").is_err());
+        assert!(EncryptionType::ChachaPoly1305.encrypt(&key, &[1u8; XNONCE_LENGTH], &[], b"data").is_err());
+        assert!(EncryptionType::AesGcm256.encrypt(&key, &[1u8; XNONCE_LENGTH], &[], b"data").is_err());
+    }
+
+    #[test]
+    fn test_invalid_key_length() {
+        let key = HashedPw::from([7u8; 16].as_ref());
+        assert!(EncryptionType::AesGcm256.encrypt(&key, &[1u8; NONCE_LENGTH], &[], b"data").is_err());
+        assert!(EncryptionType::ChachaPoly1305.encrypt(&key, &[1u8; NONCE_LENGTH], &[], b"data").is_err());
+    }
+
+    #[test]
+    fn test_legacy_hash_compatible() {
+        let hasher = PasswordHasher { hash_type: PasswordHashType::Argon2i, parameters: None };
+        let expected = [117, 229, 136, 182, 163, 162, 51, 254, 217, 141, 185, 171, 93, 121, 21, 230, 205, 89, 102, 177, 172, 84, 51, 5, 199, 211, 23, 25, 27, 3, 249, 225];
+        assert_eq!(expected.as_ref(), hasher.hash_pw(b"hallo welt", &[9u8; 12]).unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_hash_parameters_used() {
+        let salt = [1u8; SALT_LENGTH];
+        let argon2i = PasswordHasher::new(PasswordHashType::Argon2i, fast_hash_parameters());
+        let argon2id = PasswordHasher::new(PasswordHashType::Argon2id, fast_hash_parameters());
+        let more_iterations = PasswordHasher::new(PasswordHashType::Argon2i, PasswordHashParameters { iterations: 2, ..fast_hash_parameters() });
+
+        let hash = argon2i.hash_pw(b"pw", &salt).unwrap();
+        assert_eq!(KEY_LENGTH, hash.len());
+        assert_eq!(hash.as_slice(), argon2i.hash_pw(b"pw", &salt).unwrap().as_slice());
+        assert_ne!(hash.as_slice(), argon2id.hash_pw(b"pw", &salt).unwrap().as_slice());
+        assert_ne!(hash.as_slice(), more_iterations.hash_pw(b"pw", &salt).unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_scrypt() {
+        let salt = [1u8; SALT_LENGTH];
+        let parameters = PasswordHashParameters { iterations: 4, memory_costs: 2, parallelism: 1 };
+        let scrypt = PasswordHasher::new(PasswordHashType::SCrypt, parameters.clone());
+        let more_iterations = PasswordHasher::new(PasswordHashType::SCrypt, PasswordHashParameters { iterations: 5, ..parameters.clone() });
+        let argon2i = PasswordHasher::new(PasswordHashType::Argon2i, fast_hash_parameters());
+
+        let hash = scrypt.hash_pw(b"pw", &salt).unwrap();
+        assert_eq!(KEY_LENGTH, hash.len());
+        assert_eq!(hash.as_slice(), scrypt.hash_pw(b"pw", &salt).unwrap().as_slice());
+        assert_ne!(hash.as_slice(), more_iterations.hash_pw(b"pw", &salt).unwrap().as_slice());
+        assert_ne!(hash.as_slice(), argon2i.hash_pw(b"pw", &salt).unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_scrypt_known_vector() {
+        // RFC 7914, test vector 2 truncated to the key length
+        let hasher = PasswordHasher::new(PasswordHashType::SCrypt, PasswordHashParameters { iterations: 10, memory_costs: 8, parallelism: 16 });
+        let expected = [0xfd, 0xba, 0xbe, 0x1c, 0x9d, 0x34, 0x72, 0x00, 0x78, 0x56, 0xe7, 0x19, 0x0d, 0x01, 0xe9, 0xfe,
+            0x7c, 0x6a, 0xd7, 0xcb, 0xc8, 0x23, 0x78, 0x30, 0xe7, 0x73, 0x76, 0x63, 0x4b, 0x37, 0x31, 0x62];
+        assert_eq!(expected.as_ref(), hasher.hash_pw(b"password", b"NaCl").unwrap().as_slice());
+    }
+
+    #[test]
+    fn test_invalid_scrypt_parameters() {
+        let salt = [1u8; SALT_LENGTH];
+        let too_large = PasswordHasher::new(PasswordHashType::SCrypt, PasswordHashParameters { iterations: 300, memory_costs: 8, parallelism: 1 });
+        let missing = PasswordHasher { hash_type: PasswordHashType::SCrypt, parameters: None };
+        assert!(too_large.hash_pw(b"pw", &salt).is_err());
+        assert!(missing.hash_pw(b"pw", &salt).is_err());
+    }
+
+    #[test]
+    fn test_invalid_hash_parameters() {
+        let hasher = PasswordHasher::new(PasswordHashType::Argon2i, PasswordHashParameters { iterations: 0, memory_costs: 64, parallelism: 1 });
+        assert!(hasher.hash_pw(b"pw", &[1u8; SALT_LENGTH]).is_err());
+    }
 }
\ No newline at end of file
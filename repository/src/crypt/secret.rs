@@ -0,0 +1,202 @@
+//! Memory for keys and decrypted data.
+//!
+//! A `SecretVec` overwrites its memory with zeros when it is dropped and never shows its content in `Debug` output.
+//! On Linux its pages can additionally be locked into memory, so the secret is never written to swap.
+
+use failure::Error;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+#[derive(Default)]
+pub struct SecretVec {
+    content: Vec<u8>,
+    locked: bool,
+}
+
+impl SecretVec {
+    pub fn with_capacity(capacity: usize) -> Self {
+        SecretVec::from(Vec::with_capacity(capacity))
+    }
+
+    /// Locks the memory into RAM, so it is never swapped to disk. Only supported on Linux.
+    /// Fails if the limit for locked memory is reached, see `ulimit -l`.
+    /// Locks are not counted, a secret unlocks its pages when dropped even if another locked secret shares one of them.
+    pub fn lock(&mut self) -> Result<(), Error> {
+        if !self.locked {
+            lock_memory(&self.content)?;
+            self.locked = true;
+        }
+        Ok(())
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.content
+    }
+}
+
+impl Drop for SecretVec {
+    fn drop(&mut self) {
+        self.content.zeroize();
+        if self.locked {
+            unlock_memory(&self.content);
+        }
+    }
+}
+
+/// A clone of a locked secret is locked as well if the limit allows it.
+impl Clone for SecretVec {
+    fn clone(&self) -> Self {
+        let mut clone = SecretVec::from(self.content.as_slice());
+        if self.locked {
+            let _ = clone.lock();
+        }
+        clone
+    }
+}
+
+impl fmt::Debug for SecretVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretVec({} bytes)", self.content.len())
+    }
+}
+
+impl Deref for SecretVec {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.content
+    }
+}
+
+impl AsRef<[u8]> for SecretVec {
+    fn as_ref(&self) -> &[u8] {
+        &self.content
+    }
+}
+
+/// Takes over the memory of the vector without copying it.
+impl From<Vec<u8>> for SecretVec {
+    fn from(content: Vec<u8>) -> Self {
+        SecretVec { content, locked: false }
+    }
+}
+
+impl<'a> From<&'a [u8]> for SecretVec {
+    fn from(data: &'a [u8]) -> Self {
+        SecretVec::from(data.to_vec())
+    }
+}
+
+/// Grows by copying into a bigger allocation and wiping the old one, so no copy of the content is left behind.
+impl io::Write for SecretVec {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let needed = self.content.len() + buf.len();
+        if needed > self.content.capacity() {
+            let mut grown = Vec::with_capacity(needed.max(self.content.capacity() * 2));
+            grown.extend_from_slice(&self.content);
+            if self.locked {
+                lock_memory(&grown)?;
+                unlock_memory(&self.content);
+            }
+            self.content.zeroize();
+            self.content = grown;
+        }
+        self.content.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compares in constant time, so the time taken does not tell how many leading bytes match.
+impl PartialEq for SecretVec {
+    fn eq(&self, other: &SecretVec) -> bool {
+        self.content.ct_eq(&other.content).into()
+    }
+}
+
+impl Eq for SecretVec {}
+
+impl PartialEq<Vec<u8>> for SecretVec {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.content.ct_eq(other).into()
+    }
+}
+
+impl PartialEq<SecretVec> for Vec<u8> {
+    fn eq(&self, other: &SecretVec) -> bool {
+        other == self
+    }
+}
+
+/// The whole capacity is locked, the content could grow into it.
+#[cfg(target_os = "linux")]
+fn lock_memory(content: &Vec<u8>) -> io::Result<()> {
+    if content.capacity() == 0 {
+        return Ok(());
+    }
+    let result = unsafe { ::libc::mlock(content.as_ptr() as *const ::libc::c_void, content.capacity()) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unlock_memory(content: &Vec<u8>) {
+    if content.capacity() > 0 {
+        unsafe { ::libc::munlock(content.as_ptr() as *const ::libc::c_void, content.capacity()) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_memory(_content: &Vec<u8>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "locking memory is only supported on Linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unlock_memory(_content: &Vec<u8>) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_debug_hides_content() {
+        let secret = SecretVec::from(b"top secret".as_ref());
+        assert_eq!("SecretVec(10 bytes)", format!("{:?}", secret));
+        assert_eq!(b"top secret".to_vec(), secret);
+        assert_eq!(secret.clone(), secret);
+        assert!(secret != SecretVec::from(b"top secreT".as_ref()));
+    }
+
+    #[test]
+    fn test_write_grows() {
+        let mut secret = SecretVec::with_capacity(4);
+        for _ in 0..100 {
+            io::Write::write_all(&mut secret, b"top secret").unwrap();
+        }
+        assert_eq!(b"top secret".repeat(100), secret);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_lock() {
+        let mut secret = SecretVec::from(vec![1u8; 32]);
+        // the limit for locked memory might be 0 in a sandbox
+        if secret.lock().is_ok() {
+            assert!(secret.is_locked());
+            assert!(secret.clone().is_locked());
+        }
+    }
+}
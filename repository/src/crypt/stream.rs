@@ -0,0 +1,254 @@
+//! Chunked encryption of contents which should not be held in memory as a whole.
+//!
+//! The plaintext is split into segments of a fixed size, only the last segment may be shorter (or empty).
+//! Every segment is encrypted on its own and followed by its auth tag.
+//! The nonce of a segment is derived from the base nonce by xoring the segment index and a final flag into its last five bytes,
+//! so segments can neither be reordered nor dropped from the end without the decryption failing.
+
+use super::{DeEncrypter, HashedPw, PlaintextVec, TAG_LENGTH};
+use error::ErrorKind;
+use failure::Error;
+use pb::file::EncryptionType;
+use std::io::{self, Read, Write};
+use zeroize::Zeroizing;
+
+pub const DEFAULT_SEGMENT_SIZE: u32 = 64 * 1024;
+/// Upper bound for segment sizes read from stored files, a reader buffers one segment.
+pub const MAX_SEGMENT_SIZE: u32 = 16 * 1024 * 1024;
+
+fn check_segment_size(segment_size: u32) -> Result<usize, Error> {
+    if segment_size == 0 || segment_size > MAX_SEGMENT_SIZE {
+        return Err(Error::from(ErrorKind::InvalidSegmentSize(segment_size)));
+    }
+    Ok(segment_size as usize)
+}
+
+fn segment_nonce(base_nonce: &[u8], index: u32, last: bool) -> Vec<u8> {
+    let mut nonce = base_nonce.to_vec();
+    let offset = nonce.len() - 5;
+    for (i, byte) in [(index >> 24) as u8, (index >> 16) as u8, (index >> 8) as u8, index as u8].iter().enumerate() {
+        nonce[offset + i] ^= byte;
+    }
+    if last {
+        nonce[offset + 4] ^= 1;
+    }
+    nonce
+}
+
+fn io_error(error: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.compat())
+}
+
+/// Encrypts everything written to it and writes the encrypted segments to the inner writer.
+///
+/// `finish` has to be called after the last write, it encrypts the final segment.
+/// A writer that is only dropped leaves a stream behind which fails to decrypt.
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    encryption_type: EncryptionType,
+    key: HashedPw,
+    base_nonce: Vec<u8>,
+    aad: Vec<u8>,
+    segment_size: usize,
+    index: u32,
+    buffer: Zeroizing<Vec<u8>>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(inner: W, encryption_type: EncryptionType, key: HashedPw, base_nonce: &[u8], aad: &[u8], segment_size: u32) -> Result<Self, Error> {
+        let segment_size = check_segment_size(segment_size)?;
+        if base_nonce.len() != encryption_type.nonce_length() {
+            return Err(Error::from(ErrorKind::InvalidNonceLength { expected_size: encryption_type.nonce_length(), real_size: base_nonce.len() }));
+        }
+        Ok(EncryptingWriter {
+            inner,
+            encryption_type,
+            key,
+            base_nonce: base_nonce.to_vec(),
+            aad: aad.to_vec(),
+            segment_size,
+            index: 0,
+            buffer: Zeroizing::new(Vec::with_capacity(segment_size)),
+        })
+    }
+
+    /// Encrypts the remaining data as final segment and returns the inner writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.write_segment(true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_segment(&mut self, last: bool) -> Result<(), Error> {
+        let nonce = segment_nonce(&self.base_nonce, self.index, last);
+        let (mut encrypted, mut tag) = self.encryption_type.encrypt(&self.key, &nonce, &self.aad, &self.buffer)?;
+        encrypted.append(&mut tag);
+        self.inner.write_all(&encrypted)?;
+
+        self.buffer.clear();
+        self.index = self.index.checked_add(1).ok_or(ErrorKind::EncryptionFailed)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // a full segment is only written once more data follows, otherwise it might be the final one
+        if self.buffer.len() == self.segment_size {
+            self.write_segment(false).map_err(io_error)?;
+        }
+        let len = buf.len().min(self.segment_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream written by `EncryptingWriter`, at most one segment is held in memory.
+///
+/// A stream that was truncated or modified results in an `io::Error` of kind `InvalidData`.
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    encryption_type: EncryptionType,
+    key: HashedPw,
+    base_nonce: Vec<u8>,
+    aad: Vec<u8>,
+    segment_size: usize,
+    index: u32,
+    /// Encrypted data read ahead, needed to decide whether a segment is the last one
+    pending: Vec<u8>,
+    decrypted: PlaintextVec,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub fn new(inner: R, encryption_type: EncryptionType, key: HashedPw, base_nonce: &[u8], aad: &[u8], segment_size: u32) -> Result<Self, Error> {
+        let segment_size = check_segment_size(segment_size)?;
+        if base_nonce.len() != encryption_type.nonce_length() {
+            return Err(Error::from(ErrorKind::InvalidNonceLength { expected_size: encryption_type.nonce_length(), real_size: base_nonce.len() }));
+        }
+        Ok(DecryptingReader {
+            inner,
+            encryption_type,
+            key,
+            base_nonce: base_nonce.to_vec(),
+            aad: aad.to_vec(),
+            segment_size,
+            index: 0,
+            pending: Vec::new(),
+            decrypted: PlaintextVec::default(),
+            position: 0,
+            finished: false,
+        })
+    }
+
+    fn read_segment(&mut self) -> Result<(), Error> {
+        let sealed_length = self.segment_size + TAG_LENGTH;
+        // one byte more than a segment tells whether another segment follows
+        (&mut self.inner).take((sealed_length + 1 - self.pending.len()) as u64).read_to_end(&mut self.pending)?;
+        let last = self.pending.len() <= sealed_length;
+        let carry = if last { Vec::new() } else { self.pending.split_off(sealed_length) };
+
+        if self.pending.len() < TAG_LENGTH {
+            return Err(Error::from(ErrorKind::DataTooShort { msg: "encrypted segment".into(), expected_size: TAG_LENGTH, real_size: self.pending.len() }));
+        }
+        let nonce = segment_nonce(&self.base_nonce, self.index, last);
+        let (data, tag) = self.pending.split_at(self.pending.len() - TAG_LENGTH);
+        self.decrypted = self.encryption_type.decrypt(&self.key, &nonce, &self.aad, tag, data)?;
+        self.position = 0;
+        self.pending = carry;
+        self.finished = last;
+        self.index = self.index.checked_add(1).ok_or(ErrorKind::DecryptionFailed)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.decrypted.len() {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            self.read_segment().map_err(io_error)?;
+        }
+        let len = buf.len().min(self.decrypted.len() - self.position);
+        buf[..len].copy_from_slice(&self.decrypted[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypt::KEY_LENGTH;
+
+    fn encrypt_all(encryption_type: EncryptionType, key: &HashedPw, nonce: &[u8], data: &[u8], segment_size: u32) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), encryption_type, key.clone(), nonce, b"aad", segment_size).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt_all(encryption_type: EncryptionType, key: &HashedPw, nonce: &[u8], data: &[u8], segment_size: u32) -> io::Result<Vec<u8>> {
+        let mut reader = DecryptingReader::new(data, encryption_type, key.clone(), nonce, b"aad", segment_size).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let key = HashedPw::from([3u8; KEY_LENGTH].as_ref());
+        for encryption_type in [EncryptionType::ChachaPoly1305, EncryptionType::AesGcm256, EncryptionType::XChachaPoly1305].iter() {
+            let nonce = vec![9u8; encryption_type.nonce_length()];
+            for len in [0usize, 1, 15, 16, 17, 32, 100].iter() {
+                let data: Vec<u8> = (0..*len).map(|i| i as u8).collect();
+                let encrypted = encrypt_all(*encryption_type, &key, &nonce, &data, 16);
+
+                let segments = ((len + 15) / 16).max(1);
+                assert_eq!(len + segments * TAG_LENGTH, encrypted.len());
+                assert_eq!(data, decrypt_all(*encryption_type, &key, &nonce, &encrypted, 16).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncation_detected() {
+        let key = HashedPw::from([3u8; KEY_LENGTH].as_ref());
+        let nonce = [9u8; 12];
+        let data = [1u8; 40];
+        let encrypted = encrypt_all(EncryptionType::ChachaPoly1305, &key, &nonce, &data, 16);
+
+        let segment = 16 + TAG_LENGTH;
+        assert!(decrypt_all(EncryptionType::ChachaPoly1305, &key, &nonce, &encrypted[..2 * segment], 16).is_err());
+        assert!(decrypt_all(EncryptionType::ChachaPoly1305, &key, &nonce, &encrypted[..segment], 16).is_err());
+        assert!(decrypt_all(EncryptionType::ChachaPoly1305, &key, &nonce, &encrypted[..encrypted.len() - 1], 16).is_err());
+        assert!(decrypt_all(EncryptionType::ChachaPoly1305, &key, &nonce, &[], 16).is_err());
+    }
+
+    #[test]
+    fn test_reordering_detected() {
+        let key = HashedPw::from([3u8; KEY_LENGTH].as_ref());
+        let nonce = [9u8; 12];
+        let data: Vec<u8> = (0..48).collect();
+        let encrypted = encrypt_all(EncryptionType::ChachaPoly1305, &key, &nonce, &data, 16);
+
+        let segment = 16 + TAG_LENGTH;
+        let swapped = [&encrypted[segment..2 * segment], &encrypted[..segment], &encrypted[2 * segment..]].concat();
+        assert!(decrypt_all(EncryptionType::ChachaPoly1305, &key, &nonce, &swapped, 16).is_err());
+    }
+
+    #[test]
+    fn test_invalid_segment_size() {
+        let key = HashedPw::from([3u8; KEY_LENGTH].as_ref());
+        assert!(EncryptingWriter::new(Vec::new(), EncryptionType::ChachaPoly1305, key.clone(), &[0u8; 12], &[], 0).is_err());
+        assert!(DecryptingReader::new(&[][..], EncryptionType::ChachaPoly1305, key, &[0u8; 12], &[], MAX_SEGMENT_SIZE + 1).is_err());
+    }
+}
@@ -1,4 +1,9 @@
 use ::repository::RepositoryId;
+use ::repository::file::{FileId, FileVersion};
+use ::pb::file::FileType;
+use failure::{Compat, Error};
+use std::io;
+use std::path::PathBuf;
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
@@ -9,8 +14,118 @@ pub enum ErrorKind {
 
     #[fail(display = "Invalid nonce length. Expected {}, got {}", expected_size, real_size)]
     InvalidNonceLength{expected_size: usize, real_size: usize},
-    #[fail(display = "Unknown error occurred")]
-    Other,
+    #[fail(display = "Invalid key length. Expected {}, got {}", expected_size, real_size)]
+    InvalidKeyLength{expected_size: usize, real_size: usize},
+    #[fail(display = "Invalid segment size {}", _0)]
+    InvalidSegmentSize(u32),
+    #[fail(display = "Encryption failed")]
+    EncryptionFailed,
+    #[fail(display = "Decryption failed")]
+    DecryptionFailed,
     #[fail(display = "Invalid Password")]
     InvalidPassword,
+    #[fail(display = "User '{}' already has a key slot", _0)]
+    UserExists(String),
+    #[fail(display = "User '{}' has no key slot", _0)]
+    UnknownUser(String),
+    #[fail(display = "User '{}' is the last one who can open the repository", _0)]
+    LastUser(String),
+    #[fail(display = "Only '{}' can remove the first user of the repository", _0)]
+    PrimaryUser(String),
+    #[fail(display = "The file key can only be rotated while a single user has a key slot")]
+    MultipleUsers,
+    #[fail(display = "An unfinished file key rotation has to be completed first")]
+    KeyRotationPending,
+    #[fail(display = "Invalid password hash parameters: {}", _0)]
+    InvalidHashParameters(String),
+    #[fail(display = "Folder not found {:?}", _0)]
+    FolderNotFound(PathBuf),
+    #[fail(display = "Invalid file name '{}'", _0)]
+    InvalidFileName(String),
+    #[fail(display = "Invalid stored file: {}", _0)]
+    InvalidStoredFile(String),
+    #[fail(display = "File {} has type {:?} which is not expected here", file_name, file_type)]
+    UnexpectedFileType{file_name: String, file_type: FileType},
+    #[fail(display = "Peer could not prove that it knows the repository")]
+    SyncAuthenticationFailed,
+    #[fail(display = "File {} has version {} locally, the received version {} is not newer", file_id, local_version, received_version)]
+    StaleFile{file_id: FileId, local_version: FileVersion, received_version: FileVersion},
+    #[fail(display = "File {} is older than the version last written or received here", file_name)]
+    RolledBack{file_name: String},
+    #[fail(display = "Invalid sync message: {}", _0)]
+    InvalidSyncMessage(String),
+    #[fail(display = "File {} was modified concurrently. Expected version {} but got {}", file_id, expected_version, real_version)]
+    OptimisticLock{file_id: FileId, expected_version: FileVersion, real_version: FileVersion},
+    #[fail(display = "Version {} of file {} is not kept in the history", version, file_id)]
+    VersionNotFound{file_id: FileId, version: FileVersion},
+    #[fail(display = "Unknown file type {}", _0)]
+    UnknownFileType(i32),
+
+    #[fail(display = "File {} is corrupted: {}", file_name, reason)]
+    CorruptedFile{file_name: String, reason: String},
+    #[fail(display = "File {} has the unsupported type {}", file_name, file_type)]
+    UnsupportedFileType{file_name: String, file_type: i32},
+    #[fail(display = "File {} has the unsupported version {}", file_name, version)]
+    UnsupportedVersion{file_name: String, version: u32},
+    #[fail(display = "File {} failed authentication, it was damaged or manipulated", file_name)]
+    AuthenticationFailed{file_name: String},
+    #[fail(display = "File {} belongs to repository {} instead of {}", file_name, found, expected)]
+    WrongRepository{file_name: String, expected: RepositoryId, found: RepositoryId},
+    #[fail(display = "I/O failure on file {}: {}", file_name, cause)]
+    Io{file_name: String, #[cause] cause: io::Error},
+}
+
+/// Attaches the name of the stored file an error occurred on.
+pub trait FileContext<T> {
+    /// Unparseable data, failed authentication and I/O failures get their own kinds with the file name,
+    /// other errors are passed on as they are.
+    fn in_file(self, file_name: &str) -> Result<T, Error>;
+}
+
+impl<T, E: Into<Error>> FileContext<T> for Result<T, E> {
+    fn in_file(self, file_name: &str) -> Result<T, Error> {
+        self.map_err(|e| with_file_name(e.into(), file_name))
+    }
+}
+
+fn with_file_name(error: Error, file_name: &str) -> Error {
+    let file_name = file_name.to_string();
+    let error = match error.downcast::<io::Error>() {
+        Ok(io_error) => {
+            // readers that decrypt or parse while reading pass their errors on as io errors
+            if io_error.get_ref().map_or(false, |inner| inner.is::<Compat<Error>>()) {
+                let kind = io_error.kind();
+                return match io_error.into_inner().map(|inner| inner.downcast::<Compat<Error>>()) {
+                    Some(Ok(compat)) => with_file_name(compat.into_inner(), &file_name),
+                    Some(Err(inner)) => Error::from(ErrorKind::Io { file_name, cause: io::Error::new(kind, inner) }),
+                    None => Error::from(ErrorKind::Io { file_name, cause: io::Error::from(kind) }),
+                };
+            }
+            return match io_error.kind() {
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => Error::from(ErrorKind::CorruptedFile { file_name, reason: io_error.to_string() }),
+                _ => Error::from(ErrorKind::Io { file_name, cause: io_error }),
+            };
+        }
+        Err(error) => error,
+    };
+    let error = match error.downcast::<::quick_protobuf::Error>() {
+        Ok(protobuf_error) => return Error::from(ErrorKind::CorruptedFile { file_name, reason: protobuf_error.to_string() }),
+        Err(error) => error,
+    };
+    let named = match error.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::DecryptionFailed) => ErrorKind::AuthenticationFailed { file_name },
+        Some(ErrorKind::InvalidStoredFile(reason)) => ErrorKind::CorruptedFile { file_name, reason: reason.clone() },
+        Some(ErrorKind::DataTooShort { .. }) => ErrorKind::CorruptedFile { file_name, reason: error.to_string() },
+        Some(ErrorKind::UnknownFileType(file_type)) => ErrorKind::UnsupportedFileType { file_name, file_type: *file_type },
+        _ => return error,
+    };
+    Error::from(named)
+}
+
+/// Whether the error says that a file does not exist.
+pub fn is_not_found(error: &Error) -> bool {
+    match error.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::Io { cause, .. }) => cause.kind() == io::ErrorKind::NotFound,
+        _ => error.downcast_ref::<io::Error>().map_or(false, |cause| cause.kind() == io::ErrorKind::NotFound),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,276 @@
+use super::{FileSource, StoredFileName};
+use super::wrapper::known_file_type;
+use error::ErrorKind;
+use failure::Error;
+use pb::file::FileType;
+use quick_protobuf::BytesReader;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+const TEMP_FILE_PREFIX: &str = ".tmp_";
+const QUARANTINE_FOLDER: &str = ".quarantine";
+/// Enough bytes for the tag and the enum value of `StoredFileWrapper.type`
+const FILE_TYPE_PEEK_LENGTH: usize = 6;
+
+pub struct DirectoryFileSource {
+    folder: PathBuf,
+}
+
+impl DirectoryFileSource {
+    pub fn new<P: Into<PathBuf>>(folder: P) -> Result<Self, Error> {
+        let folder = folder.into();
+        if !folder.is_dir() {
+            return Err(Error::from(ErrorKind::FolderNotFound(folder)));
+        }
+        Ok(DirectoryFileSource { folder })
+    }
+
+    fn path_for(&self, name: &str) -> Result<PathBuf, Error> {
+        let is_plain_name = !name.is_empty() && !name.starts_with('.') && !name.contains(&['/', '\\'][..]);
+        if is_plain_name {
+            Ok(self.folder.join(name))
+        } else {
+            Err(Error::from(ErrorKind::InvalidFileName(name.into())))
+        }
+    }
+
+    fn list_by_type(&self, file_type: FileType) -> Result<Vec<StoredFileName>, Error> {
+        let mut names = Vec::new();
+        for name in self.list_names()? {
+            let prefix = self.peek_file_content(&name, FILE_TYPE_PEEK_LENGTH)?;
+            if read_file_type(&prefix) == Some(file_type) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Reads the `type` field of a `StoredFileWrapper` from the first bytes of a stored file.
+/// The field is always written first, so the prefix is sufficient.
+fn read_file_type(prefix: &[u8]) -> Option<FileType> {
+    let mut reader = BytesReader::from_bytes(prefix);
+    match reader.next_tag(prefix) {
+        Ok(8) => {
+            known_file_type(reader.read_int32(prefix).ok()?)
+        }
+        _ => None,
+    }
+}
+
+impl FileSource for DirectoryFileSource {
+    fn list_repositories(&self) -> Result<Vec<StoredFileName>, Error> {
+        self.list_by_type(FileType::RepositoryV1)
+    }
+
+    fn list_files(&self) -> Result<Vec<StoredFileName>, Error> {
+        self.list_by_type(FileType::FileV1)
+    }
+
+    fn list_file_versions(&self) -> Result<Vec<StoredFileName>, Error> {
+        self.list_by_type(FileType::FileVersionV1)
+    }
+
+    /// Lists the plain files in the folder, hidden ones like temporary files and subfolders are left out.
+    fn list_names(&self) -> Result<Vec<StoredFileName>, Error> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.folder)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            names.push(name);
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn get_file_content(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let mut file = File::open(self.path_for(name)?)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    fn peek_file_content(&self, name: &str, len: usize) -> Result<Vec<u8>, Error> {
+        let file = File::open(self.path_for(name)?)?;
+        let mut content = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    fn store_file(&mut self, file_name: &str, data: &[u8]) -> Result<(), Error> {
+        self.store_file_from(file_name, &mut |out| Ok(out.write_all(data)?))
+    }
+
+    fn delete_file(&mut self, file_name: &str) -> Result<(), Error> {
+        Ok(fs::remove_file(self.path_for(file_name)?)?)
+    }
+
+    /// Moves the file into the hidden folder `.quarantine`.
+    /// A file quarantined before under the same name is kept, the new one gets the first free suffix `.1`, `.2` and so on.
+    fn quarantine_file(&mut self, file_name: &str) -> Result<(), Error> {
+        let source = self.path_for(file_name)?;
+        let quarantine = self.folder.join(QUARANTINE_FOLDER);
+        fs::create_dir_all(&quarantine)?;
+        let mut target = quarantine.join(file_name);
+        let mut counter = 0;
+        while target.exists() {
+            counter += 1;
+            target = quarantine.join(format!("{}.{}", file_name, counter));
+        }
+        Ok(fs::rename(source, target)?)
+    }
+
+    fn rename_file(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        Ok(fs::rename(self.path_for(from)?, self.path_for(to)?)?)
+    }
+
+    fn open_file(&self, name: &str) -> Result<Box<dyn Read>, Error> {
+        let file = File::open(self.path_for(name)?)?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+
+    fn store_file_from(&mut self, file_name: &str, write: &mut dyn FnMut(&mut dyn Write) -> Result<(), Error>) -> Result<(), Error> {
+        let target = self.path_for(file_name)?;
+        let temp = self.folder.join(format!("{}{}", TEMP_FILE_PREFIX, Uuid::new_v4().simple()));
+
+        let result = {
+            let file = OpenOptions::new().write(true).create_new(true).open(&temp)?;
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)
+                .and_then(|_| Ok(writer.flush()?))
+                .and_then(|_| Ok(writer.get_ref().sync_all()?))
+                .and_then(|_| Ok(fs::rename(&temp, &target)?))
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pb::file::StoredFileWrapper;
+    use quick_protobuf::{MessageWrite, Writer};
+    use std::borrow::Cow;
+    use std::io;
+    use tempdir::TempDir;
+
+    fn wrapped(file_type: FileType, content: &[u8]) -> Vec<u8> {
+        let wrapper = StoredFileWrapper { type_pb: file_type, content: Cow::from(content), content_chunks: Vec::new() };
+        let mut out = Vec::new();
+        wrapper.write_message(&mut Writer::new(&mut out)).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_store_and_read() {
+        let dir = TempDir::new("directory_source").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+
+        let data = wrapped(FileType::FileV1, b"hello");
+        source.store_file("file1", &data).unwrap();
+
+        assert_eq!(data, source.get_file_content("file1").unwrap());
+        assert_eq!(&data[..3], source.peek_file_content("file1", 3).unwrap().as_slice());
+        assert_eq!(data, source.peek_file_content("file1", 1000).unwrap());
+    }
+
+    #[test]
+    fn test_list_by_type() {
+        let dir = TempDir::new("directory_source").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+
+        source.store_file("repo", &wrapped(FileType::RepositoryV1, b"repo")).unwrap();
+        source.store_file("b_file", &wrapped(FileType::FileV1, b"file")).unwrap();
+        source.store_file("a_file", &wrapped(FileType::FileV1, b"file")).unwrap();
+        source.store_file("repo.sync", &wrapped(FileType::SyncStateV1, b"state")).unwrap();
+        source.store_file("a_file.1", &wrapped(FileType::FileVersionV1, b"version")).unwrap();
+        source.store_file("garbage", b"no protobuf").unwrap();
+        fs::create_dir(dir.path().join("subfolder")).unwrap();
+
+        assert_eq!(vec!["repo".to_string()], source.list_repositories().unwrap());
+        assert_eq!(vec!["a_file".to_string(), "b_file".to_string()], source.list_files().unwrap());
+        assert_eq!(vec!["a_file.1".to_string()], source.list_file_versions().unwrap());
+    }
+
+    #[test]
+    fn test_overwrite_leaves_no_temp_files() {
+        let dir = TempDir::new("directory_source").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+
+        source.store_file("file", &wrapped(FileType::FileV1, b"first")).unwrap();
+        source.store_file("file", &wrapped(FileType::FileV1, b"second")).unwrap();
+
+        assert_eq!(wrapped(FileType::FileV1, b"second"), source.get_file_content("file").unwrap());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let dir = TempDir::new("directory_source").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+
+        source.store_file("file", &wrapped(FileType::FileV1, b"file")).unwrap();
+        source.store_file("garbage", b"no protobuf").unwrap();
+        assert_eq!(vec!["file".to_string(), "garbage".to_string()], source.list_names().unwrap());
+
+        source.quarantine_file("garbage").unwrap();
+        assert_eq!(vec!["file".to_string()], source.list_names().unwrap());
+        assert_eq!(b"no protobuf".to_vec(), fs::read(dir.path().join(".quarantine").join("garbage")).unwrap());
+
+        source.store_file("garbage", b"more garbage").unwrap();
+        source.quarantine_file("garbage").unwrap();
+        assert_eq!(b"no protobuf".to_vec(), fs::read(dir.path().join(".quarantine").join("garbage")).unwrap());
+        assert_eq!(b"more garbage".to_vec(), fs::read(dir.path().join(".quarantine").join("garbage.1")).unwrap());
+    }
+
+    #[test]
+    fn test_failed_write_keeps_old_file() {
+        let dir = TempDir::new("directory_source").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+
+        source.store_file("file", &wrapped(FileType::FileV1, b"first")).unwrap();
+        let result = source.store_file_from("file", &mut |out| {
+            out.write_all(b"partial")?;
+            Err(Error::from(io::Error::new(io::ErrorKind::Other, "write failed")))
+        });
+        assert!(result.is_err());
+
+        assert_eq!(wrapped(FileType::FileV1, b"first"), source.get_file_content("file").unwrap());
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+
+        let mut streamed = Vec::new();
+        source.open_file("file").unwrap().read_to_end(&mut streamed).unwrap();
+        assert_eq!(wrapped(FileType::FileV1, b"first"), streamed);
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let dir = TempDir::new("directory_source").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+
+        assert!(source.store_file("../escape", b"data").is_err());
+        assert!(source.store_file(".hidden", b"data").is_err());
+        assert!(source.store_file("", b"data").is_err());
+        assert!(source.get_file_content("sub/file").is_err());
+    }
+
+    #[test]
+    fn test_missing_folder() {
+        let dir = TempDir::new("directory_source").unwrap();
+        assert!(DirectoryFileSource::new(dir.path().join("missing")).is_err());
+    }
+}
@@ -1,4 +1,9 @@
 use failure::Error;
+use std::io::{Cursor, Read, Write};
+pub use self::directory::DirectoryFileSource;
+
+pub mod directory;
+pub mod wrapper;
 
 pub type StoredFileName = String;
 
@@ -7,9 +12,40 @@ pub trait FileSource {
 
     fn list_files(&self) -> Result<Vec<StoredFileName>, Error>;
 
+    /// Lists the previous versions of files kept as history.
+    fn list_file_versions(&self) -> Result<Vec<StoredFileName>, Error>;
+
+    /// Lists every stored file whatever its content, e.g. to check files that cannot be parsed.
+    fn list_names(&self) -> Result<Vec<StoredFileName>, Error>;
+
     fn get_file_content(&self, name: &str) -> Result<Vec<u8>, Error>;
 
     fn peek_file_content(&self, name: &str, len: usize) -> Result<Vec<u8>, Error>;
 
     fn store_file(&mut self, file_name: &str, data: &[u8]) -> Result<(), Error>;
+
+    fn delete_file(&mut self, file_name: &str) -> Result<(), Error>;
+
+    /// Moves a damaged file out of the way, it must not be listed afterwards but should stay recoverable.
+    fn quarantine_file(&mut self, file_name: &str) -> Result<(), Error>;
+
+    /// Opens a file for reading without loading it as a whole, the default reads it into memory.
+    fn open_file(&self, name: &str) -> Result<Box<dyn Read>, Error> {
+        Ok(Box::new(Cursor::new(self.get_file_content(name)?)))
+    }
+
+    /// Replaces the file stored under `to` with the one stored under `from`, the default copies the data in memory.
+    fn rename_file(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        let data = self.get_file_content(from)?;
+        self.store_file(to, &data)?;
+        self.delete_file(from)
+    }
+
+    /// Stores everything `write` writes under the given name.
+    /// An existing file must stay untouched if `write` fails, the default collects the data in memory.
+    fn store_file_from(&mut self, file_name: &str, write: &mut dyn FnMut(&mut dyn Write) -> Result<(), Error>) -> Result<(), Error> {
+        let mut data = Vec::new();
+        write(&mut data)?;
+        self.store_file(file_name, &data)
+    }
 }
\ No newline at end of file
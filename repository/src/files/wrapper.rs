@@ -0,0 +1,198 @@
+//! Streaming access to `StoredFileWrapper` messages.
+//!
+//! A wrapper starts with its type and content, a streamed content follows as `content_chunks` fields.
+//! Only the chunk currently read or written is held in memory.
+
+use error::ErrorKind;
+use failure::Error;
+use pb::file::FileType;
+use std::io::{self, Read, Write};
+
+const TAG_TYPE: u64 = 8;
+const TAG_CONTENT: u64 = 18;
+const TAG_CONTENT_CHUNK: u64 = 26;
+
+fn read_byte<R: Read>(input: &mut R) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads a varint, `None` if the input ended before its first byte.
+fn read_varint<R: Read>(input: &mut R) -> io::Result<Option<u64>> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let byte = match read_byte(input)? {
+            Some(byte) => byte,
+            None if i == 0 => return Ok(None),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete varint")),
+        };
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "varint too long"))
+}
+
+fn write_varint<W: Write>(output: &mut W, mut value: u64) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(10);
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+    output.write_all(&bytes)
+}
+
+/// The file type of the enum value, `FileType::from` falls back to the default for unknown values.
+pub fn known_file_type(value: i32) -> Option<FileType> {
+    match value {
+        1 => Some(FileType::RepositoryV1),
+        2 => Some(FileType::FileV1),
+        3 => Some(FileType::SyncStateV1),
+        4 => Some(FileType::FileVersionV1),
+        _ => None,
+    }
+}
+
+/// Reads the type and the content of a wrapper, the input is positioned at the first content chunk afterwards.
+pub fn read_wrapper_head<R: Read>(input: &mut R) -> Result<(FileType, Vec<u8>), Error> {
+    let mut file_type = None;
+    loop {
+        match read_varint(input)? {
+            Some(TAG_TYPE) => {
+                let value = read_varint(input)?.ok_or_else(|| ErrorKind::InvalidStoredFile("missing file type".into()))?;
+                file_type = Some(known_file_type(value as i32).ok_or(ErrorKind::UnknownFileType(value as i32))?);
+            }
+            Some(TAG_CONTENT) => {
+                let len = read_varint(input)?.ok_or_else(|| ErrorKind::InvalidStoredFile("missing content length".into()))?;
+                let mut content = Vec::new();
+                input.take(len).read_to_end(&mut content)?;
+                if content.len() as u64 != len {
+                    return Err(Error::from(ErrorKind::DataTooShort { msg: "wrapped content".into(), expected_size: len as usize, real_size: content.len() }));
+                }
+                let file_type = file_type.ok_or_else(|| ErrorKind::InvalidStoredFile("content before file type".into()))?;
+                return Ok((file_type, content));
+            }
+            Some(tag) => return Err(Error::from(ErrorKind::InvalidStoredFile(format!("unexpected tag {} in wrapper", tag)))),
+            None => return Err(Error::from(ErrorKind::InvalidStoredFile("wrapper without content".into()))),
+        }
+    }
+}
+
+/// Writes everything as `content_chunks` fields, to be used after the head of the wrapper was written.
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkWriter { inner }
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write_varint(&mut self.inner, TAG_CONTENT_CHUNK)?;
+        write_varint(&mut self.inner, buf.len() as u64)?;
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the concatenated `content_chunks` following the head read by `read_wrapper_head`.
+pub struct ChunkReader<R: Read> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChunkReader { inner, remaining: 0 }
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            match read_varint(&mut self.inner)? {
+                Some(TAG_CONTENT_CHUNK) => {
+                    self.remaining = read_varint(&mut self.inner)?.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "missing chunk length"))?;
+                }
+                Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected field after content chunks")),
+                None => return Ok(0),
+            }
+        }
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 && len > 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "content chunk truncated"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pb::file::StoredFileWrapper;
+    use quick_protobuf::{BytesReader, MessageRead};
+
+    #[test]
+    fn test_streamed_wrapper_readable_as_message() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&[8, 2, 18, 4]);
+        data.extend_from_slice(b"head");
+        {
+            let mut writer = ChunkWriter::new(&mut data);
+            writer.write_all(&vec![7u8; 300]).unwrap();
+            writer.write_all(b"end").unwrap();
+        }
+
+        let mut reader = BytesReader::from_bytes(&data);
+        let wrapper = StoredFileWrapper::from_reader(&mut reader, &data).unwrap();
+        assert_eq!(FileType::FileV1, wrapper.type_pb);
+        assert_eq!(b"head".as_ref(), wrapper.content.as_ref());
+        assert_eq!(2, wrapper.content_chunks.len());
+
+        let mut input = data.as_slice();
+        let (file_type, content) = read_wrapper_head(&mut input).unwrap();
+        assert_eq!(FileType::FileV1, file_type);
+        assert_eq!(b"head".to_vec(), content);
+        let mut chunks = Vec::new();
+        ChunkReader::new(input).read_to_end(&mut chunks).unwrap();
+        assert_eq!([vec![7u8; 300], b"end".to_vec()].concat(), chunks);
+    }
+
+    #[test]
+    fn test_truncated_chunk() {
+        let mut data = vec![8, 2, 18, 0];
+        ChunkWriter::new(&mut data).write_all(b"content").unwrap();
+        data.pop();
+
+        let mut input = data.as_slice();
+        read_wrapper_head(&mut input).unwrap();
+        assert!(ChunkReader::new(input).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_head() {
+        assert!(read_wrapper_head(&mut [8u8, 2].as_ref()).is_err());
+        assert!(read_wrapper_head(&mut [18u8, 10, 1, 2].as_ref()).is_err());
+    }
+}
@@ -2,19 +2,34 @@
 #![feature(universal_impl_trait)]
 #![feature(slice_concat_ext)]
 
-extern crate argon2rs;
+extern crate aes_gcm;
+extern crate argon2;
 extern crate chacha20_poly1305_aead;
+extern crate chacha20poly1305;
+extern crate flate2;
+#[cfg(target_os = "linux")]
+extern crate libc;
+extern crate hmac;
 #[macro_use]
 extern crate failure;
 extern crate log;
 extern crate quick_protobuf;
+extern crate rand;
+extern crate scrypt;
 extern crate sha1;
+extern crate sha2;
+extern crate subtle;
 extern crate uuid;
+extern crate zeroize;
 
+#[cfg(test)]
+extern crate tempdir;
 
-mod pb;
-mod files;
-mod repository;
-mod sync;
-mod crypt;
-mod error;
\ No newline at end of file
+
+pub mod pb;
+pub mod files;
+pub mod repository;
+pub mod sync;
+pub mod crypt;
+pub mod compression;
+pub mod error;
\ No newline at end of file
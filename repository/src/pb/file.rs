@@ -19,6 +19,8 @@ use super::*;
 pub enum FileType {
     RepositoryV1 = 1,
     FileV1 = 2,
+    SyncStateV1 = 3,
+    FileVersionV1 = 4,
 }
 
 impl Default for FileType {
@@ -32,6 +34,8 @@ impl From<i32> for FileType {
         match i {
             1 => FileType::RepositoryV1,
             2 => FileType::FileV1,
+            3 => FileType::SyncStateV1,
+            4 => FileType::FileVersionV1,
             _ => Self::default(),
         }
     }
@@ -42,6 +46,8 @@ impl<'a> From<&'a str> for FileType {
         match s {
             "RepositoryV1" => FileType::RepositoryV1,
             "FileV1" => FileType::FileV1,
+            "SyncStateV1" => FileType::SyncStateV1,
+            "FileVersionV1" => FileType::FileVersionV1,
             _ => Self::default(),
         }
     }
@@ -50,6 +56,8 @@ impl<'a> From<&'a str> for FileType {
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum EncryptionType {
     ChachaPoly1305 = 1,
+    AesGcm256 = 2,
+    XChachaPoly1305 = 3,
 }
 
 impl Default for EncryptionType {
@@ -62,6 +70,8 @@ impl From<i32> for EncryptionType {
     fn from(i: i32) -> Self {
         match i {
             1 => EncryptionType::ChachaPoly1305,
+            2 => EncryptionType::AesGcm256,
+            3 => EncryptionType::XChachaPoly1305,
             _ => Self::default(),
         }
     }
@@ -71,6 +81,8 @@ impl<'a> From<&'a str> for EncryptionType {
     fn from(s: &'a str) -> Self {
         match s {
             "ChachaPoly1305" => EncryptionType::ChachaPoly1305,
+            "AesGcm256" => EncryptionType::AesGcm256,
+            "XChachaPoly1305" => EncryptionType::XChachaPoly1305,
             _ => Self::default(),
         }
     }
@@ -79,6 +91,8 @@ impl<'a> From<&'a str> for EncryptionType {
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum PasswordHashType {
     Argon2i = 1,
+    Argon2id = 2,
+    SCrypt = 3,
 }
 
 impl Default for PasswordHashType {
@@ -91,6 +105,8 @@ impl From<i32> for PasswordHashType {
     fn from(i: i32) -> Self {
         match i {
             1 => PasswordHashType::Argon2i,
+            2 => PasswordHashType::Argon2id,
+            3 => PasswordHashType::SCrypt,
             _ => Self::default(),
         }
     }
@@ -100,6 +116,8 @@ impl<'a> From<&'a str> for PasswordHashType {
     fn from(s: &'a str) -> Self {
         match s {
             "Argon2i" => PasswordHashType::Argon2i,
+            "Argon2id" => PasswordHashType::Argon2id,
+            "SCrypt" => PasswordHashType::SCrypt,
             _ => Self::default(),
         }
     }
@@ -108,6 +126,7 @@ impl<'a> From<&'a str> for PasswordHashType {
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum CompressionType {
     DeflateZip = 1,
+    None = 2,
 }
 
 impl Default for CompressionType {
@@ -120,6 +139,7 @@ impl From<i32> for CompressionType {
     fn from(i: i32) -> Self {
         match i {
             1 => CompressionType::DeflateZip,
+            2 => CompressionType::None,
             _ => Self::default(),
         }
     }
@@ -129,6 +149,7 @@ impl<'a> From<&'a str> for CompressionType {
     fn from(s: &'a str) -> Self {
         match s {
             "DeflateZip" => CompressionType::DeflateZip,
+            "None" => CompressionType::None,
             _ => Self::default(),
         }
     }
@@ -138,6 +159,7 @@ impl<'a> From<&'a str> for CompressionType {
 pub struct StoredFileWrapper<'a> {
     pub type_pb: FileType,
     pub content: Cow<'a, [u8]>,
+    pub content_chunks: Vec<Cow<'a, [u8]>>,
 }
 
 impl<'a> MessageRead<'a> for StoredFileWrapper<'a> {
@@ -147,6 +169,7 @@ impl<'a> MessageRead<'a> for StoredFileWrapper<'a> {
             match r.next_tag(bytes) {
                 Ok(8) => msg.type_pb = r.read_enum(bytes)?,
                 Ok(18) => msg.content = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(26) => msg.content_chunks.push(r.read_bytes(bytes).map(Cow::Borrowed)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -160,11 +183,52 @@ impl<'a> MessageWrite for StoredFileWrapper<'a> {
         0
         + 1 + sizeof_varint(*(&self.type_pb) as u64)
         + 1 + sizeof_len((&self.content).len())
+        + self.content_chunks.iter().map(|s| 1 + sizeof_len((s).len())).sum::<usize>()
     }
 
     fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
         w.write_with_tag(8, |w| w.write_enum(*&self.type_pb as i32))?;
         w.write_with_tag(18, |w| w.write_bytes(&**&self.content))?;
+        for s in &self.content_chunks { w.write_with_tag(26, |w| w.write_bytes(&**s))?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct PasswordHashParameters {
+    pub iterations: u32,
+    pub memory_costs: u32,
+    pub parallelism: u32,
+}
+
+impl<'a> MessageRead<'a> for PasswordHashParameters {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(8) => msg.iterations = r.read_uint32(bytes)?,
+                Ok(16) => msg.memory_costs = r.read_uint32(bytes)?,
+                Ok(24) => msg.parallelism = r.read_uint32(bytes)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl MessageWrite for PasswordHashParameters {
+    fn get_size(&self) -> usize {
+        0
+        + 1 + sizeof_varint(*(&self.iterations) as u64)
+        + 1 + sizeof_varint(*(&self.memory_costs) as u64)
+        + 1 + sizeof_varint(*(&self.parallelism) as u64)
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        w.write_with_tag(8, |w| w.write_uint32(*&self.iterations))?;
+        w.write_with_tag(16, |w| w.write_uint32(*&self.memory_costs))?;
+        w.write_with_tag(24, |w| w.write_uint32(*&self.parallelism))?;
         Ok(())
     }
 }
@@ -180,6 +244,12 @@ pub struct StoredRepositoryV1<'a> {
     pub nonce: Cow<'a, [u8]>,
     pub encrypted_file_pw: Cow<'a, [u8]>,
     pub name: Cow<'a, str>,
+    pub pending_file_pw: Option<Cow<'a, [u8]>>,
+    pub hash_parameters: Option<PasswordHashParameters>,
+    pub user_name: Option<Cow<'a, str>>,
+    pub key_slots: Vec<KeySlot<'a>>,
+    pub kept_versions: Option<u32>,
+    pub max_version_age: Option<u64>,
 }
 
 impl<'a> MessageRead<'a> for StoredRepositoryV1<'a> {
@@ -196,6 +266,12 @@ impl<'a> MessageRead<'a> for StoredRepositoryV1<'a> {
                 Ok(58) => msg.nonce = r.read_bytes(bytes).map(Cow::Borrowed)?,
                 Ok(66) => msg.encrypted_file_pw = r.read_bytes(bytes).map(Cow::Borrowed)?,
                 Ok(74) => msg.name = r.read_string(bytes).map(Cow::Borrowed)?,
+                Ok(82) => msg.pending_file_pw = Some(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(90) => msg.hash_parameters = Some(r.read_message::<PasswordHashParameters>(bytes)?),
+                Ok(98) => msg.user_name = Some(r.read_string(bytes).map(Cow::Borrowed)?),
+                Ok(106) => msg.key_slots.push(r.read_message::<KeySlot>(bytes)?),
+                Ok(112) => msg.kept_versions = Some(r.read_uint32(bytes)?),
+                Ok(120) => msg.max_version_age = Some(r.read_uint64(bytes)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -216,6 +292,12 @@ impl<'a> MessageWrite for StoredRepositoryV1<'a> {
         + 1 + sizeof_len((&self.nonce).len())
         + 1 + sizeof_len((&self.encrypted_file_pw).len())
         + 1 + sizeof_len((&self.name).len())
+        + self.pending_file_pw.as_ref().map_or(0, |m| 1 + sizeof_len((m).len()))
+        + self.hash_parameters.as_ref().map_or(0, |m| 1 + sizeof_len((m).get_size()))
+        + self.user_name.as_ref().map_or(0, |m| 1 + sizeof_len((m).len()))
+        + self.key_slots.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
+        + self.kept_versions.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
+        + self.max_version_age.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
     }
 
     fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
@@ -228,6 +310,67 @@ impl<'a> MessageWrite for StoredRepositoryV1<'a> {
         w.write_with_tag(58, |w| w.write_bytes(&**&self.nonce))?;
         w.write_with_tag(66, |w| w.write_bytes(&**&self.encrypted_file_pw))?;
         w.write_with_tag(74, |w| w.write_string(&**&self.name))?;
+        if let Some(ref s) = self.pending_file_pw { w.write_with_tag(82, |w| w.write_bytes(&**s))?; }
+        if let Some(ref s) = self.hash_parameters { w.write_with_tag(90, |w| w.write_message(s))?; }
+        if let Some(ref s) = self.user_name { w.write_with_tag(98, |w| w.write_string(&**s))?; }
+        for s in &self.key_slots { w.write_with_tag(106, |w| w.write_message(s))?; }
+        if let Some(ref s) = self.kept_versions { w.write_with_tag(112, |w| w.write_uint32(*s))?; }
+        if let Some(ref s) = self.max_version_age { w.write_with_tag(120, |w| w.write_uint64(*s))?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct KeySlot<'a> {
+    pub user_name: Cow<'a, str>,
+    pub hash_type: PasswordHashType,
+    pub hash_parameters: Option<PasswordHashParameters>,
+    pub salt: Cow<'a, [u8]>,
+    pub double_hashed_pw: Cow<'a, [u8]>,
+    pub nonce: Cow<'a, [u8]>,
+    pub encrypted_file_pw: Cow<'a, [u8]>,
+}
+
+impl<'a> MessageRead<'a> for KeySlot<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.user_name = r.read_string(bytes).map(Cow::Borrowed)?,
+                Ok(16) => msg.hash_type = r.read_enum(bytes)?,
+                Ok(26) => msg.hash_parameters = Some(r.read_message::<PasswordHashParameters>(bytes)?),
+                Ok(34) => msg.salt = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(42) => msg.double_hashed_pw = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(50) => msg.nonce = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(58) => msg.encrypted_file_pw = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for KeySlot<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + 1 + sizeof_len((&self.user_name).len())
+        + 1 + sizeof_varint(*(&self.hash_type) as u64)
+        + self.hash_parameters.as_ref().map_or(0, |m| 1 + sizeof_len((m).get_size()))
+        + 1 + sizeof_len((&self.salt).len())
+        + 1 + sizeof_len((&self.double_hashed_pw).len())
+        + 1 + sizeof_len((&self.nonce).len())
+        + 1 + sizeof_len((&self.encrypted_file_pw).len())
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        w.write_with_tag(10, |w| w.write_string(&**&self.user_name))?;
+        w.write_with_tag(16, |w| w.write_enum(*&self.hash_type as i32))?;
+        if let Some(ref s) = self.hash_parameters { w.write_with_tag(26, |w| w.write_message(s))?; }
+        w.write_with_tag(34, |w| w.write_bytes(&**&self.salt))?;
+        w.write_with_tag(42, |w| w.write_bytes(&**&self.double_hashed_pw))?;
+        w.write_with_tag(50, |w| w.write_bytes(&**&self.nonce))?;
+        w.write_with_tag(58, |w| w.write_bytes(&**&self.encrypted_file_pw))?;
         Ok(())
     }
 }
@@ -243,6 +386,10 @@ pub struct StoredFileV1<'a> {
     pub nonce_content: Cow<'a, [u8]>,
     pub encrypted_header: Cow<'a, [u8]>,
     pub encrypted_content: Cow<'a, [u8]>,
+    pub segment_size: Option<u32>,
+    pub version_vector: Vec<DeviceVersion<'a>>,
+    pub modified: Option<u64>,
+    pub deleted: Option<u64>,
 }
 
 impl<'a> MessageRead<'a> for StoredFileV1<'a> {
@@ -259,6 +406,10 @@ impl<'a> MessageRead<'a> for StoredFileV1<'a> {
                 Ok(58) => msg.nonce_content = r.read_bytes(bytes).map(Cow::Borrowed)?,
                 Ok(66) => msg.encrypted_header = r.read_bytes(bytes).map(Cow::Borrowed)?,
                 Ok(74) => msg.encrypted_content = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(80) => msg.segment_size = Some(r.read_uint32(bytes)?),
+                Ok(90) => msg.version_vector.push(r.read_message::<DeviceVersion>(bytes)?),
+                Ok(96) => msg.modified = Some(r.read_uint64(bytes)?),
+                Ok(104) => msg.deleted = Some(r.read_uint64(bytes)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -279,6 +430,10 @@ impl<'a> MessageWrite for StoredFileV1<'a> {
         + 1 + sizeof_len((&self.nonce_content).len())
         + 1 + sizeof_len((&self.encrypted_header).len())
         + 1 + sizeof_len((&self.encrypted_content).len())
+        + self.segment_size.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
+        + self.version_vector.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
+        + self.modified.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
+        + self.deleted.as_ref().map_or(0, |m| 1 + sizeof_varint(*(m) as u64))
     }
 
     fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
@@ -291,7 +446,197 @@ impl<'a> MessageWrite for StoredFileV1<'a> {
         w.write_with_tag(58, |w| w.write_bytes(&**&self.nonce_content))?;
         w.write_with_tag(66, |w| w.write_bytes(&**&self.encrypted_header))?;
         w.write_with_tag(74, |w| w.write_bytes(&**&self.encrypted_content))?;
+        if let Some(ref s) = self.segment_size { w.write_with_tag(80, |w| w.write_uint32(*s))?; }
+        for s in &self.version_vector { w.write_with_tag(90, |w| w.write_message(s))?; }
+        if let Some(ref s) = self.modified { w.write_with_tag(96, |w| w.write_uint64(*s))?; }
+        if let Some(ref s) = self.deleted { w.write_with_tag(104, |w| w.write_uint64(*s))?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DeviceVersion<'a> {
+    pub device_id: Cow<'a, [u8]>,
+    pub version: u32,
+}
+
+impl<'a> MessageRead<'a> for DeviceVersion<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.device_id = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(16) => msg.version = r.read_uint32(bytes)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for DeviceVersion<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + 1 + sizeof_len((&self.device_id).len())
+        + 1 + sizeof_varint(*(&self.version) as u64)
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        w.write_with_tag(10, |w| w.write_bytes(&**&self.device_id))?;
+        w.write_with_tag(16, |w| w.write_uint32(*&self.version))?;
         Ok(())
     }
 }
 
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct StoredSyncStateV1<'a> {
+    pub repository_id: Cow<'a, [u8]>,
+    pub peers: Vec<PeerSync<'a>>,
+    pub tombstones: Vec<LocalTombstone<'a>>,
+    pub device_id: Option<Cow<'a, [u8]>>,
+    pub versions: Vec<LocalVersion<'a>>,
+}
+
+impl<'a> MessageRead<'a> for StoredSyncStateV1<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.repository_id = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(18) => msg.peers.push(r.read_message::<PeerSync>(bytes)?),
+                Ok(26) => msg.tombstones.push(r.read_message::<LocalTombstone>(bytes)?),
+                Ok(34) => msg.device_id = Some(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(42) => msg.versions.push(r.read_message::<LocalVersion>(bytes)?),
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for StoredSyncStateV1<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + 1 + sizeof_len((&self.repository_id).len())
+        + self.peers.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
+        + self.tombstones.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
+        + self.device_id.as_ref().map_or(0, |m| 1 + sizeof_len((m).len()))
+        + self.versions.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        w.write_with_tag(10, |w| w.write_bytes(&**&self.repository_id))?;
+        for s in &self.peers { w.write_with_tag(18, |w| w.write_message(s))?; }
+        for s in &self.tombstones { w.write_with_tag(26, |w| w.write_message(s))?; }
+        if let Some(ref s) = self.device_id { w.write_with_tag(34, |w| w.write_bytes(&**s))?; }
+        for s in &self.versions { w.write_with_tag(42, |w| w.write_message(s))?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct PeerSync<'a> {
+    pub device_id: Cow<'a, [u8]>,
+    pub last_sync: u64,
+}
+
+impl<'a> MessageRead<'a> for PeerSync<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.device_id = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(16) => msg.last_sync = r.read_uint64(bytes)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for PeerSync<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + 1 + sizeof_len((&self.device_id).len())
+        + 1 + sizeof_varint(*(&self.last_sync) as u64)
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        w.write_with_tag(10, |w| w.write_bytes(&**&self.device_id))?;
+        w.write_with_tag(16, |w| w.write_uint64(*&self.last_sync))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct LocalTombstone<'a> {
+    pub file_id: Cow<'a, [u8]>,
+    pub since: u64,
+}
+
+impl<'a> MessageRead<'a> for LocalTombstone<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.file_id = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(16) => msg.since = r.read_uint64(bytes)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for LocalTombstone<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + 1 + sizeof_len((&self.file_id).len())
+        + 1 + sizeof_varint(*(&self.since) as u64)
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        w.write_with_tag(10, |w| w.write_bytes(&**&self.file_id))?;
+        w.write_with_tag(16, |w| w.write_uint64(*&self.since))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct LocalVersion<'a> {
+    pub file_id: Cow<'a, [u8]>,
+    pub version_vector: Vec<DeviceVersion<'a>>,
+}
+
+impl<'a> MessageRead<'a> for LocalVersion<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.file_id = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(18) => msg.version_vector.push(r.read_message::<DeviceVersion>(bytes)?),
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for LocalVersion<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + 1 + sizeof_len((&self.file_id).len())
+        + self.version_vector.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        w.write_with_tag(10, |w| w.write_bytes(&**&self.file_id))?;
+        for s in &self.version_vector { w.write_with_tag(18, |w| w.write_message(s))?; }
+        Ok(())
+    }
+}
@@ -57,6 +57,7 @@ impl<'a> MessageWrite for SingleFileSync<'a> {
 #[derive(Debug, Default, PartialEq, Clone)]
 pub struct SynchronizationBucket<'a> {
     pub file_syncs: Vec<SingleFileSync<'a>>,
+    pub divisions: Vec<Subdivision>,
 }
 
 impl<'a> MessageRead<'a> for SynchronizationBucket<'a> {
@@ -65,6 +66,7 @@ impl<'a> MessageRead<'a> for SynchronizationBucket<'a> {
         while !r.is_eof() {
             match r.next_tag(bytes) {
                 Ok(10) => msg.file_syncs.push(r.read_message::<SingleFileSync>(bytes)?),
+                Ok(18) => msg.divisions.push(r.read_message::<Subdivision>(bytes)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -77,10 +79,12 @@ impl<'a> MessageWrite for SynchronizationBucket<'a> {
     fn get_size(&self) -> usize {
         0
         + self.file_syncs.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
+        + self.divisions.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
     }
 
     fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
         for s in &self.file_syncs { w.write_with_tag(10, |w| w.write_message(s))?; }
+        for s in &self.divisions { w.write_with_tag(18, |w| w.write_message(s))?; }
         Ok(())
     }
 }
@@ -162,6 +166,7 @@ impl MessageWrite for Subdivision {
 #[derive(Debug, Default, PartialEq, Clone)]
 pub struct Synchronization<'a> {
     pub buckets: Vec<HashBucket<'a>>,
+    pub file_buckets: Vec<SynchronizationBucket<'a>>,
 }
 
 impl<'a> MessageRead<'a> for Synchronization<'a> {
@@ -170,6 +175,7 @@ impl<'a> MessageRead<'a> for Synchronization<'a> {
         while !r.is_eof() {
             match r.next_tag(bytes) {
                 Ok(10) => msg.buckets.push(r.read_message::<HashBucket>(bytes)?),
+                Ok(18) => msg.file_buckets.push(r.read_message::<SynchronizationBucket>(bytes)?),
                 Ok(t) => { r.read_unknown(bytes, t)?; }
                 Err(e) => return Err(e),
             }
@@ -182,11 +188,125 @@ impl<'a> MessageWrite for Synchronization<'a> {
     fn get_size(&self) -> usize {
         0
         + self.buckets.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
+        + self.file_buckets.iter().map(|s| 1 + sizeof_len((s).get_size())).sum::<usize>()
     }
 
     fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
         for s in &self.buckets { w.write_with_tag(10, |w| w.write_message(s))?; }
+        for s in &self.file_buckets { w.write_with_tag(18, |w| w.write_message(s))?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SyncHandshake<'a> {
+    pub repository_id: Option<Cow<'a, [u8]>>,
+    pub challenge: Option<Cow<'a, [u8]>>,
+    pub proof: Option<Cow<'a, [u8]>>,
+    pub device_id: Option<Cow<'a, [u8]>>,
+}
+
+impl<'a> MessageRead<'a> for SyncHandshake<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.repository_id = Some(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(18) => msg.challenge = Some(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(26) => msg.proof = Some(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(34) => msg.device_id = Some(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for SyncHandshake<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + self.repository_id.as_ref().map_or(0, |m| 1 + sizeof_len((m).len()))
+        + self.challenge.as_ref().map_or(0, |m| 1 + sizeof_len((m).len()))
+        + self.proof.as_ref().map_or(0, |m| 1 + sizeof_len((m).len()))
+        + self.device_id.as_ref().map_or(0, |m| 1 + sizeof_len((m).len()))
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        if let Some(ref s) = self.repository_id { w.write_with_tag(10, |w| w.write_bytes(&**s))?; }
+        if let Some(ref s) = self.challenge { w.write_with_tag(18, |w| w.write_bytes(&**s))?; }
+        if let Some(ref s) = self.proof { w.write_with_tag(26, |w| w.write_bytes(&**s))?; }
+        if let Some(ref s) = self.device_id { w.write_with_tag(34, |w| w.write_bytes(&**s))?; }
         Ok(())
     }
 }
 
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct FileChunk<'a> {
+    pub file_id: Cow<'a, [u8]>,
+    pub data: Cow<'a, [u8]>,
+    pub last: bool,
+}
+
+impl<'a> MessageRead<'a> for FileChunk<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.file_id = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(18) => msg.data = r.read_bytes(bytes).map(Cow::Borrowed)?,
+                Ok(24) => msg.last = r.read_bool(bytes)?,
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for FileChunk<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + 1 + sizeof_len((&self.file_id).len())
+        + 1 + sizeof_len((&self.data).len())
+        + 1 + sizeof_varint(*(&self.last) as u64)
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        w.write_with_tag(10, |w| w.write_bytes(&**&self.file_id))?;
+        w.write_with_tag(18, |w| w.write_bytes(&**&self.data))?;
+        w.write_with_tag(24, |w| w.write_bool(*&self.last))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct FileRequest<'a> {
+    pub file_ids: Vec<Cow<'a, [u8]>>,
+}
+
+impl<'a> MessageRead<'a> for FileRequest<'a> {
+    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
+        let mut msg = Self::default();
+        while !r.is_eof() {
+            match r.next_tag(bytes) {
+                Ok(10) => msg.file_ids.push(r.read_bytes(bytes).map(Cow::Borrowed)?),
+                Ok(t) => { r.read_unknown(bytes, t)?; }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl<'a> MessageWrite for FileRequest<'a> {
+    fn get_size(&self) -> usize {
+        0
+        + self.file_ids.iter().map(|s| 1 + sizeof_len((s).len())).sum::<usize>()
+    }
+
+    fn write_message<W: Write>(&self, w: &mut Writer<W>) -> Result<()> {
+        for s in &self.file_ids { w.write_with_tag(10, |w| w.write_bytes(&**s))?; }
+        Ok(())
+    }
+}
@@ -1,13 +1,28 @@
 use uuid::Uuid;
 
-use super::repository::RepositoryId;
-use ::pb::file::{EncryptionType,CompressionType};
-use ::files::StoredFileName;
-use ::crypt::{CipherTextVec,Nonce};
+use super::repository::{Repository, RepositoryId};
+use super::{read_message, wrap_message, write_message};
+use super::version::{ConflictResolution, VersionOrdering, VersionVector};
+use ::error::{is_not_found, ErrorKind, FileContext};
+use ::pb::file::{EncryptionType, CompressionType, FileType, StoredFileV1, StoredFileWrapper};
+use ::files::{FileSource, StoredFileName};
+use ::files::wrapper::{read_wrapper_head, ChunkReader, ChunkWriter};
+use ::compression::{Compressor, LimitedReader, MAX_DECOMPRESSED_SIZE};
+use ::crypt::{AuthTagProvider, DeEncrypter, DecryptingReader, EncryptingWriter, Nonce, Plaintext, PlaintextVec, random_bytes};
+use ::crypt::stream::DEFAULT_SEGMENT_SIZE;
+use ::sync::SingleFileSync;
+use failure::Error;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub type FileId = Uuid;
 pub type FileVersion = u32;
 
+/// Prefix of a received file while its content gets authenticated.
+const RECEIVED_PREFIX: &str = "received-";
+
 #[derive(Debug)]
 pub struct RepositoryFile {
     pub id: FileId,
@@ -16,4 +31,946 @@ pub struct RepositoryFile {
     pub file_name: StoredFileName,
     pub encryption_type: EncryptionType,
     pub compression_type: CompressionType,
-}
\ No newline at end of file
+    pub version_vector: VersionVector,
+    /// Milliseconds since the unix epoch when this version was written, 0 for files written before it was stored
+    pub modified: u64,
+    /// Set for a tombstone, milliseconds since the unix epoch when the file was deleted
+    pub deleted: Option<u64>,
+}
+
+impl RepositoryFile {
+    pub fn from_stored(file_name: &str, stored: &StoredFileV1) -> Result<Self, Error> {
+        Ok(RepositoryFile {
+            id: FileId::from_bytes(stored.id.as_ref())?,
+            version: stored.version,
+            repository_id: RepositoryId::from_bytes(stored.repository_id.as_ref())?,
+            file_name: file_name.into(),
+            encryption_type: stored.encryption_type,
+            compression_type: stored.compression_type,
+            version_vector: VersionVector::from_stored(stored)?,
+            modified: stored.modified.unwrap_or(0),
+            deleted: stored.deleted,
+        })
+    }
+
+    /// The next version of this file written on the given device.
+    fn next_version(&self, repo: &Repository, version_vector: VersionVector) -> Result<Self, Error> {
+        Ok(RepositoryFile {
+            id: self.id,
+            version: version_vector.version()?,
+            repository_id: repo.id,
+            file_name: self.file_name.clone(),
+            encryption_type: repo.encryption_type,
+            compression_type: self.compression_type,
+            version_vector,
+            modified: now_millis(),
+            deleted: None,
+        })
+    }
+}
+
+/// What happened to a file received from a peer.
+#[derive(Debug)]
+pub enum ImportResult {
+    /// The received version replaced the local one or there was none
+    Stored(RepositoryFile),
+    /// The local version won a conflict and was kept
+    KeptLocal,
+    /// The received version of a conflict was stored as new file `copy`,
+    /// the local version was stored again as `local` which supersedes the received one
+    ConflictCopy { copy: RepositoryFile, local: RepositoryFile },
+    /// A conflict was left untouched
+    Deferred,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilePart {
+    Header,
+    Content,
+}
+
+impl Repository {
+    /// Lists all content files in the file source that belong to this repository, deleted files are left out.
+    /// The header of every listed file gets decrypted, which authenticates the version it is listed with.
+    /// Fails with `RolledBack` if a file is older than the version last written or received here.
+    pub fn list_files(&self, source: &impl FileSource) -> Result<Vec<RepositoryFile>, Error> {
+        let noted = self.noted_versions(source)?;
+        let mut files = Vec::new();
+        for name in source.list_files()? {
+            let file = load_stored_file(source, &name, |stored| {
+                let file = RepositoryFile::from_stored(&name, &stored)?;
+                if file.repository_id != self.id || file.deleted.is_some() {
+                    return Ok(None);
+                }
+                self.decrypt_part(&file, FilePart::Header, stored.nonce_header.as_ref(), stored.encrypted_header.as_ref())?;
+                self.check_not_rolled_back(&file, &stored, &noted)?;
+                Ok(Some(file))
+            })?;
+            files.extend(file);
+        }
+        Ok(files)
+    }
+
+    /// Lists all content files of this repository including the tombstones of deleted files, see `list_files`.
+    pub fn list_all_files(&self, source: &impl FileSource) -> Result<Vec<RepositoryFile>, Error> {
+        let noted = self.noted_versions(source)?;
+        let mut files = Vec::new();
+        for name in source.list_files()? {
+            let file = load_stored_file(source, &name, |stored| {
+                let file = RepositoryFile::from_stored(&name, &stored)?;
+                if file.repository_id != self.id {
+                    return Ok(None);
+                }
+                self.check_not_rolled_back(&file, &stored, &noted)?;
+                Ok(Some(file))
+            })?;
+            files.extend(file);
+        }
+        Ok(files)
+    }
+
+    /// Loads the file with the given id, which might be a tombstone.
+    /// Fails with `RolledBack` if it is older than the version last written or received here.
+    pub fn get_file(&self, source: &impl FileSource, id: FileId) -> Result<RepositoryFile, Error> {
+        let file_name = id.hyphenated().to_string();
+        let noted = self.noted_versions(source)?;
+        load_stored_file(source, &file_name, |stored| {
+            let file = RepositoryFile::from_stored(&file_name, &stored)?;
+            if file.repository_id != self.id {
+                return Err(Error::from(ErrorKind::WrongRepository { file_name: file_name.clone(), expected: self.id, found: file.repository_id }));
+            }
+            self.check_not_rolled_back(&file, &stored, &noted)?;
+            Ok(file)
+        })
+    }
+
+    /// Catches a stored file that was replaced by an older copy of itself, which still authenticates.
+    /// The file has to be at least as new as the version vector noted when it was last written or received here,
+    /// files without a noted version are accepted. A forged version vector fails authentication instead.
+    fn check_not_rolled_back(&self, file: &RepositoryFile, stored: &StoredFileV1, noted: &BTreeMap<FileId, VersionVector>) -> Result<(), Error> {
+        match noted.get(&file.id).map(|noted| file.version_vector.compare(noted)) {
+            Some(VersionOrdering::Older) | Some(VersionOrdering::Concurrent) => {
+                self.decrypt_part(file, FilePart::Header, stored.nonce_header.as_ref(), stored.encrypted_header.as_ref())?;
+                Err(Error::from(ErrorKind::RolledBack { file_name: file.file_name.clone() }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Lists the sync state of all files of this repository, tombstones included.
+    /// The hash covers the stored metadata including the random nonces, so two versions written independently never share it.
+    pub fn list_file_syncs(&self, source: &impl FileSource) -> Result<Vec<SingleFileSync>, Error> {
+        use sha1::{Digest, Sha1};
+
+        let mut syncs = Vec::new();
+        for name in source.list_files()? {
+            let (content, _) = open_stored_file(source, &name)?;
+            let stored: StoredFileV1 = read_message(&content).in_file(&name)?;
+            if stored.repository_id.as_ref() == self.id.as_bytes() {
+                syncs.push(SingleFileSync {
+                    id: FileId::from_bytes(stored.id.as_ref())?,
+                    version: stored.version,
+                    hash: Sha1::digest(&content).to_vec(),
+                });
+            }
+        }
+        Ok(syncs)
+    }
+
+    /// Creates a new file. The content is compressed with the given compression type before it gets encrypted,
+    /// use `CompressionType::None` for content that is already compressed like images.
+    pub fn create_file(&self, source: &mut impl FileSource, header: &Plaintext, content: &Plaintext, compression_type: CompressionType) -> Result<RepositoryFile, Error> {
+        self.create_file_from(source, header, &mut &content[..], compression_type)
+    }
+
+    /// Creates a new file with the content read from the given stream, it is never held in memory as a whole.
+    pub fn create_file_from(&self, source: &mut impl FileSource, header: &Plaintext, content: &mut impl Read, compression_type: CompressionType) -> Result<RepositoryFile, Error> {
+        let id = FileId::new_v4();
+        let file = RepositoryFile {
+            id,
+            version: 0,
+            repository_id: self.id,
+            file_name: id.hyphenated().to_string(),
+            encryption_type: self.encryption_type,
+            compression_type,
+            version_vector: VersionVector::default(),
+            modified: now_millis(),
+            deleted: None,
+        };
+        self.store_file(source, &file, header, content)?;
+        Ok(file)
+    }
+
+    /// Stores a new version of the file.
+    /// Fails if the stored version is not the one given, e.g. because it was updated in between.
+    pub fn update_file(&self, source: &mut impl FileSource, file: &RepositoryFile, header: &Plaintext, content: &Plaintext) -> Result<RepositoryFile, Error> {
+        self.update_file_from(source, file, header, &mut &content[..])
+    }
+
+    /// Stores a new version of the file with the content read from the given stream, see `update_file`.
+    pub fn update_file_from(&self, source: &mut impl FileSource, file: &RepositoryFile, header: &Plaintext, content: &mut impl Read) -> Result<RepositoryFile, Error> {
+        check_stored_version(source, file)?;
+        let updated = file.next_version(self, file.version_vector.increment(self.device_id))?;
+        self.replace_file(source, file, &updated, header, content)?;
+        Ok(updated)
+    }
+
+    /// Replaces the file with a tombstone, which removes it from the peers on the next sync.
+    /// The tombstone stays until `purge_tombstones` finds that all known peers have seen it.
+    /// Updating the tombstone brings the file back.
+    pub fn delete_file(&self, source: &mut impl FileSource, file: &RepositoryFile) -> Result<RepositoryFile, Error> {
+        check_stored_version(source, file)?;
+        let mut tombstone = file.next_version(self, file.version_vector.increment(self.device_id))?;
+        tombstone.deleted = Some(tombstone.modified);
+        self.replace_file(source, file, &tombstone, &[], &mut io::empty())?;
+        self.note_tombstone(source, tombstone.id, tombstone.modified)?;
+        Ok(tombstone)
+    }
+
+    pub fn read_header(&self, source: &impl FileSource, file: &RepositoryFile) -> Result<PlaintextVec, Error> {
+        load_stored_file(source, &file.file_name, |stored| {
+            self.decrypt_part(file, FilePart::Header, stored.nonce_header.as_ref(), stored.encrypted_header.as_ref())
+        })
+    }
+
+    /// Decrypts the content into memory that is wiped when dropped, including the buffers it outgrew.
+    /// Contents of more than `MAX_DECOMPRESSED_SIZE` bytes fail with `InvalidData`, stream them with `content_reader` instead.
+    pub fn read_content(&self, source: &impl FileSource, file: &RepositoryFile) -> Result<PlaintextVec, Error> {
+        self.read_content_limited(source, file, MAX_DECOMPRESSED_SIZE)
+    }
+
+    fn read_content_limited(&self, source: &impl FileSource, file: &RepositoryFile, limit: u64) -> Result<PlaintextVec, Error> {
+        let mut content = PlaintextVec::default();
+        let mut reader = LimitedReader::new(self.content_reader(source, file)?, limit);
+        io::copy(&mut reader, &mut content).in_file(&file.file_name)?;
+        Ok(content)
+    }
+
+    /// Decrypts the content into the given stream and returns the number of bytes written.
+    pub fn read_content_to(&self, source: &impl FileSource, file: &RepositoryFile, output: &mut impl Write) -> Result<u64, Error> {
+        let mut reader = self.content_reader(source, file)?;
+        io::copy(&mut reader, output).in_file(&file.file_name)
+    }
+
+    /// Opens the content as stream, it gets decrypted and decompressed while reading.
+    /// Contents stored in segments are authenticated segment by segment,
+    /// so a manipulated file results in an error after the data before the manipulated segment was read.
+    pub fn content_reader(&self, source: &impl FileSource, file: &RepositoryFile) -> Result<Box<dyn Read>, Error> {
+        let (content, rest) = open_stored_file(source, &file.file_name)?;
+        let stored: StoredFileV1 = read_message(&content).in_file(&file.file_name)?;
+        self.stored_content_reader(file, &stored, rest).in_file(&file.file_name)
+    }
+
+    fn stored_content_reader<'r>(&self, file: &RepositoryFile, stored: &StoredFileV1, rest: impl Read + 'r) -> Result<Box<dyn Read + 'r>, Error> {
+        let decrypted: Box<dyn Read + 'r> = match stored.segment_size {
+            Some(segment_size) => {
+                let aad = self.file_aad(file, FilePart::Content);
+                Box::new(DecryptingReader::new(ChunkReader::new(rest), file.encryption_type, self.file_pw.clone(), &stored.nonce_content, &aad, segment_size)?)
+            }
+            None => Box::new(Cursor::new(self.decrypt_part(file, FilePart::Content, stored.nonce_content.as_ref(), stored.encrypted_content.as_ref())?)),
+        };
+        Ok(file.compression_type.decompressing_reader(decrypted))
+    }
+
+    /// Opens the file as it is stored, e.g. to send it to a peer.
+    /// Only content files of this repository are exported, never the repository file or anything else in the source.
+    pub fn export_stored_file(&self, source: &impl FileSource, id: FileId) -> Result<Box<dyn Read>, Error> {
+        let file_name = id.hyphenated().to_string();
+        let mut input = source.open_file(&file_name).in_file(&file_name)?;
+        let (file_type, content) = read_wrapper_head(&mut input).in_file(&file_name)?;
+        if file_type != FileType::FileV1 {
+            return Err(Error::from(ErrorKind::UnexpectedFileType { file_name, file_type }));
+        }
+        let file = read_message(&content).and_then(|stored| RepositoryFile::from_stored(&file_name, &stored)).in_file(&file_name)?;
+        if file.repository_id != self.id {
+            return Err(Error::from(ErrorKind::WrongRepository { file_name, expected: self.id, found: file.repository_id }));
+        }
+        if file.id != id {
+            return Err(Error::from(ErrorKind::CorruptedFile { file_name, reason: format!("stored as {} but contains {}", id, file.id) }));
+        }
+        let head = write_message(&StoredFileWrapper { type_pb: file_type, content: Cow::from(content.as_slice()), content_chunks: Vec::new() })?;
+        Ok(Box::new(Cursor::new(head).chain(input)))
+    }
+
+    /// Stores a file received from a peer as it is, it stays encrypted with the file key.
+    /// The file has to belong to this repository, have the expected id and an authentic header.
+    /// It is written under a temporary name while its content gets authenticated,
+    /// the local version is only archived and replaced once all of it proved authentic.
+    ///
+    /// A received version that was changed concurrently to the local one is handled as the resolution says,
+    /// one that is older than or equal to the local one is rejected with `StaleFile`.
+    /// The input is read to its end unless another error occurs, so it can be followed by further data.
+    pub fn import_stored_file(&self, source: &mut impl FileSource, id: FileId, input: &mut impl Read, resolution: ConflictResolution) -> Result<ImportResult, Error> {
+        let file_name = id.hyphenated().to_string();
+        let (file_type, content) = read_wrapper_head(input).in_file(&file_name)?;
+        if file_type != FileType::FileV1 {
+            return Err(Error::from(ErrorKind::UnexpectedFileType { file_name, file_type }));
+        }
+        let stored: StoredFileV1 = read_message(&content).in_file(&file_name)?;
+        let file = RepositoryFile::from_stored(&file_name, &stored).in_file(&file_name)?;
+        if file.repository_id != self.id {
+            return Err(Error::from(ErrorKind::WrongRepository { file_name, expected: self.id, found: file.repository_id }));
+        }
+        if file.id != id {
+            return Err(Error::from(ErrorKind::CorruptedFile { file_name, reason: format!("received as {} but contains {}", id, file.id) }));
+        }
+        let header = self.decrypt_part(&file, FilePart::Header, stored.nonce_header.as_ref(), stored.encrypted_header.as_ref())?;
+
+        let local = load_local_file(source, &file_name)?;
+        if let Some((ref local, ref local_content)) = local {
+            match file.version_vector.compare(&local.version_vector) {
+                VersionOrdering::Newer => {}
+                VersionOrdering::Equal | VersionOrdering::Older => {
+                    io::copy(input, &mut io::sink())?;
+                    return Err(Error::from(ErrorKind::StaleFile { file_id: id, local_version: local.version, received_version: file.version }));
+                }
+                VersionOrdering::Concurrent => match resolution {
+                    ConflictResolution::LastWriterWins => {
+                        if !last_written(&file, &content, local, local_content) {
+                            io::copy(input, &mut io::sink())?;
+                            return Ok(ImportResult::KeptLocal);
+                        }
+                    }
+                    ConflictResolution::ConflictCopy if local.deleted.is_some() => {}
+                    ConflictResolution::ConflictCopy if file.deleted.is_some() => {
+                        io::copy(input, &mut io::sink())?;
+                        self.supersede(source, local, &file)?;
+                        return Ok(ImportResult::KeptLocal);
+                    }
+                    ConflictResolution::ConflictCopy => {
+                        let copy = {
+                            let mut received_content = self.stored_content_reader(&file, &stored, &mut *input)?;
+                            self.create_file_from(source, &header, &mut received_content, file.compression_type)?
+                        };
+                        io::copy(input, &mut io::sink())?;
+                        let local = self.supersede(source, local, &file)?;
+                        return Ok(ImportResult::ConflictCopy { copy, local });
+                    }
+                    ConflictResolution::Defer => {
+                        io::copy(input, &mut io::sink())?;
+                        return Ok(ImportResult::Deferred);
+                    }
+                },
+            }
+        }
+
+        let head = write_message(&StoredFileWrapper { type_pb: file_type, content: Cow::from(content.as_slice()), content_chunks: Vec::new() })?;
+        let received_name = format!("{}{}", RECEIVED_PREFIX, file_name);
+        source.store_file_from(&received_name, &mut |out| {
+            out.write_all(&head)?;
+            let mut received = TeeReader { input: &mut *input, copy: &mut *out };
+            io::copy(&mut self.stored_content_reader(&file, &stored, &mut received)?, &mut io::sink())?;
+            io::copy(&mut received, &mut io::sink())?;
+            Ok(())
+        }).in_file(&file_name)?;
+        if let Some((local, _)) = local {
+            self.archive_file(source, &local)?;
+        }
+        source.rename_file(&received_name, &file_name).in_file(&file_name)?;
+        self.note_version(source, id, &file.version_vector)?;
+        if file.deleted.is_some() {
+            self.note_tombstone(source, id, now_millis())?;
+        }
+        self.prune_history(source, id)?;
+        Ok(ImportResult::Stored(file))
+    }
+
+    /// Stores the local version of a conflict again with a version that contains the changes of both.
+    fn supersede(&self, source: &mut impl FileSource, local: &RepositoryFile, received: &RepositoryFile) -> Result<RepositoryFile, Error> {
+        let header = self.read_header(source, local)?;
+        let mut content = self.content_reader(source, local)?;
+        let merged = local.next_version(self, local.version_vector.merge(&received.version_vector).increment(self.device_id))?;
+        self.replace_file(source, local, &merged, &header, &mut content)?;
+        Ok(merged)
+    }
+
+    /// Stores the next version of a file and keeps the current one in the history.
+    /// The device id gets stored first, the version vector of `next` counts on it.
+    fn replace_file(&self, source: &mut impl FileSource, current: &RepositoryFile, next: &RepositoryFile, header: &Plaintext, content: &mut impl Read) -> Result<(), Error> {
+        self.remember_device_id(source)?;
+        self.archive_file(source, current)?;
+        self.store_file(source, next, header, content)?;
+        self.prune_history(source, next.id)
+    }
+
+    fn store_file(&self, source: &mut impl FileSource, file: &RepositoryFile, header: &Plaintext, content: &mut impl Read) -> Result<(), Error> {
+        self.store_file_as(source, file, FileType::FileV1, header, content)?;
+        self.note_version(source, file.id, &file.version_vector)
+    }
+
+    /// Writes the header and the metadata into the wrapper content and the compressed and encrypted content as chunks behind it.
+    pub fn store_file_as(&self, source: &mut impl FileSource, file: &RepositoryFile, file_type: FileType, header: &Plaintext, content: &mut impl Read) -> Result<(), Error> {
+        let nonce_header = random_bytes(file.encryption_type.nonce_length())?;
+        let nonce_content = random_bytes(file.encryption_type.nonce_length())?;
+        let encrypted_header = self.encrypt_part(file, FilePart::Header, &nonce_header, header)?;
+        let aad = self.file_aad(file, FilePart::Content);
+
+        let stored = StoredFileV1 {
+            id: Cow::from(file.id.as_bytes().as_ref()),
+            version: file.version,
+            repository_id: Cow::from(file.repository_id.as_bytes().as_ref()),
+            encryption_type: file.encryption_type,
+            compression_type: file.compression_type,
+            nonce_header: Cow::from(nonce_header),
+            nonce_content: Cow::from(nonce_content.as_slice()),
+            encrypted_header: Cow::from(encrypted_header),
+            encrypted_content: Cow::from(&[][..]),
+            segment_size: Some(DEFAULT_SEGMENT_SIZE),
+            version_vector: file.version_vector.to_stored(),
+            modified: Some(file.modified),
+            deleted: file.deleted,
+        };
+        let head = wrap_message(file_type, &stored)?;
+        let mut compressed = file.compression_type.compressing_reader(content);
+
+        source.store_file_from(&file.file_name, &mut |out| {
+            out.write_all(&head)?;
+            let mut writer = EncryptingWriter::new(ChunkWriter::new(out), file.encryption_type, self.file_pw.clone(), &nonce_content, &aad, DEFAULT_SEGMENT_SIZE)?;
+            io::copy(&mut compressed, &mut writer)?;
+            writer.finish()?;
+            Ok(())
+        }).in_file(&file.file_name)
+    }
+
+    fn encrypt_part(&self, file: &RepositoryFile, part: FilePart, nonce: &Nonce, input: &Plaintext) -> Result<Vec<u8>, Error> {
+        let aad = self.file_aad(file, part);
+        let (mut encrypted, mut tag) = file.encryption_type.encrypt(&self.file_pw, nonce, &aad, input)?;
+        encrypted.append(&mut tag);
+        Ok(encrypted)
+    }
+
+    fn decrypt_part(&self, file: &RepositoryFile, part: FilePart, nonce: &Nonce, input: &[u8]) -> Result<PlaintextVec, Error> {
+        let aad = self.file_aad(file, part);
+        let (data, tag) = file.encryption_type.get_auth_tag(input).in_file(&file.file_name)?;
+        file.encryption_type.decrypt(&self.file_pw, nonce, &aad, tag, data).in_file(&file.file_name)
+    }
+
+    /// Binds the ciphertext to the file id, its version and this repository, so a stored file cannot be moved
+    /// to another repository and its version cannot be changed without failing authentication.
+    /// An older stored version of the file still authenticates, `check_not_rolled_back` catches it.
+    /// The compression type is bound as well, it decides how the decrypted content is interpreted.
+    /// So are the version vector, the modification time and the deletion time of a tombstone,
+    /// every conflict decision of `import_stored_file` is made from them.
+    fn file_aad(&self, file: &RepositoryFile, part: FilePart) -> Vec<u8> {
+        let part_marker: &[u8] = match part {
+            FilePart::Header => b"header",
+            FilePart::Content => b"content",
+        };
+        let version = le_bytes(u64::from(file.version), 4);
+        let compression = [file.compression_type as u8];
+        [file.id.as_bytes().as_ref(), version.as_ref(), self.id.as_bytes().as_ref(), part_marker, compression.as_ref(), &metadata_aad(file)].concat()
+    }
+}
+
+/// The canonical encoding of the metadata that is not part of the ciphertext:
+/// the number of devices in the version vector, each device with its count in the order of `to_stored`, the modification time
+/// and a marker whether it is a tombstone followed by the deletion time.
+fn metadata_aad(file: &RepositoryFile) -> Vec<u8> {
+    let devices = file.version_vector.to_stored();
+    let mut aad = le_bytes(devices.len() as u64, 4);
+    for device in &devices {
+        aad.extend_from_slice(device.device_id.as_ref());
+        aad.extend(le_bytes(u64::from(device.version), 4));
+    }
+    aad.extend(le_bytes(file.modified, 8));
+    match file.deleted {
+        Some(deleted) => {
+            aad.push(1);
+            aad.extend(le_bytes(deleted, 8));
+        }
+        None => aad.push(0),
+    }
+    aad
+}
+
+/// The lowest `len` bytes of the value, least significant first.
+fn le_bytes(value: u64, len: usize) -> Vec<u8> {
+    (0..len).map(|index| (value >> (index * 8)) as u8).collect()
+}
+
+/// Copies everything read from `input` into `copy`.
+struct TeeReader<R, W> {
+    input: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.input.read(buf)?;
+        self.copy.write_all(&buf[..read])?;
+        Ok(read)
+    }
+}
+
+/// Loads the metadata of a stored file, the content chunks are not read.
+fn load_stored_file<R, F>(source: &impl FileSource, name: &str, callback: F) -> Result<R, Error>
+    where F: FnOnce(StoredFileV1) -> Result<R, Error> {
+    let (content, _) = open_stored_file(source, name)?;
+    let stored: StoredFileV1 = read_message(content.as_ref()).in_file(name)?;
+    callback(stored).in_file(name)
+}
+
+/// Fails if the stored version is not the one given.
+fn check_stored_version(source: &impl FileSource, file: &RepositoryFile) -> Result<(), Error> {
+    let stored_version = load_stored_file(source, &file.file_name, |stored| Ok(stored.version))?;
+    if stored_version != file.version {
+        return Err(Error::from(ErrorKind::OptimisticLock { file_id: file.id, expected_version: file.version, real_version: stored_version }));
+    }
+    Ok(())
+}
+
+/// The stored file and its serialized `StoredFileV1`, `None` if there is no such file.
+fn load_local_file(source: &impl FileSource, name: &str) -> Result<Option<(RepositoryFile, Vec<u8>)>, Error> {
+    match open_stored_file(source, name) {
+        Ok((content, _)) => {
+            let file = read_message(&content).and_then(|stored| RepositoryFile::from_stored(name, &stored)).in_file(name)?;
+            Ok(Some((file, content)))
+        }
+        Err(ref e) if is_not_found(e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the received version of a conflict was written last.
+/// Versions written at the same time are ordered by the hash of their metadata, so both peers decide the same way.
+fn last_written(received: &RepositoryFile, received_content: &[u8], local: &RepositoryFile, local_content: &[u8]) -> bool {
+    use sha1::{Digest, Sha1};
+
+    (received.modified, Sha1::digest(received_content).to_vec()) > (local.modified, Sha1::digest(local_content).to_vec())
+}
+
+pub fn now_millis() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+/// Returns the serialized `StoredFileV1` and the input positioned at the content chunks.
+/// Previous versions kept in the history are stored the same way.
+pub fn open_stored_file(source: &impl FileSource, name: &str) -> Result<(Vec<u8>, Box<dyn Read>), Error> {
+    let mut input = source.open_file(name).in_file(name)?;
+    let (file_type, content) = read_wrapper_head(&mut input).in_file(name)?;
+    if file_type != FileType::FileV1 && file_type != FileType::FileVersionV1 {
+        return Err(Error::from(ErrorKind::UnexpectedFileType { file_name: name.into(), file_type }));
+    }
+    Ok((content, input))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::crypt::fast_hash_parameters;
+    use ::files::DirectoryFileSource;
+    use ::pb::file::PasswordHashType;
+    use ::repository::{create_repository, open_repository};
+    use ::repository::version::DeviceId;
+    use std::thread;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn setup() -> (TempDir, DirectoryFileSource, Repository) {
+        let dir = TempDir::new("repository_file").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create_repository(&mut source, "files", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        (dir, source, repo)
+    }
+
+    #[test]
+    fn test_create_and_read() {
+        let (_dir, mut source, repo) = setup();
+
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        assert_eq!(0, file.version);
+        assert_eq!(b"header".to_vec(), repo.read_header(&source, &file).unwrap());
+        assert_eq!(b"content".to_vec(), repo.read_content(&source, &file).unwrap());
+
+        let listed = repo.list_files(&source).unwrap();
+        assert_eq!(1, listed.len());
+        assert_eq!(file.id, listed[0].id);
+    }
+
+    #[test]
+    fn test_get_file() {
+        let (_dir, mut source, repo) = setup();
+        let other = create_repository(&mut source, "other", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let loaded = repo.get_file(&source, file.id).unwrap();
+        assert_eq!(file.id, loaded.id);
+        assert_eq!(file.version, loaded.version);
+        assert!(other.get_file(&source, file.id).is_err());
+        assert!(is_not_found(&repo.get_file(&source, FileId::new_v4()).unwrap_err()));
+    }
+
+    #[test]
+    fn test_update() {
+        let (_dir, mut source, repo) = setup();
+
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let updated = repo.update_file(&mut source, &file, b"new header", b"new content").unwrap();
+        assert_eq!(1, updated.version);
+        assert_eq!(b"new header".to_vec(), repo.read_header(&source, &updated).unwrap());
+        assert_eq!(b"new content".to_vec(), repo.read_content(&source, &updated).unwrap());
+
+        assert!(repo.update_file(&mut source, &file, b"stale", b"stale").is_err());
+        assert!(repo.read_content(&source, &file).is_err());
+    }
+
+    #[test]
+    fn test_file_syncs() {
+        let (_dir, mut source, repo) = setup();
+
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let before = repo.list_file_syncs(&source).unwrap();
+        assert_eq!(1, before.len());
+        assert_eq!(file.id, before[0].id);
+        assert_eq!(0, before[0].version);
+
+        repo.update_file(&mut source, &file, b"header", b"content").unwrap();
+        let after = repo.list_file_syncs(&source).unwrap();
+        assert_eq!(1, after[0].version);
+        assert_ne!(before[0].hash, after[0].hash);
+    }
+
+    #[test]
+    fn test_rollback_detected() {
+        let (_dir, mut source, repo) = setup();
+
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let old_data = source.get_file_content(&file.file_name).unwrap();
+        let updated = repo.update_file(&mut source, &file, b"header", b"new content").unwrap();
+        let new_data = source.get_file_content(&file.file_name).unwrap();
+
+        source.store_file(&file.file_name, &old_data).unwrap();
+        assert!(repo.read_content(&source, &updated).is_err());
+        let error = repo.get_file(&source, file.id).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::RolledBack { file_name }) if *file_name == file.file_name));
+        let error = repo.list_files(&source).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::RolledBack { .. })));
+
+        source.store_file(&file.file_name, &new_data).unwrap();
+        assert_eq!(updated.version, repo.get_file(&source, file.id).unwrap().version);
+    }
+
+    #[test]
+    fn test_listed_version_authenticated() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+
+        tamper(&mut source, &file.file_name, |stored| StoredFileV1 { version: 5, ..stored });
+        let error = repo.list_files(&source).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::AuthenticationFailed { .. })));
+    }
+
+    #[test]
+    fn test_metadata_authenticated() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let file = repo.update_file(&mut source, &file, b"header", b"new content").unwrap();
+        let genuine = source.get_file_content(&file.file_name).unwrap();
+
+        let other_device = VersionVector::default().increment(DeviceId::new_v4());
+        tamper(&mut source, &file.file_name, |stored| StoredFileV1 { version_vector: other_device.to_stored(), ..stored });
+        assert_authentication_failed(&repo, &source, &file);
+
+        source.store_file(&file.file_name, &genuine).unwrap();
+        tamper(&mut source, &file.file_name, |stored| StoredFileV1 { modified: Some(u64::max_value()), ..stored });
+        assert_authentication_failed(&repo, &source, &file);
+
+        let dir = TempDir::new("repository_file").unwrap();
+        let mut peer = DirectoryFileSource::new(dir.path()).unwrap();
+        let mut received = repo.export_stored_file(&source, file.id).unwrap();
+        let error = repo.import_stored_file(&mut peer, file.id, &mut received, ConflictResolution::LastWriterWins).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::AuthenticationFailed { .. })));
+        assert!(peer.list_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_forged_content_not_imported() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", &vec![1u8; 150_000], CompressionType::None).unwrap();
+        let dir = TempDir::new("repository_file").unwrap();
+        let mut peer = DirectoryFileSource::new(dir.path()).unwrap();
+        repo.import_stored_file(&mut peer, file.id, &mut repo.export_stored_file(&source, file.id).unwrap(), ConflictResolution::LastWriterWins).unwrap();
+        let names = peer.list_names().unwrap();
+
+        let file = repo.update_file(&mut source, &file, b"header", &vec![2u8; 150_000]).unwrap();
+        let mut data = source.get_file_content(&file.file_name).unwrap();
+        let position = data.len() - 1000;
+        data[position] ^= 1;
+        source.store_file(&file.file_name, &data).unwrap();
+
+        let mut received = repo.export_stored_file(&source, file.id).unwrap();
+        let error = repo.import_stored_file(&mut peer, file.id, &mut received, ConflictResolution::LastWriterWins).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::AuthenticationFailed { .. })));
+        let local = repo.get_file(&peer, file.id).unwrap();
+        assert_eq!(0, local.version);
+        assert_eq!(vec![1u8; 150_000], repo.read_content(&peer, &local).unwrap());
+        assert!(repo.list_versions(&peer, file.id).unwrap().is_empty());
+        assert_eq!(names, peer.list_names().unwrap());
+    }
+
+    #[test]
+    fn test_forged_tombstone_rejected() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let dir = TempDir::new("repository_file").unwrap();
+        let mut peer = DirectoryFileSource::new(dir.path()).unwrap();
+        repo.import_stored_file(&mut peer, file.id, &mut repo.export_stored_file(&source, file.id).unwrap(), ConflictResolution::LastWriterWins).unwrap();
+
+        let file = repo.update_file(&mut source, &file, b"header", b"new content").unwrap();
+        tamper(&mut source, &file.file_name, |stored| StoredFileV1 { deleted: stored.modified, ..stored });
+        assert_authentication_failed(&repo, &source, &file);
+
+        let mut received = repo.export_stored_file(&source, file.id).unwrap();
+        let error = repo.import_stored_file(&mut peer, file.id, &mut received, ConflictResolution::LastWriterWins).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::AuthenticationFailed { .. })));
+        assert_eq!(1, repo.list_files(&peer).unwrap().len());
+        assert!(repo.noted_tombstones(&peer).unwrap().is_empty());
+    }
+
+    /// Rewrites the metadata of the stored file, the encrypted parts stay as they are.
+    fn tamper<F>(source: &mut DirectoryFileSource, file_name: &str, change: F) where F: for<'a> FnOnce(StoredFileV1<'a>) -> StoredFileV1<'a> {
+        let (content, mut rest) = open_stored_file(source, file_name).unwrap();
+        let stored: StoredFileV1 = read_message(&content).unwrap();
+        let mut data = wrap_message(FileType::FileV1, &change(stored)).unwrap();
+        rest.read_to_end(&mut data).unwrap();
+        source.store_file(file_name, &data).unwrap();
+    }
+
+    /// Reads the file with the metadata as it is stored now.
+    fn assert_authentication_failed(repo: &Repository, source: &DirectoryFileSource, file: &RepositoryFile) {
+        let stored = repo.get_file(source, file.id).unwrap();
+        let error = repo.read_header(source, &stored).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::AuthenticationFailed { .. })));
+        assert!(repo.read_content(source, &stored).is_err());
+    }
+
+    #[test]
+    fn test_other_repository_rejected() {
+        let (_dir, mut source, repo) = setup();
+        let other = create_repository(&mut source, "other", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let other = Repository {
+            file_pw: repo.file_pw.clone(),
+            ..other
+        };
+
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        assert!(other.read_content(&source, &file).is_err());
+        assert!(other.list_files(&source).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_export_only_own_files() {
+        let (_dir, mut source, repo) = setup();
+        let other = create_repository(&mut source, "other", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let foreign = other.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+
+        let mut exported = Vec::new();
+        repo.export_stored_file(&source, file.id).unwrap().read_to_end(&mut exported).unwrap();
+        assert_eq!(source.get_file_content(&file.file_name).unwrap(), exported);
+
+        assert!(repo.export_stored_file(&source, foreign.id).is_err());
+        assert!(repo.export_stored_file(&source, repo.id).is_err());
+        assert!(repo.export_stored_file(&source, other.id).is_err());
+    }
+
+    #[test]
+    fn test_streamed_content() {
+        let (_dir, mut source, repo) = setup();
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+        let file = repo.create_file_from(&mut source, b"header", &mut content.as_slice(), CompressionType::None).unwrap();
+        let mut output = Vec::new();
+        assert_eq!(content.len() as u64, repo.read_content_to(&source, &file, &mut output).unwrap());
+        assert_eq!(content, output);
+        assert_eq!(b"header".to_vec(), repo.read_header(&source, &file).unwrap());
+
+        let updated = repo.update_file_from(&mut source, &file, b"header", &mut &content[..1000]).unwrap();
+        assert_eq!(content[..1000].to_vec(), repo.read_content(&source, &updated).unwrap());
+    }
+
+    #[test]
+    fn test_only_read_content_limited() {
+        let (_dir, mut source, repo) = setup();
+        let content = vec![0u8; 100 * 1024];
+        let file = repo.create_file(&mut source, b"header", &content, CompressionType::DeflateZip).unwrap();
+
+        let error = repo.read_content_limited(&source, &file, 1024).unwrap_err();
+        assert!(error.to_string().contains("exceeds 1024 bytes"));
+        assert_eq!(content, repo.read_content_limited(&source, &file, content.len() as u64).unwrap());
+
+        let mut streamed = Vec::new();
+        repo.content_reader(&source, &file).unwrap().read_to_end(&mut streamed).unwrap();
+        assert_eq!(content, streamed);
+    }
+
+    #[test]
+    fn test_modified_segment_detected() {
+        let (_dir, mut source, repo) = setup();
+        let content = vec![5u8; 150_000];
+
+        let file = repo.create_file(&mut source, b"header", &content, CompressionType::None).unwrap();
+        let mut data = source.get_file_content(&file.file_name).unwrap();
+        let position = data.len() - 1000;
+        data[position] ^= 1;
+        source.store_file(&file.file_name, &data).unwrap();
+
+        assert_eq!(b"header".to_vec(), repo.read_header(&source, &file).unwrap());
+        let error = repo.read_content(&source, &file).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::AuthenticationFailed { file_name }) if *file_name == file.file_name));
+    }
+
+    #[test]
+    fn test_errors_name_the_file() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+
+        let mut data = source.get_file_content(&file.file_name).unwrap();
+        data.truncate(data.len() / 2);
+        source.store_file(&file.file_name, &data).unwrap();
+        let error = repo.read_header(&source, &file).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::CorruptedFile { file_name, .. }) if *file_name == file.file_name));
+
+        source.store_file(&file.file_name, &[8, 42, 18, 0]).unwrap();
+        let error = repo.read_header(&source, &file).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::UnsupportedFileType { file_type: 42, .. })));
+
+        source.delete_file(&file.file_name).unwrap();
+        let error = repo.read_header(&source, &file).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::Io { file_name, cause }) if *file_name == file.file_name && cause.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn test_unsegmented_content_readable() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"", CompressionType::DeflateZip).unwrap();
+
+        let nonce_header = random_bytes(file.encryption_type.nonce_length()).unwrap();
+        let nonce_content = random_bytes(file.encryption_type.nonce_length()).unwrap();
+        let compressed = CompressionType::DeflateZip.compress(b"old content").unwrap();
+        let stored = StoredFileV1 {
+            id: Cow::from(file.id.as_bytes().as_ref()),
+            version: file.version,
+            repository_id: Cow::from(repo.id.as_bytes().as_ref()),
+            encryption_type: file.encryption_type,
+            compression_type: file.compression_type,
+            nonce_header: Cow::from(nonce_header.as_slice()),
+            nonce_content: Cow::from(nonce_content.as_slice()),
+            encrypted_header: Cow::from(repo.encrypt_part(&file, FilePart::Header, &nonce_header, b"old header").unwrap()),
+            encrypted_content: Cow::from(repo.encrypt_part(&file, FilePart::Content, &nonce_content, &compressed).unwrap()),
+            segment_size: None,
+            version_vector: Vec::new(),
+            modified: None,
+            deleted: None,
+        };
+        source.store_file(&file.file_name, &wrap_message(FileType::FileV1, &stored).unwrap()).unwrap();
+
+        assert_eq!(b"old header".to_vec(), repo.read_header(&source, &file).unwrap());
+        assert_eq!(b"old content".to_vec(), repo.read_content(&source, &file).unwrap());
+    }
+
+    #[test]
+    fn test_compression_per_file() {
+        let (_dir, mut source, repo) = setup();
+        let content = "a note that compresses well ".repeat(200);
+
+        let compressed = repo.create_file(&mut source, b"header", content.as_bytes(), CompressionType::DeflateZip).unwrap();
+        let plain = repo.create_file(&mut source, b"header", content.as_bytes(), CompressionType::None).unwrap();
+
+        assert!(source.get_file_content(&compressed.file_name).unwrap().len() < content.len() / 10);
+        assert!(source.get_file_content(&plain.file_name).unwrap().len() > content.len());
+
+        assert_eq!(content.as_bytes(), repo.read_content(&source, &compressed).unwrap().as_slice());
+        assert_eq!(content.as_bytes(), repo.read_content(&source, &plain).unwrap().as_slice());
+
+        let updated = repo.update_file(&mut source, &plain, b"header", b"new content").unwrap();
+        assert_eq!(CompressionType::None, updated.compression_type);
+        assert_eq!(CompressionType::None, repo.list_files(&source).unwrap().iter().find(|f| f.id == plain.id).unwrap().compression_type);
+    }
+
+    /// A second device of the repository which has the given file as well.
+    fn second_device(source: &DirectoryFileSource, repo: &Repository, file: &RepositoryFile) -> (TempDir, DirectoryFileSource, Repository) {
+        let dir = TempDir::new("repository_file_device").unwrap();
+        let mut other_source = DirectoryFileSource::new(dir.path()).unwrap();
+        for name in [repo.id.hyphenated().to_string(), file.file_name.clone()].iter() {
+            other_source.store_file(name, &source.get_file_content(name).unwrap()).unwrap();
+        }
+        let other_repo = open_repository(&other_source, repo.id, b"secret").unwrap();
+        (dir, other_source, other_repo)
+    }
+
+    /// Two devices with the same file, changed on the first device before the second one.
+    fn concurrent_change() -> (TempDir, DirectoryFileSource, Repository, RepositoryFile, TempDir, DirectoryFileSource, Repository, RepositoryFile) {
+        let (dir_a, mut source_a, repo_a) = setup();
+        let file = repo_a.create_file(&mut source_a, b"header", b"original", CompressionType::DeflateZip).unwrap();
+        let (dir_b, mut source_b, repo_b) = second_device(&source_a, &repo_a, &file);
+
+        let file_a = repo_a.update_file(&mut source_a, &file, b"header a", b"content a").unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let file_b = repo_b.update_file(&mut source_b, &file, b"header b", b"content b").unwrap();
+        (dir_a, source_a, repo_a, file_a, dir_b, source_b, repo_b, file_b)
+    }
+
+    fn import(repo: &Repository, source: &mut DirectoryFileSource, from: &DirectoryFileSource, file: &RepositoryFile, resolution: ConflictResolution) -> Result<ImportResult, Error> {
+        let mut input = repo.export_stored_file(from, file.id).unwrap();
+        repo.import_stored_file(source, file.id, &mut input, resolution)
+    }
+
+    #[test]
+    fn test_import_newer_version() {
+        let (_dir_a, mut source_a, repo_a) = setup();
+        let file = repo_a.create_file(&mut source_a, b"header", b"original", CompressionType::DeflateZip).unwrap();
+        let (_dir_b, mut source_b, repo_b) = second_device(&source_a, &repo_a, &file);
+        let updated = repo_a.update_file(&mut source_a, &file, b"header", b"updated").unwrap();
+
+        let stored = match import(&repo_b, &mut source_b, &source_a, &updated, ConflictResolution::Defer).unwrap() {
+            ImportResult::Stored(file) => file,
+            result => panic!("unexpected {:?}", result),
+        };
+        assert_eq!(updated.version_vector, stored.version_vector);
+        assert_eq!(b"updated".to_vec(), repo_b.read_content(&source_b, &stored).unwrap());
+
+        let stale = import(&repo_b, &mut source_b, &source_a, &updated, ConflictResolution::Defer).unwrap_err();
+        assert!(matches!(stale.downcast_ref::<ErrorKind>(), Some(ErrorKind::StaleFile { .. })));
+    }
+
+    #[test]
+    fn test_conflict_last_writer_wins() {
+        let (_dir_a, mut source_a, repo_a, file_a, _dir_b, source_b, _, file_b) = concurrent_change();
+        assert_eq!(file_a.version, file_b.version);
+        assert_eq!(VersionOrdering::Concurrent, file_a.version_vector.compare(&file_b.version_vector));
+
+        let stored = match import(&repo_a, &mut source_a, &source_b, &file_b, ConflictResolution::LastWriterWins).unwrap() {
+            ImportResult::Stored(file) => file,
+            result => panic!("unexpected {:?}", result),
+        };
+        assert_eq!(b"content b".to_vec(), repo_a.read_content(&source_a, &stored).unwrap());
+        assert_eq!(b"header b".to_vec(), repo_a.read_header(&source_a, &stored).unwrap());
+    }
+
+    #[test]
+    fn test_conflict_copy() {
+        let (_dir_a, mut source_a, repo_a, file_a, _dir_b, source_b, _, file_b) = concurrent_change();
+
+        let (copy, local) = match import(&repo_a, &mut source_a, &source_b, &file_b, ConflictResolution::ConflictCopy).unwrap() {
+            ImportResult::ConflictCopy { copy, local } => (copy, local),
+            result => panic!("unexpected {:?}", result),
+        };
+        assert_ne!(file_a.id, copy.id);
+        assert_eq!(b"content b".to_vec(), repo_a.read_content(&source_a, &copy).unwrap());
+        assert_eq!(b"header b".to_vec(), repo_a.read_header(&source_a, &copy).unwrap());
+        assert_eq!(b"content a".to_vec(), repo_a.read_content(&source_a, &local).unwrap());
+        assert_eq!(VersionOrdering::Newer, local.version_vector.compare(&file_b.version_vector));
+        assert_eq!(2, repo_a.list_files(&source_a).unwrap().len());
+    }
+
+    #[test]
+    fn test_conflict_deferred() {
+        let (_dir_a, mut source_a, repo_a, file_a, _dir_b, source_b, _, file_b) = concurrent_change();
+
+        let result = import(&repo_a, &mut source_a, &source_b, &file_b, ConflictResolution::Defer).unwrap();
+        assert!(matches!(result, ImportResult::Deferred));
+        assert_eq!(b"content a".to_vec(), repo_a.read_content(&source_a, &file_a).unwrap());
+    }
+}
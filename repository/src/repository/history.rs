@@ -0,0 +1,256 @@
+//! History of previous file versions.
+//!
+//! Before a version of a file gets replaced it is kept as it is stored, still encrypted with the file key,
+//! under the name `<file-id>.<version>.<modified>`. Kept versions have the type `FileVersionV1`,
+//! so they are neither listed as files nor synced. Which versions are kept is decided by the `HistoryRetention`
+//! stored in the repository file, it is applied whenever a file gets a new version.
+
+use super::file::{now_millis, open_stored_file, FileId, FileVersion, RepositoryFile};
+use super::repository::Repository;
+use super::{read_message, rewrite_repository, write_message};
+use ::crypt::PlaintextVec;
+use ::error::{ErrorKind, FileContext};
+use ::files::FileSource;
+use ::pb::file::{FileType, StoredFileV1, StoredFileWrapper, StoredRepositoryV1};
+use failure::Error;
+use std::borrow::Cow;
+use std::io;
+use std::time::Duration;
+
+pub const DEFAULT_KEPT_VERSIONS: usize = 10;
+
+/// Which previous versions of a file are kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryRetention {
+    /// Keeps the given number of previous versions of each file, 0 keeps no history at all
+    Versions(usize),
+    /// Keeps the previous versions written within the given duration, it is stored in whole seconds.
+    /// Versions without modification time are kept.
+    Age(Duration),
+}
+
+impl Default for HistoryRetention {
+    fn default() -> Self {
+        HistoryRetention::Versions(DEFAULT_KEPT_VERSIONS)
+    }
+}
+
+impl Repository {
+    /// Stores the retention in the repository file, it applies from the next new file version on.
+    pub fn set_history_retention(&mut self, source: &mut impl FileSource, retention: HistoryRetention) -> Result<(), Error> {
+        let (kept_versions, max_version_age) = match retention {
+            HistoryRetention::Versions(keep) => (Some(keep.min(u32::max_value() as usize) as u32), None),
+            HistoryRetention::Age(max_age) => (None, Some(max_age.as_secs())),
+        };
+        rewrite_repository(source, self.id, |repo| Ok(StoredRepositoryV1 { kept_versions, max_version_age, ..repo }))?;
+        self.history = retention;
+        Ok(())
+    }
+
+    /// Lists the kept previous versions of a file, the oldest first. The current version is not part of it.
+    /// Versions written concurrently on different devices can share a version number, they are ordered by modification time.
+    /// Only the files named after the file id are opened.
+    pub fn list_versions(&self, source: &impl FileSource, id: FileId) -> Result<Vec<RepositoryFile>, Error> {
+        let prefix = format!("{}.", id.hyphenated());
+        let mut versions = Vec::new();
+        for name in source.list_names()? {
+            if !name.starts_with(&prefix) {
+                continue;
+            }
+            let (content, _) = open_stored_file(source, &name)?;
+            let version = read_message(&content).and_then(|stored| RepositoryFile::from_stored(&name, &stored)).in_file(&name)?;
+            if version.id == id && version.repository_id == self.id {
+                versions.push(version);
+            }
+        }
+        versions.sort_by_key(|version| (version.version, version.modified));
+        Ok(versions)
+    }
+
+    /// Decrypts header and content of a kept version, the one written last if several share the version number.
+    pub fn read_version(&self, source: &impl FileSource, id: FileId, version: FileVersion) -> Result<(PlaintextVec, PlaintextVec), Error> {
+        let kept = self.find_version(source, id, version)?;
+        Ok((self.read_header(source, &kept)?, self.read_content(source, &kept)?))
+    }
+
+    /// Stores header and content of a kept version as new version of the file,
+    /// the replaced version is kept in the history like on every update.
+    pub fn restore_version(&self, source: &mut impl FileSource, file: &RepositoryFile, version: FileVersion) -> Result<RepositoryFile, Error> {
+        let kept = self.find_version(source, file.id, version)?;
+        let header = self.read_header(source, &kept)?;
+        let mut content = self.content_reader(source, &kept)?;
+        self.update_file_from(source, file, &header, &mut content)
+    }
+
+    /// Keeps the stored version of the file in the history. Tombstones have nothing worth keeping.
+    pub fn archive_file(&self, source: &mut impl FileSource, file: &RepositoryFile) -> Result<(), Error> {
+        if file.deleted.is_some() || self.history == HistoryRetention::Versions(0) {
+            return Ok(());
+        }
+        let (content, mut rest) = open_stored_file(source, &file.file_name)?;
+        let stored: StoredFileV1 = read_message(&content).in_file(&file.file_name)?;
+        let name = version_name(FileId::from_bytes(stored.id.as_ref())?, stored.version, stored.modified.unwrap_or(0));
+        let head = write_message(&StoredFileWrapper { type_pb: FileType::FileVersionV1, content: Cow::from(content.as_slice()), content_chunks: Vec::new() })?;
+        source.store_file_from(&name, &mut |out| {
+            out.write_all(&head)?;
+            io::copy(&mut rest, out)?;
+            Ok(())
+        }).in_file(&file.file_name)
+    }
+
+    /// Deletes the kept versions of the file the retention does not cover anymore.
+    pub fn prune_history(&self, source: &mut impl FileSource, id: FileId) -> Result<(), Error> {
+        let versions = self.list_versions(source, id)?;
+        let expired: Vec<&RepositoryFile> = match self.history {
+            HistoryRetention::Versions(keep) => versions.iter().take(versions.len().saturating_sub(keep)).collect(),
+            HistoryRetention::Age(max_age) => {
+                let max_age = max_age.as_secs() * 1000 + u64::from(max_age.subsec_millis());
+                let cutoff = now_millis().saturating_sub(max_age);
+                versions.iter().filter(|version| version.modified != 0 && version.modified < cutoff).collect()
+            }
+        };
+        for version in expired {
+            source.delete_file(&version.file_name)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes all kept versions of the file, e.g. once its tombstone is purged.
+    pub fn delete_history(&self, source: &mut impl FileSource, id: FileId) -> Result<(), Error> {
+        for version in self.list_versions(source, id)? {
+            source.delete_file(&version.file_name)?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts the kept versions with the file key of this repository, which replaced the one of `old`.
+    /// Versions that are already encrypted with it are skipped, so an interrupted rotation can continue.
+    pub fn reencrypt_history(&self, source: &mut impl FileSource, old: &Repository) -> Result<(), Error> {
+        for name in source.list_file_versions()? {
+            let (content, _) = open_stored_file(source, &name)?;
+            let version = read_message(&content).and_then(|stored| RepositoryFile::from_stored(&name, &stored)).in_file(&name)?;
+            if version.repository_id != self.id || self.read_header(source, &version).is_ok() {
+                continue;
+            }
+            let header = old.read_header(source, &version)?;
+            self.store_file_as(source, &version, FileType::FileVersionV1, &header, &mut old.content_reader(source, &version)?)?;
+        }
+        Ok(())
+    }
+
+    fn find_version(&self, source: &impl FileSource, id: FileId, version: FileVersion) -> Result<RepositoryFile, Error> {
+        self.list_versions(source, id)?.into_iter()
+            .rev()
+            .find(|kept| kept.version == version)
+            .ok_or_else(|| Error::from(ErrorKind::VersionNotFound { file_id: id, version }))
+    }
+}
+
+/// The retention stored in the repository file, the default if none is stored.
+pub(super) fn stored_retention(repo: &StoredRepositoryV1) -> HistoryRetention {
+    match (repo.kept_versions, repo.max_version_age) {
+        (_, Some(max_age)) => HistoryRetention::Age(Duration::from_secs(max_age)),
+        (Some(keep), None) => HistoryRetention::Versions(keep as usize),
+        (None, None) => HistoryRetention::default(),
+    }
+}
+
+fn version_name(id: FileId, version: FileVersion, modified: u64) -> String {
+    format!("{}.{}.{}", id.hyphenated(), version, modified)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::crypt::fast_hash_parameters;
+    use ::files::DirectoryFileSource;
+    use ::pb::file::{CompressionType, EncryptionType, PasswordHashType};
+    use ::repository::{create_repository, open_repository, rotate_file_key};
+    use std::thread;
+    use tempdir::TempDir;
+
+    fn setup() -> (TempDir, DirectoryFileSource, Repository) {
+        let dir = TempDir::new("repository_history").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create_repository(&mut source, "history", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        (dir, source, repo)
+    }
+
+    fn versions(repo: &Repository, source: &impl FileSource, id: FileId) -> Vec<FileVersion> {
+        repo.list_versions(source, id).unwrap().iter().map(|version| version.version).collect()
+    }
+
+    #[test]
+    fn test_read_and_restore_version() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header 0", b"content 0", CompressionType::DeflateZip).unwrap();
+        let file = repo.update_file(&mut source, &file, b"header 1", b"content 1").unwrap();
+        let file = repo.update_file(&mut source, &file, b"header 2", b"content 2").unwrap();
+
+        assert_eq!(vec![0, 1], versions(&repo, &source, file.id));
+        assert_eq!(1, repo.list_files(&source).unwrap().len());
+        assert_eq!(1, repo.list_file_syncs(&source).unwrap().len());
+        assert_eq!((b"header 0".to_vec(), b"content 0".to_vec()), repo.read_version(&source, file.id, 0).map(|(header, content)| (header.to_vec(), content.to_vec())).unwrap());
+        let missing = repo.read_version(&source, file.id, 2).unwrap_err();
+        assert!(matches!(missing.downcast_ref::<ErrorKind>(), Some(ErrorKind::VersionNotFound { version: 2, .. })));
+
+        let restored = repo.restore_version(&mut source, &file, 0).unwrap();
+        assert_eq!(3, restored.version);
+        assert_eq!(b"content 0".to_vec(), repo.read_content(&source, &restored).unwrap());
+        assert_eq!(vec![0, 1, 2], versions(&repo, &source, file.id));
+    }
+
+    #[test]
+    fn test_deleted_file_restored() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let tombstone = repo.delete_file(&mut source, &file).unwrap();
+
+        let restored = repo.restore_version(&mut source, &tombstone, 0).unwrap();
+        assert!(restored.deleted.is_none());
+        assert_eq!(b"content".to_vec(), repo.read_content(&source, &restored).unwrap());
+        assert_eq!(vec![0], versions(&repo, &source, file.id));
+    }
+
+    #[test]
+    fn test_retention() {
+        let (_dir, mut source, mut repo) = setup();
+        repo.history = HistoryRetention::Versions(2);
+        let mut file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        for _ in 0..4 {
+            file = repo.update_file(&mut source, &file, b"header", b"content").unwrap();
+        }
+        assert_eq!(vec![2, 3], versions(&repo, &source, file.id));
+
+        repo.history = HistoryRetention::Age(Duration::from_millis(1));
+        thread::sleep(Duration::from_millis(5));
+        let file = repo.update_file(&mut source, &file, b"header", b"content").unwrap();
+        assert!(versions(&repo, &source, file.id).is_empty());
+
+        repo.history = HistoryRetention::Versions(0);
+        repo.update_file(&mut source, &file, b"header", b"content").unwrap();
+        assert!(versions(&repo, &source, file.id).is_empty());
+    }
+
+    #[test]
+    fn test_retention_stored() {
+        let (_dir, mut source, mut repo) = setup();
+        assert_eq!(HistoryRetention::default(), repo.history);
+
+        repo.set_history_retention(&mut source, HistoryRetention::Age(Duration::from_secs(3600))).unwrap();
+        assert_eq!(HistoryRetention::Age(Duration::from_secs(3600)), open_repository(&source, repo.id, b"secret").unwrap().history);
+        repo.set_history_retention(&mut source, HistoryRetention::Versions(3)).unwrap();
+        assert_eq!(HistoryRetention::Versions(3), open_repository(&source, repo.id, b"secret").unwrap().history);
+    }
+
+    #[test]
+    fn test_history_survives_key_rotation() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"old content", CompressionType::DeflateZip).unwrap();
+        repo.update_file(&mut source, &file, b"header", b"new content").unwrap();
+
+        rotate_file_key(&mut source, repo.id, b"secret").unwrap();
+        let repo = open_repository(&source, repo.id, b"secret").unwrap();
+        assert_eq!(b"old content".to_vec(), repo.read_version(&source, file.id, 0).unwrap().1);
+    }
+}
@@ -1,74 +1,363 @@
 use ::error::*;
 use ::files::FileSource;
 use ::pb::file::*;
-use crypt::{CipherText, CipherTextVec, DoubleHashedPw, Plaintext, PlaintextVec};
+use crypt::{CipherTextVec, DoubleHashedPw, HashedPw, Nonce, PasswordHasher, Plaintext, SecretVec, AAD};
 use failure::Error;
-use quick_protobuf::{BytesReader, MessageRead};
+use quick_protobuf::{BytesReader, MessageRead, MessageWrite, Writer};
+use std::borrow::Cow;
 pub use self::repository::*;
+use self::users::primary_slot;
 
 pub mod repository;
 pub mod file;
+pub mod history;
+pub mod tombstone;
+pub mod users;
+pub mod verify;
+pub mod version;
+
+/// The version of the stored repository format this crate reads and writes.
+const REPOSITORY_VERSION: u32 = 1;
+
+/// Creates a new repository, use `crypt::default_hash_parameters` unless there is a reason for other costs.
+/// The key slot of the password has no user name, see `create_repository_as`.
+pub fn create_repository(source: &mut impl FileSource, name: &str, pw: &Plaintext, enc_type: EncryptionType, hash_type: PasswordHashType, hash_parameters: PasswordHashParameters) -> Result<Repository, Error> {
+    create_repository_as(source, name, "", pw, enc_type, hash_type, hash_parameters)
+}
 
-pub fn open_repository(source: &impl FileSource, id: RepositoryId, pw: &Plaintext) -> Result<Repository, Error> {
-    let repository_file_names = source.list_repositories()?;
+/// Creates a new repository whose first key slot belongs to `user_name`, more users are added with `add_user`.
+pub fn create_repository_as(source: &mut impl FileSource, name: &str, user_name: &str, pw: &Plaintext, enc_type: EncryptionType, hash_type: PasswordHashType, hash_parameters: PasswordHashParameters) -> Result<Repository, Error> {
+    use crypt::{random_bytes, DeEncrypter, Hasher, KEY_LENGTH, SALT_LENGTH};
+    use uuid::Uuid;
 
-    let callback = |repo: StoredRepositoryV1| -> Result<Repository, Error> {
-        let pw = get_password(&repo, pw)?;
+    let id = Uuid::new_v4();
+    let salt = random_bytes(SALT_LENGTH)?;
+    let nonce = random_bytes(enc_type.nonce_length())?;
+    let file_pw = HashedPw { content: SecretVec::from(random_bytes(KEY_LENGTH)?) };
+
+    let hasher = PasswordHasher::new(hash_type, hash_parameters);
+    let hashed_pw = hasher.hash_pw(pw, &salt)?;
+    let double_hash_pw = hasher.double_hash_pw(pw, &salt)?;
+    let encrypted_file_pw = encrypt_file_pw(enc_type, &hashed_pw, &nonce, &file_pw_aad(id.as_bytes(), &salt), &file_pw)?;
+
+    let repo = StoredRepositoryV1 {
+        id: Cow::from(id.as_bytes().as_ref()),
+        version: REPOSITORY_VERSION,
+        enc_type,
+        hash_type,
+        salt: Cow::from(salt),
+        double_hashed_pw: Cow::from(double_hash_pw.as_slice()),
+        nonce: Cow::from(nonce),
+        encrypted_file_pw: Cow::from(encrypted_file_pw),
+        name: Cow::from(name),
+        pending_file_pw: None,
+        hash_parameters: hasher.parameters.clone(),
+        user_name: if user_name.is_empty() { None } else { Some(Cow::from(user_name)) },
+        key_slots: Vec::new(),
+        kept_versions: None,
+        max_version_age: None,
+    };
+    let data = wrap_message(FileType::RepositoryV1, &repo)?;
+    let file_name = id.hyphenated().to_string();
+    source.store_file(&file_name, &data).in_file(&file_name)?;
+
+    let repository = Repository {
+        id,
+        file_pw,
+        double_hash_pw,
+        encryption_type: enc_type,
+        name: name.into(),
+        device_id: version::DeviceId::new_v4(),
+        history: history::HistoryRetention::default(),
+    };
+    repository.remember_device_id(source)?;
+    Ok(repository)
+}
+
+/// Opens the repository with the password of its first key slot, `open_repository_as` opens it as another user.
+pub fn open_repository(source: &impl FileSource, id: RepositoryId, pw: &Plaintext) -> Result<Repository, Error> {
+    load_repository(source, id, |_, repo| {
+        let slot = primary_slot(&repo);
+        let file_pw = get_password(&repo, &slot, pw)?;
         Ok(Repository {
             id: RepositoryId::from_bytes(repo.id.as_ref())?,
-            file_pw: repo.encrypted_file_pw.to_vec(),
-            double_hash_pw: DoubleHashedPw::from(repo.double_hashed_pw.as_ref()),
-            name: repo.name.into(),
+            file_pw,
+            double_hash_pw: DoubleHashedPw::from(slot.double_hashed_pw.as_ref()),
+            encryption_type: repo.enc_type,
+            name: repo.name.to_string(),
+            device_id: tombstone::local_device_id(source, id)?,
+            history: history::stored_retention(&repo),
         })
-    };
+    })
+}
 
-    let mut repository = None;
+/// Lists the repositories stored in the source, they can be listed without knowing their passwords.
+/// Fails with `CorruptedFile` naming the first repository file that cannot be parsed, like `load_repository`.
+pub fn list_repositories(source: &impl FileSource) -> Result<Vec<RepositoryDescriptor>, Error> {
+    let mut repositories = Vec::new();
+    for name in source.list_repositories()? {
+        let content = source.get_file_content(&name).in_file(&name)?;
+        let wrapper: StoredFileWrapper = read_message(&content).in_file(&name)?;
+        if wrapper.type_pb != FileType::RepositoryV1 {
+            return Err(Error::from(ErrorKind::UnexpectedFileType { file_name: name, file_type: wrapper.type_pb }));
+        }
+        let repo: StoredRepositoryV1 = read_message(&wrapper.content).in_file(&name)?;
+        let id = RepositoryId::from_bytes(repo.id.as_ref())
+            .map_err(|e| ErrorKind::CorruptedFile { file_name: name.clone(), reason: e.to_string() })?;
+        repositories.push(RepositoryDescriptor { id, name: repo.name.into() });
+    }
+    Ok(repositories)
+}
 
-    for n in repository_file_names.iter() {
-        let content = source.get_file_content(n)?;
-        let mut reader = BytesReader::from_bytes(content.as_ref());
-        let res = StoredFileWrapper::from_reader(&mut reader, content.as_ref());
-        if let Ok(wrapper) = res {
-            if wrapper.type_pb == FileType::RepositoryV1 {
-                let mut reader = BytesReader::from_bytes(wrapper.content.as_ref());
+/// Changes the password of the first key slot, `change_user_password` changes the one of another user.
+/// Only the file key stored in the repository file gets re-encrypted, all content files stay untouched.
+pub fn change_password(source: &mut impl FileSource, id: RepositoryId, old_pw: &Plaintext, new_pw: &Plaintext) -> Result<(), Error> {
+    use crypt::{random_bytes, DeEncrypter, Hasher, SALT_LENGTH};
+
+    rewrite_repository(source, id, |repo| {
+        let slot = primary_slot(&repo);
+        let old_hashed_pw = hash_checked_pw(&slot, old_pw)?;
+        let file_pw = decrypt_file_pw(repo.enc_type, &old_hashed_pw, repo.nonce.as_ref(), &file_pw_aad(&repo.id, &repo.salt), &repo.encrypted_file_pw)?;
+        let pending_file_pw = match repo.pending_file_pw {
+            Some(ref pending) => Some(decrypt_pending_file_pw(&repo, &old_hashed_pw, pending)?),
+            None => None,
+        };
+
+        let salt = random_bytes(SALT_LENGTH)?;
+        let nonce = random_bytes(repo.enc_type.nonce_length())?;
+        let hashed_pw = hasher(&slot).hash_pw(new_pw, &salt)?;
+        let double_hash_pw = hasher(&slot).double_hash_pw(new_pw, &salt)?;
+        let encrypted_file_pw = encrypt_file_pw(repo.enc_type, &hashed_pw, &nonce, &file_pw_aad(&repo.id, &salt), &file_pw)?;
+
+        let mut updated = StoredRepositoryV1 {
+            salt: Cow::from(salt),
+            nonce: Cow::from(nonce),
+            double_hashed_pw: Cow::from(double_hash_pw.to_vec()),
+            encrypted_file_pw: Cow::from(encrypted_file_pw),
+            pending_file_pw: None,
+            ..repo
+        };
+        if let Some(pending) = pending_file_pw {
+            let encrypted = encrypt_pending_file_pw(&updated, &hashed_pw, &pending)?;
+            updated.pending_file_pw = Some(Cow::from(encrypted));
+        }
+        Ok(updated)
+    })
+}
 
-                let res = StoredRepositoryV1::from_reader(&mut reader, wrapper.content.as_ref());
+/// Replaces the file key with a new random one and re-encrypts every file of the repository with it.
+/// Use this when the file key itself might have leaked, changing the password is not enough then.
+///
+/// The files keep their versions and are not archived in the history, only their encryption changes.
+/// The new key is stored in the repository file before the first content file gets touched.
+/// If the rotation gets interrupted, files that were already converted cannot be read
+/// until `rotate_file_key` is called again, which continues with the same key.
+///
+/// The new key can only be wrapped for the password given here, so further users have to be
+/// removed before and added again afterwards, otherwise this fails with `MultipleUsers`.
+pub fn rotate_file_key(source: &mut impl FileSource, id: RepositoryId, pw: &Plaintext) -> Result<Repository, Error> {
+    use crypt::{random_bytes, DeEncrypter, KEY_LENGTH};
+
+    let device_id = tombstone::local_device_id(&*source, id)?;
+    let (old, hashed_pw, new_file_pw, pending_stored) = load_repository(source, id, |_, repo| {
+        let hashed_pw = hash_checked_pw(&primary_slot(&repo), pw)?;
+        if !repo.key_slots.is_empty() {
+            return Err(Error::from(ErrorKind::MultipleUsers));
+        }
+        let old = Repository {
+            id: RepositoryId::from_bytes(repo.id.as_ref())?,
+            file_pw: decrypt_file_pw(repo.enc_type, &hashed_pw, repo.nonce.as_ref(), &file_pw_aad(&repo.id, &repo.salt), &repo.encrypted_file_pw)?,
+            double_hash_pw: DoubleHashedPw::from(repo.double_hashed_pw.as_ref()),
+            encryption_type: repo.enc_type,
+            name: repo.name.to_string(),
+            device_id,
+            history: history::stored_retention(&repo),
+        };
+        let (new_file_pw, pending_stored) = match repo.pending_file_pw {
+            Some(ref pending) => (decrypt_pending_file_pw(&repo, &hashed_pw, pending)?, true),
+            None => (HashedPw::from(random_bytes(KEY_LENGTH)?.as_ref()), false),
+        };
+        Ok((old, hashed_pw, new_file_pw, pending_stored))
+    })?;
+
+    if !pending_stored {
+        rewrite_repository(source, id, |repo| {
+            let encrypted = encrypt_pending_file_pw(&repo, &hashed_pw, &new_file_pw)?;
+            Ok(StoredRepositoryV1 { pending_file_pw: Some(Cow::from(encrypted)), ..repo })
+        })?;
+    }
+
+    let new = Repository {
+        file_pw: new_file_pw.clone(),
+        ..old.clone()
+    };
+    for file in old.list_all_files(source)? {
+        if new.read_header(source, &file).is_ok() {
+            continue;
+        }
+        let header = old.read_header(source, &file)?;
+        new.store_file_as(source, &file, FileType::FileV1, &header, &mut old.content_reader(source, &file)?)?;
+    }
+    new.reencrypt_history(source, &old)?;
+
+    rewrite_repository(source, id, |repo| {
+        let nonce = random_bytes(repo.enc_type.nonce_length())?;
+        let encrypted_file_pw = encrypt_file_pw(repo.enc_type, &hashed_pw, &nonce, &file_pw_aad(&repo.id, &repo.salt), &new_file_pw)?;
+        Ok(StoredRepositoryV1 {
+            nonce: Cow::from(nonce),
+            encrypted_file_pw: Cow::from(encrypted_file_pw),
+            pending_file_pw: None,
+            ..repo
+        })
+    })?;
+    Ok(new)
+}
 
-                if let Ok(repo) = res {
-                    if repo.id.as_ref() == id.as_bytes() {
-                        repository = Some(callback(repo)?);
-                    }
-                }
+/// Passes the stored repository with the given id to `callback`.
+/// A repository file that cannot be parsed might belong to another repository, so the search goes on.
+/// If the repository is not found, the error of the first such file is returned instead of `RepositoryNotFound`.
+fn load_repository<R, F>(source: &impl FileSource, id: RepositoryId, callback: F) -> Result<R, Error>
+    where F: FnOnce(&str, StoredRepositoryV1) -> Result<R, Error> {
+    let mut damaged = None;
+    for name in source.list_repositories()? {
+        let content = source.get_file_content(&name).in_file(&name)?;
+        let wrapper: StoredFileWrapper = match read_message(&content).in_file(&name) {
+            Ok(wrapper) => wrapper,
+            Err(e) => {
+                damaged.get_or_insert(e);
+                continue;
+            }
+        };
+        if wrapper.type_pb != FileType::RepositoryV1 {
+            damaged.get_or_insert(Error::from(ErrorKind::UnexpectedFileType { file_name: name.clone(), file_type: wrapper.type_pb }));
+            continue;
+        }
+        let repo: StoredRepositoryV1 = match read_message(&wrapper.content).in_file(&name) {
+            Ok(repo) => repo,
+            Err(e) => {
+                damaged.get_or_insert(e);
+                continue;
+            }
+        };
+        if repo.id.as_ref() == id.as_bytes() {
+            if repo.version != REPOSITORY_VERSION {
+                return Err(Error::from(ErrorKind::UnsupportedVersion { file_name: name, version: repo.version }));
             }
+            // the password is checked first, so a failing decryption means the file is damaged
+            return callback(&name, repo).in_file(&name);
         }
     }
+    Err(damaged.unwrap_or_else(|| Error::from(ErrorKind::RepositoryNotFound(id))))
+}
+
+/// Replaces the stored repository file with the result of `update`.
+fn rewrite_repository<F>(source: &mut impl FileSource, id: RepositoryId, update: F) -> Result<(), Error>
+    where F: for<'a> FnOnce(StoredRepositoryV1<'a>) -> Result<StoredRepositoryV1<'a>, Error> {
+    let (file_name, data) = load_repository(source, id, |file_name, repo| {
+        let updated = update(repo)?;
+        Ok((file_name.to_string(), wrap_message(FileType::RepositoryV1, &updated)?))
+    })?;
+    source.store_file(&file_name, &data).in_file(&file_name)
+}
 
-    if let Some(repo) = repository {
-        Ok(repo)
-    } else {
-        Err(Error::from(ErrorKind::RepositoryNotFound(id)))
+/// Serializes the message and wraps it into a `StoredFileWrapper` of the given type,
+/// ready to be passed to `FileSource::store_file`.
+pub fn wrap_message<M: MessageWrite>(file_type: FileType, message: &M) -> Result<Vec<u8>, Error> {
+    let content = write_message(message)?;
+    let wrapper = StoredFileWrapper {
+        type_pb: file_type,
+        content: Cow::from(content),
+        content_chunks: Vec::new(),
+    };
+    write_message(&wrapper)
+}
+
+pub fn write_message<M: MessageWrite>(message: &M) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(message.get_size());
+    message.write_message(&mut Writer::new(&mut out))?;
+    Ok(out)
+}
+
+pub fn read_message<'a, M: MessageRead<'a>>(bytes: &'a [u8]) -> Result<M, Error> {
+    let mut reader = BytesReader::from_bytes(bytes);
+    Ok(M::from_reader(&mut reader, bytes)?)
+}
+
+fn get_password(repo: &StoredRepositoryV1, slot: &KeySlot, pw: &Plaintext) -> Result<HashedPw, Error> {
+    let hashed_pw = hash_checked_pw(slot, pw)?;
+    decrypt_file_pw(repo.enc_type, &hashed_pw, slot.nonce.as_ref(), &file_pw_aad(&repo.id, &slot.salt), &slot.encrypted_file_pw)
+}
+
+/// Derives the key that encrypts the file key from the password, after checking the password against the stored double hash.
+/// The hashes are compared in constant time, a mismatch is reported as `InvalidPassword`.
+fn hash_checked_pw(slot: &KeySlot, pw: &Plaintext) -> Result<HashedPw, Error> {
+    use crypt::Hasher;
+    use subtle::ConstantTimeEq;
+
+    let hasher = hasher(slot);
+    let hashed_pw = hasher.hash_pw(pw, slot.salt.as_ref())?;
+    let double_hashed_pw = hasher.hash_pw(hashed_pw.as_ref(), slot.salt.as_ref())?;
+    if !bool::from(double_hashed_pw.as_slice().ct_eq(slot.double_hashed_pw.as_ref())) {
+        return Err(Error::from(ErrorKind::InvalidPassword));
     }
+    Ok(hashed_pw)
+}
+
+fn hasher(slot: &KeySlot) -> PasswordHasher {
+    PasswordHasher {
+        hash_type: slot.hash_type,
+        parameters: slot.hash_parameters.clone(),
+    }
+}
+
+fn file_pw_aad(id: &[u8], salt: &[u8]) -> Vec<u8> {
+    [id, salt].concat()
+}
+
+/// Encrypts the file key with the password derived key, the auth tag is appended to the result.
+fn encrypt_file_pw(enc_type: EncryptionType, hashed_pw: &HashedPw, nonce: &Nonce, aad: &AAD, file_pw: &HashedPw) -> Result<CipherTextVec, Error> {
+    use crypt::DeEncrypter;
+
+    let (mut encrypted, mut tag) = enc_type.encrypt(hashed_pw, nonce, aad, file_pw)?;
+    encrypted.append(&mut tag);
+    Ok(encrypted)
 }
 
+fn decrypt_file_pw(enc_type: EncryptionType, hashed_pw: &HashedPw, nonce: &Nonce, aad: &AAD, encrypted: &[u8]) -> Result<HashedPw, Error> {
+    use crypt::{AuthTagProvider, DeEncrypter};
 
-fn get_password<'a, 'b>(repo: &'a StoredRepositoryV1<'a>, pw: &'b Plaintext) -> Result<PlaintextVec, Error> {
-    use chacha20_poly1305_aead::decrypt;
-    use crypt::{Hasher, AuthTagProvider, DeEncrypter};
-    use std::slice::SliceConcatExt;
+    let (data, tag) = enc_type.get_auth_tag(encrypted)?;
+    let decrypted = enc_type.decrypt(hashed_pw, nonce, aad, tag, data)?;
+    Ok(HashedPw { content: decrypted })
+}
+
+/// The pending file key of an unfinished key rotation carries its own nonce in front of the cipher text.
+fn encrypt_pending_file_pw(repo: &StoredRepositoryV1, hashed_pw: &HashedPw, file_pw: &HashedPw) -> Result<Vec<u8>, Error> {
+    use crypt::{random_bytes, DeEncrypter};
 
-    let hashed_pw = repo.hash_type.hash_pw(pw, repo.salt.as_ref());
-    let (data, authtag) = repo.enc_type.get_auth_tag(repo.encrypted_file_pw.as_ref())?;
-    let aad: &[u8] = &[repo.id.as_ref(), repo.salt.as_ref()].concat();
+    let nonce = random_bytes(repo.enc_type.nonce_length())?;
+    let aad = [repo.id.as_ref(), repo.salt.as_ref(), b"pending"].concat();
+    let encrypted = encrypt_file_pw(repo.enc_type, hashed_pw, &nonce, &aad, file_pw)?;
+    Ok([nonce, encrypted].concat())
+}
 
-    let decrypted = repo.enc_type.decrypt(&hashed_pw, repo.nonce.as_ref(), aad, authtag, data)?;
+fn decrypt_pending_file_pw(repo: &StoredRepositoryV1, hashed_pw: &HashedPw, pending: &[u8]) -> Result<HashedPw, Error> {
+    use crypt::DeEncrypter;
 
-    Ok(decrypted)
+    let nonce_length = repo.enc_type.nonce_length();
+    if pending.len() < nonce_length {
+        return Err(Error::from(ErrorKind::DataTooShort { msg: "pending file key".into(), expected_size: nonce_length, real_size: pending.len() }));
+    }
+    let (nonce, encrypted) = pending.split_at(nonce_length);
+    let aad = [repo.id.as_ref(), repo.salt.as_ref(), b"pending"].concat();
+    decrypt_file_pw(repo.enc_type, hashed_pw, nonce, &aad, encrypted)
 }
 
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::io;
     use uuid::Uuid;
 
 
@@ -83,28 +372,19 @@ mod test {
             unimplemented!()
         }
 
-        fn get_file_content(&self, name: &str) -> Result<Vec<u8>, Error> {
-            use quick_protobuf::Writer;
-            use std::borrow::Cow;
-
-            let message = get_repo();
-            let mut out = Vec::new();
-            {
-                let mut writer = Writer::new(&mut out);
-                writer.write_message(&message)?;
-            }
+        fn list_file_versions(&self) -> Result<Vec<String>, Error> {
+            unimplemented!()
+        }
 
-            let message = StoredFileWrapper {
-                type_pb: FileType::RepositoryV1,
-                content: Cow::from(out)
-            };
-            let mut out = Vec::new();
-            {
-                let mut writer = Writer::new(&mut out);
-                writer.write_message(&message)?;
-            }
+        fn list_names(&self) -> Result<Vec<String>, Error> {
+            unimplemented!()
+        }
 
-            Ok(out)
+        fn get_file_content(&self, name: &str) -> Result<Vec<u8>, Error> {
+            if name.ends_with(".sync") {
+                return Err(Error::from(io::Error::from(io::ErrorKind::NotFound)));
+            }
+            wrap_message(FileType::RepositoryV1, &get_repo())
         }
 
         fn peek_file_content(&self, name: &str, len: usize) -> Result<Vec<u8>, Error> {
@@ -114,6 +394,14 @@ mod test {
         fn store_file(&mut self, file_name: &str, data: &[u8]) -> Result<(), Error> {
             unimplemented!()
         }
+
+        fn delete_file(&mut self, file_name: &str) -> Result<(), Error> {
+            unimplemented!()
+        }
+
+        fn quarantine_file(&mut self, file_name: &str) -> Result<(), Error> {
+            unimplemented!()
+        }
     }
 
     fn get_uuid() -> Uuid {
@@ -126,12 +414,12 @@ mod test {
     fn get_repo<'a>() -> StoredRepositoryV1<'a> {
         use std::borrow::Cow;
         use crypt::{Hasher, DeEncrypter};
-        use std::slice::SliceConcatExt;
 
         let id = get_uuid();
         let nonce = [9u8; 12];
-        let hash_pw = PasswordHashType::Argon2i.hash_pw(b"hallo welt", &nonce);
-        let double_hash_pw = PasswordHashType::Argon2i.double_hash_pw(b"hallo welt", &nonce);
+        let hasher = PasswordHasher { hash_type: PasswordHashType::Argon2i, parameters: None };
+        let hash_pw = hasher.hash_pw(b"hallo welt", &nonce).unwrap();
+        let double_hash_pw = hasher.double_hash_pw(b"hallo welt", &nonce).unwrap();
         let aad: &[u8] = &[id.as_bytes().as_ref(), nonce.as_ref()].concat();
         let (mut encrypted_pw, mut tag) = EncryptionType::ChachaPoly1305.encrypt(&hash_pw, &nonce, aad, b"real password").unwrap();
 
@@ -145,8 +433,14 @@ mod test {
             name: Cow::from("test repo"),
             id: Cow::from(id.as_bytes().to_vec()),
             version: 1,
-            double_hashed_pw: Cow::from(double_hash_pw.content),
+            double_hashed_pw: Cow::from(double_hash_pw.to_vec()),
             encrypted_file_pw: Cow::from(encrypted_pw),
+            pending_file_pw: None,
+            hash_parameters: None,
+            user_name: None,
+            key_slots: Vec::new(),
+            kept_versions: None,
+            max_version_age: None,
         }
     }
 
@@ -154,5 +448,249 @@ mod test {
     fn test_open_repo() {
         let file_source = TestFileSource {};
         let repo = open_repository(&file_source, get_uuid(), b"hallo welt").unwrap();
+        assert_eq!(b"real password".as_ref(), repo.file_pw.as_slice());
+    }
+
+    #[test]
+    fn test_create_repo() {
+        use crypt::fast_hash_parameters;
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("create_repo").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let created = create_repository(&mut source, "my repo", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+
+        assert_eq!(vec![created.id.hyphenated().to_string()], source.list_repositories().unwrap());
+
+        let opened = open_repository(&source, created.id, b"secret").unwrap();
+        assert_eq!("my repo", opened.name);
+        assert_eq!(created.file_pw.as_slice(), opened.file_pw.as_slice());
+        assert_eq!(created.double_hash_pw.as_slice(), opened.double_hash_pw.as_slice());
+
+        assert!(open_repository(&source, created.id, b"wrong").is_err());
+    }
+
+    #[test]
+    fn test_list_repositories() {
+        use crypt::fast_hash_parameters;
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("list_repos").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        assert!(list_repositories(&source).unwrap().is_empty());
+
+        let created = create_repository(&mut source, "my repo", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let damaged = StoredFileWrapper { type_pb: FileType::RepositoryV1, content: Cow::from(&[0xffu8, 0xff, 0xff][..]), content_chunks: Vec::new() };
+        assert_eq!(vec![RepositoryDescriptor { id: created.id, name: "my repo".into() }], list_repositories(&source).unwrap());
+
+        source.store_file("damaged", &write_message(&damaged).unwrap()).unwrap();
+        let error = list_repositories(&source).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::CorruptedFile { file_name, .. }) if file_name == "damaged"));
+    }
+
+    #[test]
+    fn test_damaged_repository_reported() {
+        use crypt::fast_hash_parameters;
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("damaged_repo").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let created = create_repository(&mut source, "repo", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let file_name = created.id.hyphenated().to_string();
+
+        rewrite_repository(&mut source, created.id, |repo| Ok(StoredRepositoryV1 { version: 2, ..repo })).unwrap();
+        let error = open_repository(&source, created.id, b"secret").err().unwrap();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::UnsupportedVersion { version: 2, .. })));
+
+        let damaged = StoredFileWrapper { type_pb: FileType::RepositoryV1, content: Cow::from(&[0xffu8, 0xff, 0xff][..]), content_chunks: Vec::new() };
+        source.store_file(&file_name, &write_message(&damaged).unwrap()).unwrap();
+        let error = open_repository(&source, created.id, b"secret").err().unwrap();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::CorruptedFile { file_name: name, .. }) if *name == file_name));
+    }
+
+    #[test]
+    fn test_wrong_password_told_apart_from_damage() {
+        use crypt::fast_hash_parameters;
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("wrong_password").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let created = create_repository(&mut source, "repo", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+
+        let error = open_repository(&source, created.id, b"wrong").err().unwrap();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::InvalidPassword)));
+        let error = change_password(&mut source, created.id, b"wrong", b"new").unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::InvalidPassword)));
+        let error = rotate_file_key(&mut source, created.id, b"wrong").err().unwrap();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::InvalidPassword)));
+
+        rewrite_repository(&mut source, created.id, |repo| {
+            let mut encrypted_file_pw = repo.encrypted_file_pw.to_vec();
+            encrypted_file_pw[0] ^= 1;
+            Ok(StoredRepositoryV1 { encrypted_file_pw: Cow::from(encrypted_file_pw), ..repo })
+        }).unwrap();
+        let error = open_repository(&source, created.id, b"secret").err().unwrap();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::AuthenticationFailed { file_name }) if *file_name == created.id.hyphenated().to_string()));
+    }
+
+    #[test]
+    fn test_hash_parameters_stored() {
+        use crypt::fast_hash_parameters;
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("hash_parameters").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let parameters = PasswordHashParameters { iterations: 2, ..fast_hash_parameters() };
+        let created = create_repository(&mut source, "repo", b"pw", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2id, parameters.clone()).unwrap();
+
+        let (hash_type, stored) = load_repository(&source, created.id, |_, repo| Ok((repo.hash_type, repo.hash_parameters))).unwrap();
+        assert_eq!(PasswordHashType::Argon2id, hash_type);
+        assert_eq!(Some(parameters), stored);
+        assert!(open_repository(&source, created.id, b"pw").is_ok());
+    }
+
+    #[test]
+    fn test_scrypt_repository() {
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("scrypt_repository").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let parameters = PasswordHashParameters { iterations: 4, memory_costs: 2, parallelism: 1 };
+        let created = create_repository(&mut source, "repo", b"pw", EncryptionType::ChachaPoly1305, PasswordHashType::SCrypt, parameters).unwrap();
+
+        let opened = open_repository(&source, created.id, b"pw").unwrap();
+        assert_eq!(created.file_pw.as_slice(), opened.file_pw.as_slice());
+        assert!(open_repository(&source, created.id, b"wrong").is_err());
+    }
+
+    #[test]
+    fn test_xchacha_repository() {
+        use crypt::fast_hash_parameters;
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("xchacha_repository").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let created = create_repository(&mut source, "repo", b"pw", EncryptionType::XChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let file = created.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+
+        let nonce_length = load_repository(&source, created.id, |_, repo| Ok(repo.nonce.len())).unwrap();
+        assert_eq!(24, nonce_length);
+
+        let rotated = rotate_file_key(&mut source, created.id, b"pw").unwrap();
+        let files = rotated.list_files(&source).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(EncryptionType::XChachaPoly1305, files[0].encryption_type);
+        assert_eq!(b"content".to_vec(), rotated.read_content(&source, &files[0]).unwrap());
+        assert!(created.read_content(&source, &files[0]).is_err());
+        assert_eq!(file.version, files[0].version);
+    }
+
+    struct FailingFileSource {
+        inner: ::files::DirectoryFileSource,
+        remaining_writes: usize,
+    }
+
+    impl FileSource for FailingFileSource {
+        fn list_repositories(&self) -> Result<Vec<String>, Error> {
+            self.inner.list_repositories()
+        }
+
+        fn list_files(&self) -> Result<Vec<String>, Error> {
+            self.inner.list_files()
+        }
+
+        fn list_file_versions(&self) -> Result<Vec<String>, Error> {
+            self.inner.list_file_versions()
+        }
+
+        fn list_names(&self) -> Result<Vec<String>, Error> {
+            self.inner.list_names()
+        }
+
+        fn get_file_content(&self, name: &str) -> Result<Vec<u8>, Error> {
+            self.inner.get_file_content(name)
+        }
+
+        fn peek_file_content(&self, name: &str, len: usize) -> Result<Vec<u8>, Error> {
+            self.inner.peek_file_content(name, len)
+        }
+
+        fn store_file(&mut self, file_name: &str, data: &[u8]) -> Result<(), Error> {
+            if self.remaining_writes == 0 {
+                return Err(Error::from(io::Error::new(io::ErrorKind::Other, "write failed")));
+            }
+            self.remaining_writes -= 1;
+            self.inner.store_file(file_name, data)
+        }
+
+        fn delete_file(&mut self, file_name: &str) -> Result<(), Error> {
+            self.inner.delete_file(file_name)
+        }
+
+        fn quarantine_file(&mut self, file_name: &str) -> Result<(), Error> {
+            self.inner.quarantine_file(file_name)
+        }
+    }
+
+    #[test]
+    fn test_change_password() {
+        use crypt::fast_hash_parameters;
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("change_password").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create_repository(&mut source, "repo", b"old", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let file_data = source.get_file_content(&file.file_name).unwrap();
+
+        assert!(change_password(&mut source, repo.id, b"wrong", b"new").is_err());
+        change_password(&mut source, repo.id, b"old", b"new").unwrap();
+
+        assert!(open_repository(&source, repo.id, b"old").is_err());
+        let opened = open_repository(&source, repo.id, b"new").unwrap();
+        assert_eq!(repo.file_pw.as_slice(), opened.file_pw.as_slice());
+        assert_eq!(file_data, source.get_file_content(&file.file_name).unwrap());
+        assert_eq!(b"content".to_vec(), opened.read_content(&source, &file).unwrap());
+    }
+
+    #[test]
+    fn test_rotate_file_key() {
+        use crypt::fast_hash_parameters;
+        use files::DirectoryFileSource;
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("rotate_file_key").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create_repository(&mut source, "repo", b"pw", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        for i in 0..3u8 {
+            repo.create_file(&mut source, &[i], &[i; 10], CompressionType::DeflateZip).unwrap();
+        }
+
+        let mut failing = FailingFileSource { inner: source, remaining_writes: 2 };
+        assert!(rotate_file_key(&mut failing, repo.id, b"pw").is_err());
+        let mut source = failing.inner;
+
+        let rotated = rotate_file_key(&mut source, repo.id, b"pw").unwrap();
+        assert_ne!(repo.file_pw.as_slice(), rotated.file_pw.as_slice());
+
+        let opened = open_repository(&source, repo.id, b"pw").unwrap();
+        assert_eq!(rotated.file_pw.as_slice(), opened.file_pw.as_slice());
+        let files = opened.list_files(&source).unwrap();
+        assert_eq!(3, files.len());
+        for file in files.iter() {
+            let header = opened.read_header(&source, file).unwrap();
+            assert_eq!(vec![header[0]; 10], opened.read_content(&source, file).unwrap());
+            assert!(repo.read_header(&source, file).is_err());
+            assert_eq!(0, file.version);
+            assert!(opened.list_versions(&source, file.id).unwrap().is_empty());
+        }
     }
 }
\ No newline at end of file
@@ -1,27 +1,37 @@
-use crypt::{DoubleHashedPw, CipherTextVec};
+use crypt::{DoubleHashedPw, HashedPw};
 use failure::Error;
-use std::convert::TryFrom;
+use pb::file::EncryptionType;
+use super::history::HistoryRetention;
+use super::version::DeviceId;
 use uuid::Uuid;
 
 pub type RepositoryId = Uuid;
 pub type RepositoryName = String;
 
+/// What can be known about a repository without opening it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepositoryDescriptor {
+    pub id: RepositoryId,
+    pub name: RepositoryName,
+}
+
+#[derive(Clone)]
 pub struct Repository {
     pub name: RepositoryName,
     pub id: RepositoryId,
     pub double_hash_pw: DoubleHashedPw,
-    pub file_pw: CipherTextVec,
+    pub file_pw: HashedPw,
+    pub encryption_type: EncryptionType,
+    /// Counts the changes of this device in the version vectors of the files.
+    /// It is kept in the local sync state, so every open from the same folder reuses it.
+    pub device_id: DeviceId,
+    /// Which previous versions of the files are kept when they get replaced, see `set_history_retention`.
+    pub history: HistoryRetention,
 }
-
-impl<'a> TryFrom<::pb::file::StoredRepositoryV1<'a>> for Repository {
-    type Error = ::failure::Error;
-
-    fn try_from(original: ::pb::file::StoredRepositoryV1<'a>) -> Result<Repository, Error> {
-        Ok(Repository {
-            name: original.name.into(),
-            id: Uuid::from_bytes(original.id.as_ref())?,
-            double_hash_pw: DoubleHashedPw::from(original.double_hashed_pw.as_ref()),
-            file_pw: original.encrypted_file_pw.into(),
-        })
+impl Repository {
+    /// Locks the keys of the repository into RAM, so they are never swapped to disk. Only supported on Linux.
+    pub fn lock_secrets(&mut self) -> Result<(), Error> {
+        self.file_pw.content.lock()?;
+        self.double_hash_pw.content.lock()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,302 @@
+//! Garbage collection of tombstones.
+//!
+//! A tombstone has to stay until every peer has seen it, otherwise a peer that still has the file would bring it back.
+//! The local sync state of a repository records when the last complete sync with each known peer started
+//! and since when each tombstone is stored locally. It is stored next to the repository and never synced.
+//! It also keeps the device id of the folder, so the version vectors get one entry per installation instead of one per open,
+//! and the version vector of every file as it was last written or received, so a file that is replaced by an older copy is noticed.
+//! A folder that is rolled back as a whole, this state included, is not noticed.
+
+use super::file::{now_millis, FileId};
+use super::repository::{Repository, RepositoryId};
+use super::version::{DeviceId, VersionVector};
+use super::{read_message, wrap_message};
+use ::error::{is_not_found, ErrorKind, FileContext};
+use ::files::FileSource;
+use ::pb::file::{FileType, LocalTombstone, LocalVersion, PeerSync, StoredFileWrapper, StoredSyncStateV1};
+use failure::Error;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+struct SyncState {
+    peers: BTreeMap<DeviceId, u64>,
+    tombstones: BTreeMap<FileId, u64>,
+    device_id: Option<DeviceId>,
+    versions: BTreeMap<FileId, VersionVector>,
+}
+
+/// The device id stored for the repository in this source, a new one if there is none yet.
+/// It gets stored with the next write of the sync state, see `Repository::remember_device_id`.
+pub fn local_device_id(source: &impl FileSource, id: RepositoryId) -> Result<DeviceId, Error> {
+    Ok(load_sync_state(source, id)?.device_id.unwrap_or_else(DeviceId::new_v4))
+}
+
+impl Repository {
+    /// Records a complete sync with the peer, `started` is the time in milliseconds since the unix epoch when it started.
+    pub fn record_peer_sync(&self, source: &mut impl FileSource, peer: DeviceId, started: u64) -> Result<(), Error> {
+        self.update_sync_state(source, |state| {
+            state.peers.insert(peer, started);
+        })
+    }
+
+    /// Stops waiting for the peer before tombstones are purged, e.g. for a device that is gone for good.
+    pub fn forget_peer(&self, source: &mut impl FileSource, peer: DeviceId) -> Result<(), Error> {
+        self.update_sync_state(source, |state| {
+            state.peers.remove(&peer);
+        })
+    }
+
+    /// The known peers and when the last complete sync with each of them started.
+    pub fn known_peers(&self, source: &impl FileSource) -> Result<Vec<(DeviceId, u64)>, Error> {
+        Ok(load_sync_state(source, self.id)?.peers.into_iter().collect())
+    }
+
+    /// Records that a tombstone is stored locally since the given time, it has to be called whenever one is stored.
+    pub fn note_tombstone(&self, source: &mut impl FileSource, file_id: FileId, since: u64) -> Result<(), Error> {
+        self.update_sync_state(source, |state| {
+            state.tombstones.insert(file_id, since);
+        })
+    }
+
+    /// The files whose tombstones are tracked for purging.
+    pub fn noted_tombstones(&self, source: &impl FileSource) -> Result<Vec<FileId>, Error> {
+        Ok(load_sync_state(source, self.id)?.tombstones.keys().cloned().collect())
+    }
+
+    /// Records the version of a file that was written or received, it has to be called whenever one is stored.
+    pub fn note_version(&self, source: &mut impl FileSource, file_id: FileId, version_vector: &VersionVector) -> Result<(), Error> {
+        self.update_sync_state(source, |state| {
+            state.versions.insert(file_id, version_vector.clone());
+        })
+    }
+
+    /// The version vectors of the files as they were last written or received.
+    pub fn noted_versions(&self, source: &impl FileSource) -> Result<BTreeMap<FileId, VersionVector>, Error> {
+        Ok(load_sync_state(source, self.id)?.versions)
+    }
+
+    /// Stores the device id in the sync state unless one is stored already, so the next open reuses it.
+    pub fn remember_device_id(&self, source: &mut impl FileSource) -> Result<(), Error> {
+        let state = load_sync_state(source, self.id)?;
+        if state.device_id.is_none() {
+            self.store_sync_state(source, &state)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the tombstones stored locally for longer than the horizon which every known peer has seen,
+    /// i.e. that were stored before the last complete sync with the peer started. Returns the ids of the purged files.
+    ///
+    /// Peers whose last complete sync started longer than `peer_timeout` ago are forgotten first, so a device
+    /// that is gone does not keep the tombstones forever. Such a peer brings deleted files back if it returns.
+    pub fn purge_tombstones(&self, source: &mut impl FileSource, horizon: Duration, peer_timeout: Duration) -> Result<Vec<FileId>, Error> {
+        let now = now_millis();
+        let cutoff = now.saturating_sub(millis(horizon));
+        let mut state = load_sync_state(source, self.id)?;
+        let peer_cutoff = now.saturating_sub(millis(peer_timeout));
+        state.peers = state.peers.into_iter().filter(|&(_, last_sync)| last_sync > peer_cutoff).collect();
+
+        let mut tombstones = BTreeMap::new();
+        let mut purged = Vec::new();
+        for file in self.list_all_files(source)? {
+            if file.deleted.is_none() {
+                continue;
+            }
+            // tombstones stored before they were tracked count from now on
+            let since = state.tombstones.get(&file.id).cloned().unwrap_or(now);
+            if since <= cutoff && state.peers.values().all(|last_sync| *last_sync > since) {
+                source.delete_file(&file.file_name)?;
+                self.delete_history(source, file.id)?;
+                state.versions.remove(&file.id);
+                purged.push(file.id);
+            } else {
+                tombstones.insert(file.id, since);
+            }
+        }
+        state.tombstones = tombstones;
+        self.store_sync_state(source, &state)?;
+        Ok(purged)
+    }
+
+    fn update_sync_state<F>(&self, source: &mut impl FileSource, update: F) -> Result<(), Error>
+        where F: FnOnce(&mut SyncState) {
+        let mut state = load_sync_state(source, self.id)?;
+        update(&mut state);
+        self.store_sync_state(source, &state)
+    }
+
+    /// Stores the state together with the device id of this repository, unless the state has one already.
+    fn store_sync_state(&self, source: &mut impl FileSource, state: &SyncState) -> Result<(), Error> {
+        let stored = StoredSyncStateV1 {
+            repository_id: Cow::from(self.id.as_bytes().as_ref()),
+            peers: state.peers.iter()
+                .map(|(device, last_sync)| PeerSync { device_id: Cow::from(device.as_bytes().to_vec()), last_sync: *last_sync })
+                .collect(),
+            tombstones: state.tombstones.iter()
+                .map(|(file_id, since)| LocalTombstone { file_id: Cow::from(file_id.as_bytes().to_vec()), since: *since })
+                .collect(),
+            device_id: Some(Cow::from(state.device_id.unwrap_or(self.device_id).as_bytes().to_vec())),
+            versions: state.versions.iter()
+                .map(|(file_id, version_vector)| LocalVersion { file_id: Cow::from(file_id.as_bytes().to_vec()), version_vector: version_vector.to_stored() })
+                .collect(),
+        };
+        source.store_file(&sync_state_name(self), &wrap_message(FileType::SyncStateV1, &stored)?)
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+fn load_sync_state(source: &impl FileSource, id: RepositoryId) -> Result<SyncState, Error> {
+    let name = state_file_name(id);
+    let data = match source.get_file_content(&name) {
+        Ok(data) => data,
+        Err(ref e) if is_not_found(e) => return Ok(SyncState::default()),
+        Err(e) => return Err(e).in_file(&name),
+    };
+    let wrapper: StoredFileWrapper = read_message(&data).in_file(&name)?;
+    if wrapper.type_pb != FileType::SyncStateV1 {
+        return Err(Error::from(ErrorKind::UnexpectedFileType { file_name: name, file_type: wrapper.type_pb }));
+    }
+    let stored: StoredSyncStateV1 = read_message(&wrapper.content).in_file(&name)?;
+    let repository_id = RepositoryId::from_bytes(stored.repository_id.as_ref()).in_file(&name)?;
+    if repository_id != id {
+        return Err(Error::from(ErrorKind::WrongRepository { file_name: name, expected: id, found: repository_id }));
+    }
+
+    let mut state = SyncState::default();
+    for peer in &stored.peers {
+        state.peers.insert(DeviceId::from_bytes(peer.device_id.as_ref())?, peer.last_sync);
+    }
+    for tombstone in &stored.tombstones {
+        state.tombstones.insert(FileId::from_bytes(tombstone.file_id.as_ref())?, tombstone.since);
+    }
+    if let Some(ref device_id) = stored.device_id {
+        state.device_id = Some(DeviceId::from_bytes(device_id.as_ref())?);
+    }
+    for version in &stored.versions {
+        state.versions.insert(FileId::from_bytes(version.file_id.as_ref())?, VersionVector::from_devices(&version.version_vector).in_file(&name)?);
+    }
+    Ok(state)
+}
+
+pub fn sync_state_name(repo: &Repository) -> String {
+    state_file_name(repo.id)
+}
+
+fn state_file_name(id: RepositoryId) -> String {
+    format!("{}.sync", id.hyphenated())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::crypt::fast_hash_parameters;
+    use ::files::DirectoryFileSource;
+    use ::pb::file::{CompressionType, EncryptionType, PasswordHashType};
+    use ::repository::{create_repository, open_repository};
+    use std::thread;
+    use tempdir::TempDir;
+
+    fn peer_timeout() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn setup() -> (TempDir, DirectoryFileSource, Repository) {
+        let dir = TempDir::new("repository_tombstone").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create_repository(&mut source, "tombstones", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        (dir, source, repo)
+    }
+
+    #[test]
+    fn test_deleted_file_listed_as_tombstone() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let tombstone = repo.delete_file(&mut source, &file).unwrap();
+
+        assert!(tombstone.deleted.is_some());
+        assert_eq!(file.version + 1, tombstone.version);
+        assert!(repo.list_files(&source).unwrap().is_empty());
+        assert_eq!(vec![file.id], repo.list_all_files(&source).unwrap().iter().map(|f| f.id).collect::<Vec<_>>());
+        assert_eq!(1, repo.list_file_syncs(&source).unwrap().len());
+        assert!(repo.read_header(&source, &tombstone).unwrap().is_empty());
+        assert!(repo.read_content(&source, &tombstone).unwrap().is_empty());
+        assert!(repo.delete_file(&mut source, &file).is_err());
+
+        let restored = repo.update_file(&mut source, &tombstone, b"header", b"restored").unwrap();
+        assert!(restored.deleted.is_none());
+        assert_eq!(1, repo.list_files(&source).unwrap().len());
+    }
+
+    #[test]
+    fn test_purge_waits_for_horizon() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        repo.delete_file(&mut source, &file).unwrap();
+
+        assert!(repo.purge_tombstones(&mut source, Duration::from_secs(3600), peer_timeout()).unwrap().is_empty());
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(vec![file.id], repo.purge_tombstones(&mut source, Duration::from_millis(1), peer_timeout()).unwrap());
+        assert!(repo.list_all_files(&source).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_purge_waits_for_peers() {
+        let (_dir, mut source, repo) = setup();
+        let (peer, other_peer) = (DeviceId::new_v4(), DeviceId::new_v4());
+        repo.record_peer_sync(&mut source, peer, now_millis()).unwrap();
+        repo.record_peer_sync(&mut source, other_peer, now_millis()).unwrap();
+        thread::sleep(Duration::from_millis(5));
+
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        repo.delete_file(&mut source, &file).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        assert!(repo.purge_tombstones(&mut source, Duration::from_millis(0), peer_timeout()).unwrap().is_empty());
+
+        repo.record_peer_sync(&mut source, peer, now_millis()).unwrap();
+        assert!(repo.purge_tombstones(&mut source, Duration::from_millis(0), peer_timeout()).unwrap().is_empty());
+
+        repo.forget_peer(&mut source, other_peer).unwrap();
+        assert_eq!(vec![peer], repo.known_peers(&source).unwrap().iter().map(|p| p.0).collect::<Vec<_>>());
+        assert_eq!(vec![file.id], repo.purge_tombstones(&mut source, Duration::from_millis(0), peer_timeout()).unwrap());
+    }
+
+    #[test]
+    fn test_silent_peers_forgotten() {
+        let (_dir, mut source, repo) = setup();
+        let (silent, active) = (DeviceId::new_v4(), DeviceId::new_v4());
+        repo.record_peer_sync(&mut source, silent, now_millis() - 10_000).unwrap();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        repo.delete_file(&mut source, &file).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        repo.record_peer_sync(&mut source, active, now_millis()).unwrap();
+
+        assert!(repo.purge_tombstones(&mut source, Duration::from_millis(0), peer_timeout()).unwrap().is_empty());
+        assert_eq!(2, repo.known_peers(&source).unwrap().len());
+        assert_eq!(vec![file.id], repo.purge_tombstones(&mut source, Duration::from_millis(0), Duration::from_secs(1)).unwrap());
+        assert_eq!(vec![active], repo.known_peers(&source).unwrap().iter().map(|p| p.0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_device_id_reused() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+
+        let opened = open_repository(&source, repo.id, b"secret").unwrap();
+        assert_eq!(repo.device_id, opened.device_id);
+        let updated = opened.update_file(&mut source, &file, b"header", b"v2").unwrap();
+        let reopened = open_repository(&source, repo.id, b"secret").unwrap();
+        let updated = reopened.update_file(&mut source, &updated, b"header", b"v3").unwrap();
+        assert_eq!(1, updated.version_vector.to_stored().len());
+
+        source.delete_file(&sync_state_name(&repo)).unwrap();
+        let other = open_repository(&source, repo.id, b"secret").unwrap();
+        assert_ne!(repo.device_id, other.device_id);
+        other.update_file(&mut source, &updated, b"header", b"v4").unwrap();
+        assert_eq!(other.device_id, open_repository(&source, repo.id, b"secret").unwrap().device_id);
+    }
+}
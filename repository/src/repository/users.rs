@@ -0,0 +1,287 @@
+//! Users of a repository.
+//!
+//! Every user has a key slot with their own salt, hash parameters and a copy of the file key
+//! encrypted with their password. The first slot is stored in the fields of the repository file
+//! that held the only password before, further users are appended as `key_slots`.
+//! Adding and removing users needs the password of the user who does it, so a session alone is not enough.
+//! The first user can only be removed by themselves.
+//! Removing a user only drops the slot, the content files stay untouched. If the removed user
+//! might have kept the file key, `rotate_file_key` has to be run as well.
+
+use super::repository::{Repository, RepositoryId};
+use super::{decrypt_file_pw, encrypt_file_pw, file_pw_aad, get_password, hash_checked_pw, hasher, history, load_repository, rewrite_repository, tombstone};
+use ::error::ErrorKind;
+use ::files::FileSource;
+use ::pb::file::{KeySlot, PasswordHashParameters, PasswordHashType, StoredRepositoryV1};
+use crypt::{DoubleHashedPw, PasswordHasher, Plaintext};
+use failure::Error;
+use std::borrow::Cow;
+
+/// The names of all users that can open the repository, the first one is the user of the first key slot.
+/// An empty name is the unnamed slot of a repository created with `create_repository`.
+pub fn list_users(source: &impl FileSource, id: RepositoryId) -> Result<Vec<String>, Error> {
+    load_repository(source, id, |_, repo| {
+        Ok(key_slots(&repo).into_iter().map(|slot| slot.user_name.into_owned()).collect())
+    })
+}
+
+/// Opens the repository with the key slot of the given user.
+/// An unknown user fails like a wrong password, after the same amount of hashing.
+pub fn open_repository_as(source: &impl FileSource, id: RepositoryId, user_name: &str, pw: &Plaintext) -> Result<Repository, Error> {
+    load_repository(source, id, |_, repo| {
+        let slots = key_slots(&repo);
+        let slot = match slots.iter().find(|slot| slot.user_name == user_name) {
+            Some(slot) => slot,
+            None => {
+                hash_checked_pw(&slots[0], pw)?;
+                return Err(Error::from(ErrorKind::InvalidPassword));
+            }
+        };
+        let file_pw = get_password(&repo, slot, pw)?;
+        Ok(Repository {
+            id: RepositoryId::from_bytes(repo.id.as_ref())?,
+            file_pw,
+            double_hash_pw: DoubleHashedPw::from(slot.double_hashed_pw.as_ref()),
+            encryption_type: repo.enc_type,
+            name: repo.name.to_string(),
+            device_id: tombstone::local_device_id(source, id)?,
+            history: history::stored_retention(&repo),
+        })
+    })
+}
+
+/// Adds a key slot for a new user, the file key is taken from the opened repository.
+/// `caller` has to be an existing user and `caller_pw` their password.
+/// Fails with `KeyRotationPending` while a key rotation is unfinished, the new key could not be stored for the user.
+pub fn add_user(source: &mut impl FileSource, repo: &Repository, caller: &str, caller_pw: &Plaintext, user_name: &str, pw: &Plaintext,
+                hash_type: PasswordHashType, hash_parameters: PasswordHashParameters) -> Result<(), Error> {
+    use crypt::{random_bytes, DeEncrypter, Hasher, SALT_LENGTH};
+
+    rewrite_repository(source, repo.id, |stored| {
+        let mut slots = key_slots(&stored);
+        check_caller(&slots, caller, caller_pw)?;
+        if stored.pending_file_pw.is_some() {
+            return Err(Error::from(ErrorKind::KeyRotationPending));
+        }
+        if slots.iter().any(|slot| slot.user_name == user_name) {
+            return Err(Error::from(ErrorKind::UserExists(user_name.to_string())));
+        }
+
+        let salt = random_bytes(SALT_LENGTH)?;
+        let nonce = random_bytes(stored.enc_type.nonce_length())?;
+        let hasher = PasswordHasher::new(hash_type, hash_parameters);
+        let hashed_pw = hasher.hash_pw(pw, &salt)?;
+        let double_hash_pw = hasher.double_hash_pw(pw, &salt)?;
+        let encrypted_file_pw = encrypt_file_pw(stored.enc_type, &hashed_pw, &nonce, &file_pw_aad(&stored.id, &salt), &repo.file_pw)?;
+
+        slots.push(KeySlot {
+            user_name: Cow::from(user_name.to_string()),
+            hash_type,
+            hash_parameters: hasher.parameters,
+            salt: Cow::from(salt),
+            double_hashed_pw: Cow::from(double_hash_pw.to_vec()),
+            nonce: Cow::from(nonce),
+            encrypted_file_pw: Cow::from(encrypted_file_pw),
+        });
+        Ok(with_key_slots(stored, slots))
+    })
+}
+
+/// Removes the key slot of the user, the last user cannot be removed.
+/// `caller` has to be an existing user and `caller_pw` their password, the first user can only remove themselves.
+/// Sessions that already opened the repository as this user keep the file key.
+pub fn remove_user(source: &mut impl FileSource, repo: &Repository, caller: &str, caller_pw: &Plaintext, user_name: &str) -> Result<(), Error> {
+    rewrite_repository(source, repo.id, |stored| {
+        let mut slots = key_slots(&stored);
+        check_caller(&slots, caller, caller_pw)?;
+        let index = slots.iter().position(|slot| slot.user_name == user_name)
+            .ok_or_else(|| ErrorKind::UnknownUser(user_name.to_string()))?;
+        if index == 0 && caller != user_name {
+            return Err(Error::from(ErrorKind::PrimaryUser(user_name.to_string())));
+        }
+        if slots.len() == 1 {
+            return Err(Error::from(ErrorKind::LastUser(user_name.to_string())));
+        }
+        slots.remove(index);
+        Ok(with_key_slots(stored, slots))
+    })
+}
+
+/// Changes the password of the given user, see `change_password`.
+pub fn change_user_password(source: &mut impl FileSource, id: RepositoryId, user_name: &str, old_pw: &Plaintext, new_pw: &Plaintext) -> Result<(), Error> {
+    use crypt::{random_bytes, DeEncrypter, Hasher, SALT_LENGTH};
+
+    let index = list_users(&*source, id)?.iter().position(|name| name == user_name)
+        .ok_or_else(|| ErrorKind::UnknownUser(user_name.to_string()))?;
+    if index == 0 {
+        return super::change_password(source, id, old_pw, new_pw);
+    }
+    rewrite_repository(source, id, |stored| {
+        let mut slots = key_slots(&stored);
+        let file_pw = {
+            let slot = &slots[index];
+            let old_hashed_pw = hash_checked_pw(slot, old_pw)?;
+            decrypt_file_pw(stored.enc_type, &old_hashed_pw, slot.nonce.as_ref(), &file_pw_aad(&stored.id, &slot.salt), &slot.encrypted_file_pw)?
+        };
+
+        let salt = random_bytes(SALT_LENGTH)?;
+        let nonce = random_bytes(stored.enc_type.nonce_length())?;
+        let hasher = hasher(&slots[index]);
+        let hashed_pw = hasher.hash_pw(new_pw, &salt)?;
+        let double_hash_pw = hasher.double_hash_pw(new_pw, &salt)?;
+        let encrypted_file_pw = encrypt_file_pw(stored.enc_type, &hashed_pw, &nonce, &file_pw_aad(&stored.id, &salt), &file_pw)?;
+
+        let slot = &mut slots[index];
+        slot.salt = Cow::from(salt);
+        slot.nonce = Cow::from(nonce);
+        slot.double_hashed_pw = Cow::from(double_hash_pw.to_vec());
+        slot.encrypted_file_pw = Cow::from(encrypted_file_pw);
+        Ok(with_key_slots(stored, slots))
+    })
+}
+
+/// Checks the password of the caller, an unknown caller fails like a wrong password, after the same amount of hashing.
+fn check_caller(slots: &[KeySlot], caller: &str, caller_pw: &Plaintext) -> Result<(), Error> {
+    match slots.iter().find(|slot| slot.user_name == caller) {
+        Some(slot) => hash_checked_pw(slot, caller_pw).map(|_| ()),
+        None => {
+            hash_checked_pw(&slots[0], caller_pw)?;
+            Err(Error::from(ErrorKind::InvalidPassword))
+        }
+    }
+}
+
+/// The key slot stored in the fields of the repository file itself.
+pub(super) fn primary_slot<'a>(repo: &StoredRepositoryV1<'a>) -> KeySlot<'a> {
+    KeySlot {
+        user_name: repo.user_name.clone().unwrap_or_default(),
+        hash_type: repo.hash_type,
+        hash_parameters: repo.hash_parameters.clone(),
+        salt: repo.salt.clone(),
+        double_hashed_pw: repo.double_hashed_pw.clone(),
+        nonce: repo.nonce.clone(),
+        encrypted_file_pw: repo.encrypted_file_pw.clone(),
+    }
+}
+
+fn key_slots<'a>(repo: &StoredRepositoryV1<'a>) -> Vec<KeySlot<'a>> {
+    let mut slots = vec![primary_slot(repo)];
+    slots.extend(repo.key_slots.iter().cloned());
+    slots
+}
+
+/// Stores the slots into the repository, the first one becomes the primary slot.
+/// A pending file key belongs to the primary slot, so it must not change while a rotation is unfinished.
+fn with_key_slots<'a>(repo: StoredRepositoryV1<'a>, mut slots: Vec<KeySlot<'a>>) -> StoredRepositoryV1<'a> {
+    let primary = slots.remove(0);
+    StoredRepositoryV1 {
+        user_name: if primary.user_name.is_empty() { None } else { Some(primary.user_name) },
+        hash_type: primary.hash_type,
+        hash_parameters: primary.hash_parameters,
+        salt: primary.salt,
+        double_hashed_pw: primary.double_hashed_pw,
+        nonce: primary.nonce,
+        encrypted_file_pw: primary.encrypted_file_pw,
+        key_slots: slots,
+        ..repo
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::{change_password, create_repository, create_repository_as, open_repository, rotate_file_key};
+    use crypt::fast_hash_parameters;
+    use files::DirectoryFileSource;
+    use pb::file::{CompressionType, EncryptionType};
+    use tempdir::TempDir;
+
+    fn create(source: &mut DirectoryFileSource) -> Repository {
+        create_repository_as(source, "repo", "alice", b"alice pw", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap()
+    }
+
+    #[test]
+    fn test_add_user() {
+        let dir = TempDir::new("add_user").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create(&mut source);
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+
+        add_user(&mut source, &repo, "alice", b"alice pw", "bob", b"bob pw", PasswordHashType::SCrypt, fast_hash_parameters()).unwrap();
+        assert_eq!(vec!["alice".to_string(), "bob".to_string()], list_users(&source, repo.id).unwrap());
+        assert!(add_user(&mut source, &repo, "alice", b"alice pw", "bob", b"other", PasswordHashType::Argon2i, fast_hash_parameters()).is_err());
+        let error = add_user(&mut source, &repo, "alice", b"bob pw", "carol", b"carol pw", PasswordHashType::Argon2i, fast_hash_parameters()).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::InvalidPassword)));
+        let error = add_user(&mut source, &repo, "carol", b"carol pw", "carol", b"carol pw", PasswordHashType::Argon2i, fast_hash_parameters()).unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::InvalidPassword)));
+
+        let bob = open_repository_as(&source, repo.id, "bob", b"bob pw").unwrap();
+        assert_eq!(b"content".to_vec(), bob.read_content(&source, &file).unwrap());
+        assert!(open_repository_as(&source, repo.id, "bob", b"alice pw").is_err());
+        assert!(open_repository_as(&source, repo.id, "carol", b"bob pw").is_err());
+        assert!(open_repository_as(&source, repo.id, "alice", b"alice pw").is_ok());
+        assert!(open_repository(&source, repo.id, b"alice pw").is_ok());
+    }
+
+    #[test]
+    fn test_remove_user() {
+        let dir = TempDir::new("remove_user").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create(&mut source);
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let file_data = source.get_file_content(&file.file_name).unwrap();
+        add_user(&mut source, &repo, "alice", b"alice pw", "bob", b"bob pw", PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+
+        let error = remove_user(&mut source, &repo, "bob", b"bob pw", "alice").unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::PrimaryUser(_))));
+        let error = remove_user(&mut source, &repo, "alice", b"bob pw", "alice").unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::InvalidPassword)));
+        let error = remove_user(&mut source, &repo, "carol", b"bob pw", "bob").unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::InvalidPassword)));
+
+        remove_user(&mut source, &repo, "alice", b"alice pw", "alice").unwrap();
+        assert_eq!(vec!["bob".to_string()], list_users(&source, repo.id).unwrap());
+        assert!(open_repository_as(&source, repo.id, "alice", b"alice pw").is_err());
+        assert!(open_repository(&source, repo.id, b"bob pw").is_ok());
+        assert_eq!(file_data, source.get_file_content(&file.file_name).unwrap());
+
+        assert!(remove_user(&mut source, &repo, "bob", b"bob pw", "alice").is_err());
+        let error = remove_user(&mut source, &repo, "bob", b"bob pw", "bob").unwrap_err();
+        assert!(matches!(error.downcast_ref::<ErrorKind>(), Some(ErrorKind::LastUser(_))));
+    }
+
+    #[test]
+    fn test_change_user_password() {
+        let dir = TempDir::new("change_user_password").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create(&mut source);
+        add_user(&mut source, &repo, "alice", b"alice pw", "bob", b"bob pw", PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+
+        assert!(change_user_password(&mut source, repo.id, "bob", b"alice pw", b"new").is_err());
+        change_user_password(&mut source, repo.id, "bob", b"bob pw", b"new").unwrap();
+        assert!(open_repository_as(&source, repo.id, "bob", b"bob pw").is_err());
+        assert!(open_repository_as(&source, repo.id, "bob", b"new").is_ok());
+        assert!(open_repository_as(&source, repo.id, "alice", b"alice pw").is_ok());
+
+        change_password(&mut source, repo.id, b"alice pw", b"alice new").unwrap();
+        assert!(open_repository_as(&source, repo.id, "alice", b"alice new").is_ok());
+        assert!(open_repository_as(&source, repo.id, "bob", b"new").is_ok());
+    }
+
+    #[test]
+    fn test_rotation_needs_single_user() {
+        let dir = TempDir::new("rotation_single_user").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create_repository(&mut source, "repo", b"pw", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        assert_eq!(vec![String::new()], list_users(&source, repo.id).unwrap());
+        add_user(&mut source, &repo, "", b"pw", "bob", b"bob pw", PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+
+        assert!(rotate_file_key(&mut source, repo.id, b"pw").is_err());
+        remove_user(&mut source, &repo, "", b"pw", "bob").unwrap();
+        let rotated = rotate_file_key(&mut source, repo.id, b"pw").unwrap();
+
+        add_user(&mut source, &rotated, "", b"pw", "bob", b"bob pw", PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let bob = open_repository_as(&source, repo.id, "bob", b"bob pw").unwrap();
+        assert_eq!(rotated.file_pw.as_slice(), bob.file_pw.as_slice());
+    }
+}
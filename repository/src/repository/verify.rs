@@ -0,0 +1,249 @@
+//! Integrity check of a whole repository.
+//!
+//! Every stored file is looked at, also the ones the other functions skip because they cannot be parsed
+//! or belong to another repository. Problems are collected into a report instead of failing on the first one.
+//! In repair mode damaged files are moved out of the way with `FileSource::quarantine_file`,
+//! they are neither listed nor synced afterwards but can still be inspected.
+
+use super::file::{FileId, FileVersion, RepositoryFile};
+use super::read_message;
+use super::repository::{Repository, RepositoryId};
+use super::tombstone::sync_state_name;
+use ::files::wrapper::read_wrapper_head;
+use ::files::{FileSource, StoredFileName};
+use ::pb::file::{FileType, StoredFileV1, StoredRepositoryV1, StoredSyncStateV1};
+use failure::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyMode {
+    /// Only reports the problems
+    Report,
+    /// Moves damaged files and duplicates stored under a wrong name into quarantine
+    Repair,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The file is no `StoredFileWrapper` or its content does not match its type
+    Unparseable { file_name: StoredFileName, error: String },
+    /// The file belongs to a repository that is not stored in the source
+    UnknownRepository { file_name: StoredFileName, repository_id: RepositoryId },
+    /// Header or content cannot be decrypted, the file was damaged or manipulated
+    Unauthentic { file_name: StoredFileName, error: String },
+    /// The file is not stored under the name of its id, so it cannot be found by its id
+    Misnamed { file_name: StoredFileName, file_id: FileId },
+    /// Several files have the same id, their names and versions are listed
+    DuplicateId { file_id: FileId, files: Vec<(StoredFileName, FileVersion)> },
+    /// A file that is referenced by a kept version or the sync state does not exist
+    MissingFile { file_id: FileId, referenced_by: StoredFileName },
+}
+
+#[derive(Debug, Default)]
+pub struct VerificationReport {
+    /// The number of stored files that were looked at
+    pub checked: usize,
+    pub problems: Vec<Problem>,
+    /// The files moved into quarantine in repair mode
+    pub quarantined: Vec<StoredFileName>,
+}
+
+impl VerificationReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+enum Checked {
+    Repository(RepositoryId),
+    File(RepositoryFile),
+    Version(RepositoryFile),
+    Foreign(RepositoryId),
+    Damaged(Problem),
+    Other,
+}
+
+/// Checks every file in the source: it has to be parseable, the files of this repository have to decrypt
+/// and every other file has to belong to a repository in the source. Ids have to be unique and match the file names,
+/// kept versions and tombstones must not refer to missing files.
+pub fn verify_repository(source: &mut impl FileSource, repo: &Repository, mode: VerifyMode) -> Result<VerificationReport, Error> {
+    let mut report = VerificationReport::default();
+    let mut to_quarantine = Vec::new();
+    let mut repositories = BTreeSet::new();
+    let mut files: BTreeMap<FileId, Vec<RepositoryFile>> = BTreeMap::new();
+    let mut versions = Vec::new();
+    let mut foreign = Vec::new();
+
+    for name in source.list_names()? {
+        report.checked += 1;
+        match check_file(source, repo, &name)? {
+            Checked::Repository(id) => {
+                repositories.insert(id);
+            }
+            Checked::File(file) => files.entry(file.id).or_default().push(file),
+            Checked::Version(version) => versions.push(version),
+            Checked::Foreign(repository_id) => foreign.push((name, repository_id)),
+            Checked::Damaged(problem) => {
+                report.problems.push(problem);
+                to_quarantine.push(name);
+            }
+            Checked::Other => {}
+        }
+    }
+
+    for (file_name, repository_id) in foreign {
+        if !repositories.contains(&repository_id) {
+            report.problems.push(Problem::UnknownRepository { file_name, repository_id });
+        }
+    }
+    for (file_id, same_id) in &files {
+        let proper_name = file_id.hyphenated().to_string();
+        if same_id.len() > 1 {
+            report.problems.push(Problem::DuplicateId {
+                file_id: *file_id,
+                files: same_id.iter().map(|file| (file.file_name.clone(), file.version)).collect(),
+            });
+            if same_id.iter().any(|file| file.file_name == proper_name) {
+                to_quarantine.extend(same_id.iter().filter(|file| file.file_name != proper_name).map(|file| file.file_name.clone()));
+            }
+        } else if same_id[0].file_name != proper_name {
+            report.problems.push(Problem::Misnamed { file_name: same_id[0].file_name.clone(), file_id: *file_id });
+        }
+    }
+    for version in versions {
+        if !files.contains_key(&version.id) {
+            report.problems.push(Problem::MissingFile { file_id: version.id, referenced_by: version.file_name });
+        }
+    }
+    // a damaged sync state was reported above
+    for file_id in repo.noted_tombstones(source).unwrap_or_default() {
+        if !files.contains_key(&file_id) {
+            report.problems.push(Problem::MissingFile { file_id, referenced_by: sync_state_name(repo) });
+        }
+    }
+
+    if mode == VerifyMode::Repair {
+        for name in to_quarantine {
+            source.quarantine_file(&name)?;
+            report.quarantined.push(name);
+        }
+    }
+    Ok(report)
+}
+
+/// Only a failing file source is an error, everything wrong with the file itself is a problem.
+fn check_file(source: &impl FileSource, repo: &Repository, name: &str) -> Result<Checked, Error> {
+    let unparseable = |error: Error| Checked::Damaged(Problem::Unparseable { file_name: name.into(), error: error.to_string() });
+
+    let mut input = source.open_file(name)?;
+    let (file_type, content) = match read_wrapper_head(&mut input) {
+        Ok(head) => head,
+        Err(e) => return Ok(unparseable(e)),
+    };
+    match file_type {
+        FileType::RepositoryV1 => {
+            let id = read_message::<StoredRepositoryV1>(&content).and_then(|stored| Ok(RepositoryId::from_bytes(stored.id.as_ref())?));
+            Ok(id.map(Checked::Repository).unwrap_or_else(unparseable))
+        }
+        FileType::FileV1 | FileType::FileVersionV1 => {
+            let file = match read_message::<StoredFileV1>(&content).and_then(|stored| RepositoryFile::from_stored(name, &stored)) {
+                Ok(file) => file,
+                Err(e) => return Ok(unparseable(e)),
+            };
+            if file.repository_id != repo.id {
+                return Ok(Checked::Foreign(file.repository_id));
+            }
+            if let Err(e) = repo.read_header(source, &file).and_then(|_| repo.read_content_to(source, &file, &mut io::sink())) {
+                return Ok(Checked::Damaged(Problem::Unauthentic { file_name: name.into(), error: e.to_string() }));
+            }
+            Ok(if file_type == FileType::FileV1 { Checked::File(file) } else { Checked::Version(file) })
+        }
+        FileType::SyncStateV1 => {
+            let repository_id = read_message::<StoredSyncStateV1>(&content).and_then(|stored| Ok(RepositoryId::from_bytes(stored.repository_id.as_ref())?));
+            Ok(match repository_id {
+                Ok(id) if id == repo.id => Checked::Other,
+                Ok(id) => Checked::Foreign(id),
+                Err(e) => unparseable(e),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::crypt::fast_hash_parameters;
+    use ::files::DirectoryFileSource;
+    use ::pb::file::{CompressionType, EncryptionType, PasswordHashType};
+    use ::repository::create_repository;
+    use std::fs;
+    use tempdir::TempDir;
+
+    fn setup() -> (TempDir, DirectoryFileSource, Repository) {
+        let dir = TempDir::new("repository_verify").unwrap();
+        let mut source = DirectoryFileSource::new(dir.path()).unwrap();
+        let repo = create_repository(&mut source, "verify", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        (dir, source, repo)
+    }
+
+    #[test]
+    fn test_clean_repository() {
+        let (_dir, mut source, repo) = setup();
+        let file = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        repo.update_file(&mut source, &file, b"header", b"new content").unwrap();
+        let deleted = repo.create_file(&mut source, b"header", b"content", CompressionType::None).unwrap();
+        repo.delete_file(&mut source, &deleted).unwrap();
+
+        let report = verify_repository(&mut source, &repo, VerifyMode::Repair).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert_eq!(source.list_names().unwrap().len(), report.checked);
+        assert!(report.quarantined.is_empty());
+    }
+
+    #[test]
+    fn test_problems_found_and_quarantined() {
+        let (dir, mut source, repo) = setup();
+        let tampered = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        let path = dir.path().join(&tampered.file_name);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&path, data).unwrap();
+
+        let duplicated = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        fs::copy(dir.path().join(&duplicated.file_name), dir.path().join("copy")).unwrap();
+
+        let removed = repo.create_file(&mut source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        repo.update_file(&mut source, &removed, b"header", b"new content").unwrap();
+        source.delete_file(&removed.file_name).unwrap();
+
+        let other_dir = TempDir::new("repository_verify").unwrap();
+        let mut other_source = DirectoryFileSource::new(other_dir.path()).unwrap();
+        let other = create_repository(&mut other_source, "other", b"secret", EncryptionType::ChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let foreign = other.create_file(&mut other_source, b"header", b"content", CompressionType::DeflateZip).unwrap();
+        fs::copy(other_dir.path().join(&foreign.file_name), dir.path().join(&foreign.file_name)).unwrap();
+
+        source.store_file("garbage", b"no protobuf").unwrap();
+
+        let report = verify_repository(&mut source, &repo, VerifyMode::Report).unwrap();
+        assert_eq!(5, report.problems.len(), "{:?}", report.problems);
+        assert!(report.problems.iter().any(|p| matches!(p, Problem::Unauthentic { file_name, .. } if *file_name == tampered.file_name)));
+        assert!(report.problems.iter().any(|p| matches!(p, Problem::DuplicateId { file_id, files } if *file_id == duplicated.id && files.len() == 2)));
+        assert!(report.problems.iter().any(|p| matches!(p, Problem::MissingFile { file_id, .. } if *file_id == removed.id)));
+        assert!(report.problems.contains(&Problem::UnknownRepository { file_name: foreign.file_name.clone(), repository_id: other.id }));
+        assert!(report.problems.iter().any(|p| matches!(p, Problem::Unparseable { file_name, .. } if file_name == "garbage")));
+        assert!(report.quarantined.is_empty());
+
+        let report = verify_repository(&mut source, &repo, VerifyMode::Repair).unwrap();
+        let mut quarantined = report.quarantined.clone();
+        quarantined.sort();
+        let mut expected = vec![tampered.file_name.clone(), "copy".to_string(), "garbage".to_string()];
+        expected.sort();
+        assert_eq!(expected, quarantined);
+
+        let report = verify_repository(&mut source, &repo, VerifyMode::Report).unwrap();
+        assert_eq!(2, report.problems.len(), "{:?}", report.problems);
+        assert_eq!(1, repo.list_files(&source).unwrap().len());
+    }
+}
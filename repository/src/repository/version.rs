@@ -0,0 +1,160 @@
+//! Version vectors to detect files changed concurrently on different devices.
+//!
+//! Every device counts its own changes of a file, the version of the file is the sum of all counts.
+//! A version that knows all changes of another one is newer, if both know changes the other one misses they are concurrent.
+
+use super::file::FileVersion;
+use ::error::ErrorKind;
+use ::pb::file::{DeviceVersion, StoredFileV1};
+use failure::Error;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+pub type DeviceId = Uuid;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VersionVector {
+    versions: BTreeMap<DeviceId, u32>,
+}
+
+/// How a version relates to another one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VersionOrdering {
+    Equal,
+    Older,
+    Newer,
+    Concurrent,
+}
+
+/// What to do when a file received from a peer was changed concurrently to the local one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictResolution {
+    /// The version modified last is kept on both sides, the other one is dropped
+    LastWriterWins,
+    /// The received version is stored as a new file and the local one replaces it on the peer.
+    /// A deletion never wins over a change, the changed version is kept instead
+    ConflictCopy,
+    /// Both sides stay as they are and the conflict is reported, e.g. to ask the user and sync again with another resolution
+    Defer,
+}
+
+impl VersionVector {
+    /// Files written before version vectors existed count all their changes for the nil device.
+    pub fn from_stored(stored: &StoredFileV1) -> Result<Self, Error> {
+        let mut versions = BTreeMap::new();
+        if stored.version_vector.is_empty() {
+            if stored.version > 0 {
+                versions.insert(DeviceId::nil(), stored.version);
+            }
+            return Ok(VersionVector { versions });
+        }
+        let vector = VersionVector::from_devices(&stored.version_vector)?;
+        if vector.sum() != u64::from(stored.version) {
+            return Err(Error::from(ErrorKind::InvalidStoredFile(format!("version {} does not match its version vector", stored.version))));
+        }
+        Ok(vector)
+    }
+
+    /// The vector of the stored entries, without the fallback for files written before version vectors existed.
+    pub fn from_devices(devices: &[DeviceVersion]) -> Result<Self, Error> {
+        let mut versions = BTreeMap::new();
+        for entry in devices {
+            if versions.insert(DeviceId::from_bytes(entry.device_id.as_ref())?, entry.version).is_some() {
+                return Err(Error::from(ErrorKind::InvalidStoredFile("device listed twice in version vector".into())));
+            }
+        }
+        Ok(VersionVector { versions })
+    }
+
+    pub fn to_stored(&self) -> Vec<DeviceVersion<'static>> {
+        self.versions.iter()
+            .filter(|&(_, version)| *version > 0)
+            .map(|(device, version)| DeviceVersion { device_id: Cow::from(device.as_bytes().to_vec()), version: *version })
+            .collect()
+    }
+
+    pub fn version(&self) -> Result<FileVersion, Error> {
+        let sum = self.sum();
+        if sum > u64::from(FileVersion::MAX) {
+            return Err(Error::from(ErrorKind::InvalidStoredFile("version vector overflows".into())));
+        }
+        Ok(sum as FileVersion)
+    }
+
+    /// The version after one more change on the given device.
+    pub fn increment(&self, device: DeviceId) -> Self {
+        let mut versions = self.versions.clone();
+        *versions.entry(device).or_insert(0) += 1;
+        VersionVector { versions }
+    }
+
+    /// The version that knows all changes of both.
+    pub fn merge(&self, other: &VersionVector) -> Self {
+        let mut versions = self.versions.clone();
+        for (device, version) in &other.versions {
+            let entry = versions.entry(*device).or_insert(0);
+            *entry = (*entry).max(*version);
+        }
+        VersionVector { versions }
+    }
+
+    /// How this version relates to the other one.
+    pub fn compare(&self, other: &VersionVector) -> VersionOrdering {
+        let knows_more = self.versions.iter().any(|(device, version)| *version > other.get(device));
+        let knows_less = other.versions.iter().any(|(device, version)| *version > self.get(device));
+        match (knows_more, knows_less) {
+            (false, false) => VersionOrdering::Equal,
+            (true, false) => VersionOrdering::Newer,
+            (false, true) => VersionOrdering::Older,
+            (true, true) => VersionOrdering::Concurrent,
+        }
+    }
+
+    fn get(&self, device: &DeviceId) -> u32 {
+        self.versions.get(device).cloned().unwrap_or(0)
+    }
+
+    fn sum(&self) -> u64 {
+        self.versions.values().map(|version| u64::from(*version)).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let (a, b) = (DeviceId::new_v4(), DeviceId::new_v4());
+        let base = VersionVector::default().increment(a);
+        let changed_a = base.increment(a);
+        let changed_b = base.increment(b);
+
+        assert_eq!(VersionOrdering::Equal, base.compare(&base.clone()));
+        assert_eq!(VersionOrdering::Newer, changed_a.compare(&base));
+        assert_eq!(VersionOrdering::Older, base.compare(&changed_b));
+        assert_eq!(VersionOrdering::Concurrent, changed_a.compare(&changed_b));
+
+        let merged = changed_a.merge(&changed_b);
+        assert_eq!(VersionOrdering::Newer, merged.compare(&changed_a));
+        assert_eq!(VersionOrdering::Newer, merged.compare(&changed_b));
+        assert_eq!(3, merged.version().unwrap());
+    }
+
+    #[test]
+    fn test_stored_vector() {
+        let device = DeviceId::new_v4();
+        let vector = VersionVector::default().increment(device).increment(device);
+        let stored = StoredFileV1 { version: 2, version_vector: vector.to_stored(), ..StoredFileV1::default() };
+        assert_eq!(vector, VersionVector::from_stored(&stored).unwrap());
+
+        let mismatch = StoredFileV1 { version: 3, ..stored.clone() };
+        assert!(VersionVector::from_stored(&mismatch).is_err());
+
+        let legacy = StoredFileV1 { version: 4, ..StoredFileV1::default() };
+        let legacy = VersionVector::from_stored(&legacy).unwrap();
+        assert_eq!(4, legacy.version().unwrap());
+        assert_eq!(VersionOrdering::Newer, legacy.increment(device).compare(&legacy));
+    }
+}
@@ -0,0 +1,221 @@
+//! Conversions between the sync structs and the `pb::sync` messages sent to a peer.
+//! Everything received is validated, the divisions are used to index into file ids.
+
+use super::{FileBucket, FileId, HashBucket, SingleFileSync, Subdivision, MAX_DIVISION};
+use error::ErrorKind;
+use failure::Error;
+use pb::sync;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+
+impl From<Subdivision> for sync::Subdivision {
+    fn from(division: Subdivision) -> Self {
+        sync::Subdivision {
+            division: division.division,
+            modulo: u32::from(division.modulo),
+            remainder: division.remainder,
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a sync::Subdivision> for Subdivision {
+    type Error = Error;
+
+    fn try_from(division: &'a sync::Subdivision) -> Result<Self, Error> {
+        let valid = division.division >= 1 && division.division <= MAX_DIVISION
+            && division.modulo >= 1 && division.modulo <= u32::from(u8::MAX)
+            && division.remainder < division.modulo;
+        if !valid {
+            return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("invalid subdivision {:?}", division))));
+        }
+        Ok(Subdivision {
+            division: division.division,
+            modulo: division.modulo as u8,
+            remainder: division.remainder,
+        })
+    }
+}
+
+/// The divisions of a bucket have to start with the first one and follow each other.
+fn divisions_try_from(divisions: &[sync::Subdivision]) -> Result<Vec<Subdivision>, Error> {
+    let mut result = Vec::with_capacity(divisions.len());
+    for (i, division) in divisions.iter().enumerate() {
+        let division = Subdivision::try_from(division)?;
+        if division.division != i as u32 + 1 {
+            return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("division {} at position {}", division.division, i))));
+        }
+        result.push(division);
+    }
+    Ok(result)
+}
+
+impl From<SingleFileSync> for sync::SingleFileSync<'static> {
+    fn from(file: SingleFileSync) -> Self {
+        sync::SingleFileSync {
+            id: Cow::from(file.id.as_bytes().to_vec()),
+            version: file.version,
+            hash: Cow::from(file.hash),
+        }
+    }
+}
+
+impl<'a, 'b> TryFrom<&'b sync::SingleFileSync<'a>> for SingleFileSync {
+    type Error = Error;
+
+    fn try_from(file: &'b sync::SingleFileSync<'a>) -> Result<Self, Error> {
+        Ok(SingleFileSync {
+            id: FileId::from_bytes(file.id.as_ref())?,
+            version: file.version,
+            hash: file.hash.to_vec(),
+        })
+    }
+}
+
+impl<'a> From<HashBucket<'a>> for sync::HashBucket<'static> {
+    fn from(bucket: HashBucket<'a>) -> Self {
+        sync::HashBucket {
+            hash: Cow::from(bucket.hash),
+            divisions: bucket.divisions.into_iter().map(sync::Subdivision::from).collect(),
+        }
+    }
+}
+
+/// A bucket received from the peer only carries its hash, the files stay on the other side.
+impl<'a, 'b> TryFrom<&'b sync::HashBucket<'a>> for HashBucket<'static> {
+    type Error = Error;
+
+    fn try_from(bucket: &'b sync::HashBucket<'a>) -> Result<Self, Error> {
+        Ok(HashBucket {
+            hash: bucket.hash.to_vec(),
+            divisions: divisions_try_from(&bucket.divisions)?,
+            files: Vec::new(),
+        })
+    }
+}
+
+impl From<FileBucket> for sync::SynchronizationBucket<'static> {
+    fn from(bucket: FileBucket) -> Self {
+        sync::SynchronizationBucket {
+            file_syncs: bucket.files.into_iter().map(sync::SingleFileSync::from).collect(),
+            divisions: bucket.divisions.into_iter().map(sync::Subdivision::from).collect(),
+        }
+    }
+}
+
+impl<'a, 'b> TryFrom<&'b sync::SynchronizationBucket<'a>> for FileBucket {
+    type Error = Error;
+
+    fn try_from(bucket: &'b sync::SynchronizationBucket<'a>) -> Result<Self, Error> {
+        let divisions = divisions_try_from(&bucket.divisions)?;
+        let mut files = Vec::with_capacity(bucket.file_syncs.len());
+        for file in bucket.file_syncs.iter() {
+            let file = SingleFileSync::try_from(file)?;
+            if !divisions.iter().all(|d| d.contains(&file.id)) {
+                return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("file {} is not part of its bucket", file.id))));
+            }
+            files.push(file);
+        }
+        Ok(FileBucket { divisions, files })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use quick_protobuf::{BytesReader, MessageRead, MessageWrite, Writer};
+    use sync::create_buckets;
+    use uuid::Uuid;
+
+    fn serialized<M: MessageWrite>(message: &M) -> Vec<u8> {
+        let mut out = Vec::new();
+        message.write_message(&mut Writer::new(&mut out)).unwrap();
+        out
+    }
+
+    fn file(version: u32) -> SingleFileSync {
+        let id = Uuid::new_v4();
+        SingleFileSync { id, version, hash: id.as_bytes()[..8].to_vec() }
+    }
+
+    #[test]
+    fn test_single_file_roundtrip() {
+        let original = file(7);
+        let bytes = serialized(&sync::SingleFileSync::from(original.clone()));
+
+        let message = sync::SingleFileSync::from_reader(&mut BytesReader::from_bytes(&bytes), &bytes).unwrap();
+        assert_eq!(original, SingleFileSync::try_from(&message).unwrap());
+    }
+
+    #[test]
+    fn test_hash_bucket_roundtrip() {
+        let files: Vec<SingleFileSync> = (0..50).map(file).collect();
+        let references: Vec<&SingleFileSync> = files.iter().collect();
+        let first = create_buckets(&references, 4, Vec::new()).remove(0);
+        let bucket = create_buckets(&first.files, 16, first.divisions.clone()).remove(0);
+        let (hash, divisions) = (bucket.hash.clone(), bucket.divisions.clone());
+        assert_eq!(2, divisions.len());
+
+        let bytes = serialized(&sync::HashBucket::from(bucket));
+        let message = sync::HashBucket::from_reader(&mut BytesReader::from_bytes(&bytes), &bytes).unwrap();
+        assert_eq!(HashBucket { hash, divisions, files: Vec::new() }, HashBucket::try_from(&message).unwrap());
+    }
+
+    #[test]
+    fn test_file_bucket_roundtrip() {
+        let files: Vec<SingleFileSync> = (0..50).map(file).collect();
+        let references: Vec<&SingleFileSync> = files.iter().collect();
+        let bucket = create_buckets(&references, 3, Vec::new()).remove(0);
+        let original = FileBucket { divisions: bucket.divisions, files: bucket.files.into_iter().cloned().collect() };
+
+        let bytes = serialized(&sync::SynchronizationBucket::from(original.clone()));
+        let message = sync::SynchronizationBucket::from_reader(&mut BytesReader::from_bytes(&bytes), &bytes).unwrap();
+        assert_eq!(original, FileBucket::try_from(&message).unwrap());
+    }
+
+    #[test]
+    fn test_synchronization_roundtrip() {
+        let files: Vec<SingleFileSync> = (0..20).map(file).collect();
+        let references: Vec<&SingleFileSync> = files.iter().collect();
+        let mut buckets = create_buckets(&references, 2, Vec::new());
+        let file_bucket = FileBucket { divisions: buckets[0].divisions.clone(), files: buckets[0].files.iter().map(|f| (*f).clone()).collect() };
+        let hash_bucket = buckets.remove(1);
+        let expected_hash = HashBucket { hash: hash_bucket.hash.clone(), divisions: hash_bucket.divisions.clone(), files: Vec::new() };
+
+        let original = sync::Synchronization {
+            buckets: vec![hash_bucket.into()],
+            file_buckets: vec![file_bucket.clone().into()],
+        };
+        let bytes = serialized(&original);
+        let message = sync::Synchronization::from_reader(&mut BytesReader::from_bytes(&bytes), &bytes).unwrap();
+        assert_eq!(original, message);
+        assert_eq!(expected_hash, HashBucket::try_from(&message.buckets[0]).unwrap());
+        assert_eq!(file_bucket, FileBucket::try_from(&message.file_buckets[0]).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_messages_rejected() {
+        let invalid = [
+            sync::Subdivision { division: 0, modulo: 2, remainder: 0 },
+            sync::Subdivision { division: MAX_DIVISION + 1, modulo: 2, remainder: 0 },
+            sync::Subdivision { division: 1, modulo: 0, remainder: 0 },
+            sync::Subdivision { division: 1, modulo: 256, remainder: 0 },
+            sync::Subdivision { division: 1, modulo: 2, remainder: 2 },
+        ];
+        for division in invalid.iter() {
+            assert!(Subdivision::try_from(division).is_err());
+        }
+
+        let skipped = sync::HashBucket { hash: Cow::from(vec![1]), divisions: vec![sync::Subdivision { division: 2, modulo: 2, remainder: 0 }] };
+        assert!(HashBucket::try_from(&skipped).is_err());
+
+        let short_id = sync::SingleFileSync { id: Cow::from(vec![1, 2, 3]), version: 1, hash: Cow::from(vec![]) };
+        assert!(SingleFileSync::try_from(&short_id).is_err());
+
+        let mut misplaced = file(1);
+        let mut id = *misplaced.id.as_bytes();
+        id[14] = 1;
+        misplaced.id = Uuid::from_bytes(&id).unwrap();
+        let bucket = FileBucket { divisions: vec![Subdivision { division: 1, modulo: 2, remainder: 0 }], files: vec![misplaced] };
+        assert!(FileBucket::try_from(&sync::SynchronizationBucket::from(bucket)).is_err());
+    }
+}
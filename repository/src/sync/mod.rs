@@ -1,26 +1,40 @@
 use ::pb::sync;
+use error::ErrorKind;
 use failure::Error;
-use repository::file::RepositoryFile;
 use sha1::Sha1;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use uuid::Uuid;
 
+mod convert;
+mod peer;
+
+pub use self::peer::{serve_peer, sync_with_peer};
+
 pub type FileId = Uuid;
 pub type Hash = Vec<u8>;
 pub type FileVersion = u32;
 
+/// Buckets with at most this many files are compared file by file instead of being subdivided further.
+pub const MAX_BUCKET_SIZE: usize = 32;
+/// Number of sub buckets a bucket gets divided into.
+const FAN_OUT: u8 = 16;
+/// Every division uses another byte of the file id, the first one is never used.
+const MAX_DIVISION: u32 = 15;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct HashBucket<'a> {
     hash: Hash,
     divisions: Vec<Subdivision>,
-    files: Vec<&'a RepositoryFile>,
+    files: Vec<&'a SingleFileSync>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SingleFileSync {
-    id: FileId,
-    version: FileVersion,
-    hash: Hash,
+    pub id: FileId,
+    pub version: FileVersion,
+    /// Hash of the stored file, two files with the same id and version but another hash were written independently.
+    pub hash: Hash,
 }
 
 #[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
@@ -30,10 +44,36 @@ pub struct Subdivision {
     remainder: u32,
 }
 
+/// All files of a bucket, sent once a bucket is small enough to compare it file by file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileBucket {
+    divisions: Vec<Subdivision>,
+    files: Vec<SingleFileSync>,
+}
+
+impl Subdivision {
+    fn contains(&self, id: &FileId) -> bool {
+        let byte = id.as_bytes()[15 - self.division as usize];
+        u32::from(byte % self.modulo) == self.remainder
+    }
+}
+
+/// Files that differ between the local repository and the peer.
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncDiff {
+    /// Files the peer does not have or only has in an older version
+    pub push: Vec<FileId>,
+    /// Files the peer has in a newer version or the local side does not have at all
+    pub pull: Vec<FileId>,
+    /// Files with the same version on both sides but a different content
+    pub conflicts: Vec<FileId>,
+}
+
 impl<'a> HashBucket<'a> {
     fn split(self, max_bucket_size: usize) -> Vec<HashBucket<'a>> {
         if self.files.len() > max_bucket_size {
-            let mut split = create_buckets(self.files.as_slice(), max_bucket_size, self.divisions);
+            let modulo = (self.files.len() / max_bucket_size + 1).min(FAN_OUT as usize);
+            let mut split = create_buckets(self.files.as_slice(), modulo as u8, self.divisions);
 
             loop {
                 let option = split.iter_mut().position(|i| i.files.len() > max_bucket_size);
@@ -52,59 +92,149 @@ impl<'a> HashBucket<'a> {
     }
 }
 
-fn create_buckets<'a, 'b>(files: &'b [&'a RepositoryFile], max_bucket_size: usize, divisions: Vec<Subdivision>) -> Vec<HashBucket<'a>> {
-    use std::collections::HashMap;
-    use sha1::{Sha1, Digest};
+/// Creates one bucket per remainder of the next division, empty buckets included.
+fn create_buckets<'a, 'b>(files: &'b [&'a SingleFileSync], modulo: u8, divisions: Vec<Subdivision>) -> Vec<HashBucket<'a>> {
+    let division = divisions.last().map(|d| d.division + 1).unwrap_or(1);
 
-    let mut buckets = Vec::new();
-    let mut map: HashMap<u8, HashBucket<'a>> = HashMap::new();
-    let modulo = files.len() / max_bucket_size + 1;
+    let mut map: HashMap<u32, Vec<&'a SingleFileSync>> = (0..u32::from(modulo)).map(|remainder| (remainder, Vec::new())).collect();
+    for file in files.iter() {
+        let byte = file.id.as_bytes()[15 - division as usize];
+        let remainder = u32::from(byte % modulo);
+        map.get_mut(&remainder).expect("all remainders present").push(file);
+    }
 
-    let current_division = divisions.last().map(|d| Subdivision { division: d.division + 1, remainder: 0, modulo: modulo as u8 }).unwrap_or(Subdivision { division: 1, remainder: 0, modulo: modulo as u8 });
+    map.into_iter().map(|(remainder, files)| {
+        let mut divs = divisions.clone();
+        divs.push(Subdivision { division, modulo, remainder });
+        HashBucket {
+            hash: bucket_hash(&files),
+            divisions: divs,
+            files,
+        }
+    }).collect()
+}
 
+/// Hashes id, version and hash of all files, independent of their order.
+fn bucket_hash(files: &[&SingleFileSync]) -> Hash {
+    use sha1::Digest;
 
-    for file in files.iter() {
-        let byte = file.id.as_bytes()[15 - current_division.division as usize];
-        let map_index = byte % current_division.modulo;
-
-        map.entry(map_index).or_insert_with(|| {
-            let mut divs = divisions.clone();
-            let mut div = current_division.clone();
-            div.remainder = map_index as u32;
-            divs.push(div);
-            HashBucket {
-                divisions: divs,
-                files: Vec::new(),
-                hash: Vec::with_capacity(0),
+    let mut sorted = files.to_vec();
+    sorted.sort_by_key(|f| f.id);
+
+    let mut sha1 = Sha1::default();
+    sorted.iter().for_each(|f| {
+        sha1.input(f.id.as_bytes());
+        let version: [u8; 4] = [f.version as u8, (f.version >> 8) as u8, (f.version >> 16) as u8, (f.version >> 24) as u8];
+        sha1.input(&version);
+        sha1.input(&f.hash);
+    });
+    sha1.result().to_vec()
+}
+
+fn files_in<'a>(files: &'a [SingleFileSync], divisions: &[Subdivision]) -> Vec<&'a SingleFileSync> {
+    files.iter().filter(|f| divisions.iter().all(|d| d.contains(&f.id))).collect()
+}
+
+/// Compares the local files with the peer by exchanging `Synchronization` messages.
+///
+/// The first request contains the hashes of the top level buckets.
+/// The peer answers every bucket whose hash differs from its own, small buckets with their files and
+/// large ones with its hash, which get divided and sent again.
+/// Only buckets containing differences are looked at, so the amount of data exchanged grows with the number of changes
+/// instead of the number of files.
+pub struct Reconciliation<'a> {
+    files: &'a [SingleFileSync],
+    diff: SyncDiff,
+}
+
+impl<'a> Reconciliation<'a> {
+    pub fn new(files: &'a [SingleFileSync]) -> Self {
+        Reconciliation { files, diff: SyncDiff::default() }
+    }
+
+    pub fn start(&self) -> sync::Synchronization<'static> {
+        let files: Vec<&SingleFileSync> = self.files.iter().collect();
+        let buckets = create_buckets(&files, FAN_OUT, Vec::new());
+        sync::Synchronization {
+            buckets: buckets.into_iter().map(sync::HashBucket::from).collect(),
+            file_buckets: Vec::new(),
+        }
+    }
+
+    /// Processes the answer of the peer and returns the next request, `None` once all differences are known.
+    pub fn process_answer(&mut self, answer: &sync::Synchronization) -> Result<Option<sync::Synchronization<'static>>, Error> {
+        for file_bucket in answer.file_buckets.iter() {
+            let remote = FileBucket::try_from(file_bucket)?;
+            self.compare_files(files_in(self.files, &remote.divisions), &remote.files);
+        }
+
+        let mut buckets = Vec::new();
+        for bucket in answer.buckets.iter() {
+            let remote = HashBucket::try_from(bucket)?;
+            if remote.divisions.len() as u32 >= MAX_DIVISION {
+                return Err(Error::from(ErrorKind::InvalidSyncMessage("bucket can not be divided further".into())));
             }
-        }).files.push(file);
+            let local = files_in(self.files, &remote.divisions);
+            buckets.extend(create_buckets(&local, FAN_OUT, remote.divisions).into_iter().map(sync::HashBucket::from));
+        }
+
+        if buckets.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(sync::Synchronization { buckets, file_buckets: Vec::new() }))
+        }
     }
 
-    for (key, mut value) in map.into_iter() {
-        let mut sha1 = Sha1::default();
-        value.files.iter().for_each(|f| {
-            sha1.input(f.id.as_bytes());
+    pub fn finish(self) -> SyncDiff {
+        self.diff
+    }
 
-            let bytes: [u8; 4] = unsafe { ::std::mem::transmute(f.version.to_le()) };
-            sha1.input(&bytes);
-        });
-        let hash = sha1.result().to_vec();
-        value.hash = hash;
-        buckets.push(value);
+    fn compare_files(&mut self, local: Vec<&SingleFileSync>, remote: &[SingleFileSync]) {
+        let mut local: HashMap<FileId, &SingleFileSync> = local.into_iter().map(|f| (f.id, f)).collect();
+        for remote_file in remote.iter() {
+            match local.remove(&remote_file.id) {
+                None => self.diff.pull.push(remote_file.id),
+                Some(local_file) if local_file.version > remote_file.version => self.diff.push.push(local_file.id),
+                Some(local_file) if local_file.version < remote_file.version => self.diff.pull.push(local_file.id),
+                Some(local_file) if local_file.hash != remote_file.hash => self.diff.conflicts.push(local_file.id),
+                Some(_) => {}
+            }
+        }
+        self.diff.push.extend(local.keys());
     }
+}
 
-    buckets
+/// Answers a request of a `Reconciliation` running on the peer.
+pub fn answer_synchronization(files: &[SingleFileSync], request: &sync::Synchronization) -> Result<sync::Synchronization<'static>, Error> {
+    let mut answer = sync::Synchronization::default();
+    for bucket in request.buckets.iter() {
+        let remote = HashBucket::try_from(bucket)?;
+        let local = files_in(files, &remote.divisions);
+        let hash = bucket_hash(&local);
+        if hash == remote.hash {
+            continue;
+        }
+        if local.len() <= MAX_BUCKET_SIZE || remote.divisions.len() as u32 >= MAX_DIVISION {
+            let file_bucket = FileBucket { divisions: remote.divisions, files: local.into_iter().cloned().collect() };
+            answer.file_buckets.push(file_bucket.into());
+        } else {
+            answer.buckets.push(HashBucket { hash, divisions: remote.divisions, files: local }.into());
+        }
+    }
+    Ok(answer)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use quick_protobuf::MessageWrite;
+    use std::borrow::Cow;
     use uuid::Uuid;
 
     #[test]
     fn test_create_buckets() {
-        let files: Vec<RepositoryFile> = (0..2000_u16).map(|n| create_random_file()).collect();
-        let reference: Vec<&RepositoryFile> = files.iter().collect();
+        let files: Vec<SingleFileSync> = (0..2000_u16).map(|_| create_random_file()).collect();
+        let reference: Vec<&SingleFileSync> = files.iter().collect();
 
         let buckets = HashBucket {
             hash: Vec::with_capacity(0),
@@ -115,20 +245,115 @@ mod test {
         buckets.iter().for_each(|b| {
             println!("divisions: {:?}, File len {}", b.divisions, b.files.len());
             assert!(b.hash.len() > 0);
+            assert!(b.files.len() <= 100);
+            assert!(b.files.iter().all(|f| b.divisions.iter().all(|d| d.contains(&f.id))));
         });
         assert!(buckets.len() > 1);
+        assert_eq!(2000, buckets.iter().map(|b| b.files.len()).sum::<usize>());
+    }
+
+    #[test]
+    fn test_bucket_hash_independent_of_order() {
+        let files: Vec<SingleFileSync> = (0..10).map(|_| create_random_file()).collect();
+        let forward: Vec<&SingleFileSync> = files.iter().collect();
+        let backward: Vec<&SingleFileSync> = files.iter().rev().collect();
+        assert_eq!(bucket_hash(&forward), bucket_hash(&backward));
     }
 
-    fn create_random_file() -> RepositoryFile {
-        use pb::file::{CompressionType, EncryptionType};
+    /// Runs the reconciliation between both sides and returns the diff and the number of bytes exchanged.
+    fn reconcile(local: &[SingleFileSync], remote: &[SingleFileSync]) -> (SyncDiff, usize) {
+        let mut reconciliation = Reconciliation::new(local);
+        let mut request = reconciliation.start();
+        let mut bytes = 0;
+        loop {
+            let answer = answer_synchronization(remote, &request).unwrap();
+            bytes += request.get_size() + answer.get_size();
+            match reconciliation.process_answer(&answer).unwrap() {
+                Some(next) => request = next,
+                None => break,
+            }
+        }
+        let mut diff = reconciliation.finish();
+        diff.push.sort();
+        diff.pull.sort();
+        diff.conflicts.sort();
+        (diff, bytes)
+    }
 
-        RepositoryFile {
-            id: Uuid::new_v4(),
-            compression_type: CompressionType::DeflateZip,
-            encryption_type: EncryptionType::ChachaPoly1305,
-            file_name: "bla".into(),
-            repository_id: Uuid::new_v4(),
+    #[test]
+    fn test_identical_repositories() {
+        let files: Vec<SingleFileSync> = (0..1000).map(|_| create_random_file()).collect();
+        let (diff, bytes) = reconcile(&files, &files);
+        assert_eq!(SyncDiff::default(), diff);
+        assert!(bytes < 1024);
+    }
+
+    #[test]
+    fn test_detects_all_differences() {
+        let local: Vec<SingleFileSync> = (0..500).map(|_| create_random_file()).collect();
+        let mut remote = local.clone();
+
+        let only_local = create_random_file();
+        let only_remote = create_random_file();
+        remote[10].version += 1;
+        remote[20].version -= 1;
+        remote[30].hash = vec![9; 20];
+        let mut local = local;
+        local.push(only_local.clone());
+        remote.push(only_remote.clone());
+
+        let (diff, _) = reconcile(&local, &remote);
+        let mut push = vec![only_local.id, local[20].id];
+        let mut pull = vec![only_remote.id, local[10].id];
+        push.sort();
+        pull.sort();
+        assert_eq!(SyncDiff { push, pull, conflicts: vec![local[30].id] }, diff);
+    }
+
+    #[test]
+    fn test_empty_side() {
+        let files: Vec<SingleFileSync> = (0..300).map(|_| create_random_file()).collect();
+        let mut ids: Vec<FileId> = files.iter().map(|f| f.id).collect();
+        ids.sort();
+
+        let (diff, _) = reconcile(&[], &files);
+        assert_eq!(ids, diff.pull);
+        let (diff, _) = reconcile(&files, &[]);
+        assert_eq!(ids, diff.push);
+    }
+
+    #[test]
+    fn test_few_changes_in_many_files() {
+        let local: Vec<SingleFileSync> = (0..100_000).map(|_| create_random_file()).collect();
+        let mut remote = local.clone();
+        for i in [7, 4_000, 33_333, 70_001, 99_999].iter() {
+            remote[*i].version += 1;
+        }
+
+        let (diff, bytes) = reconcile(&local, &remote);
+        assert_eq!(5, diff.pull.len());
+        assert!(diff.push.is_empty() && diff.conflicts.is_empty());
+        assert!(bytes < 16 * 1024);
+    }
+
+    #[test]
+    fn test_invalid_subdivision_rejected() {
+        let request = sync::Synchronization {
+            buckets: vec![sync::HashBucket {
+                hash: Cow::from(vec![0; 20]),
+                divisions: vec![sync::Subdivision { division: 16, modulo: 2, remainder: 0 }],
+            }],
+            file_buckets: Vec::new(),
+        };
+        assert!(answer_synchronization(&[], &request).is_err());
+    }
+
+    fn create_random_file() -> SingleFileSync {
+        let id = Uuid::new_v4();
+        SingleFileSync {
+            id,
             version: 1,
+            hash: id.as_bytes().to_vec(),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,639 @@
+//! Synchronization of a repository with a peer over a byte stream, e.g. a `TcpStream`.
+//!
+//! Every message is sent as a frame: the length of the rest as u32 big endian, the kind of the message and the serialized message.
+//! Both sides first prove that they know the file key of the repository without sending it.
+//! The initiator proves it first, so the responder never hands out a proof to an unauthenticated peer.
+//! Every later frame carries a sequence number after the kind and ends with an HMAC-SHA256 tag over kind, sequence number and message.
+//! The tag keys are derived from the file key and the handshake, one per direction,
+//! so a frame that was changed, dropped, replayed or reordered fails the sync.
+//! Then the initiator reconciles its files with the peer and exchanges the files that differ.
+//! Files are sent as they are stored, they stay encrypted with the file key the whole time.
+//! Each side resolves files changed concurrently on both sides with its own `ConflictResolution`.
+//! A complete sync is recorded on both sides, so tombstones can be purged once every known peer has seen them.
+//! Timeouts are up to the caller, e.g. by `TcpStream::set_read_timeout`.
+
+use super::{answer_synchronization, FileId, Reconciliation, SyncDiff};
+use crypt::{random_bytes, HashedPw};
+use error::ErrorKind;
+use failure::Error;
+use files::FileSource;
+use hmac::{Hmac, Mac};
+use pb::sync::{FileChunk, FileRequest, SyncHandshake, Synchronization};
+use repository::file::{now_millis, ImportResult};
+use repository::version::{ConflictResolution, DeviceId};
+use repository::{read_message, write_message, Repository};
+use sha2::Sha256;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+
+const CHALLENGE_LENGTH: usize = 32;
+/// Upper bound for received frames, the answer listing all files of a large repository is the biggest one.
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+/// Upper bound for handshake frames, they are read before the peer is authenticated.
+const MAX_HANDSHAKE_LENGTH: usize = 256;
+const FILE_CHUNK_SIZE: usize = 1024 * 1024;
+
+const SEQUENCE_LENGTH: usize = 8;
+const TAG_LENGTH: usize = 32;
+
+const SYNC_KEY_INFO: &[u8] = b"idnadrev sync handshake";
+const FRAME_KEY_INFO: &[u8] = b"idnadrev sync frames";
+const INITIATOR: &[u8] = b"initiator";
+const RESPONDER: &[u8] = b"responder";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameKind {
+    Handshake = 1,
+    Synchronization = 2,
+    FileChunk = 3,
+    FileRequest = 4,
+    Done = 5,
+}
+
+impl FrameKind {
+    fn from_byte(byte: u8) -> Result<Self, Error> {
+        match byte {
+            1 => Ok(FrameKind::Handshake),
+            2 => Ok(FrameKind::Synchronization),
+            3 => Ok(FrameKind::FileChunk),
+            4 => Ok(FrameKind::FileRequest),
+            5 => Ok(FrameKind::Done),
+            _ => Err(Error::from(ErrorKind::InvalidSyncMessage(format!("unknown frame kind {}", byte)))),
+        }
+    }
+}
+
+/// The keys and sequence numbers of the frames sent after the handshake.
+struct FrameKeys {
+    send: HashedPw,
+    receive: HashedPw,
+    sent: u64,
+    received: u64,
+}
+
+struct Connection<S: Read + Write> {
+    stream: S,
+    /// Set once the handshake is done, frames are not authenticated before
+    keys: Option<FrameKeys>,
+}
+
+impl<S: Read + Write> Connection<S> {
+    fn new(stream: S) -> Self {
+        Connection { stream, keys: None }
+    }
+
+    /// Authenticates all further frames with the given keys.
+    fn authenticate(&mut self, send: HashedPw, receive: HashedPw) {
+        self.keys = Some(FrameKeys { send, receive, sent: 0, received: 0 });
+    }
+
+    fn send(&mut self, kind: FrameKind, payload: &[u8]) -> Result<(), Error> {
+        let mut body = vec![kind as u8];
+        if let Some(ref mut keys) = self.keys {
+            body.extend_from_slice(&sequence_bytes(keys.sent));
+            body.extend_from_slice(payload);
+            let tag = frame_mac(&keys.send, &body).result().code();
+            body.extend_from_slice(&tag);
+            keys.sent += 1;
+        } else {
+            body.extend_from_slice(payload);
+        }
+        let length = body.len();
+        if length > MAX_FRAME_LENGTH {
+            return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("frame of {} bytes too large", length))));
+        }
+        let length = length as u32;
+        let head = [(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8];
+        self.stream.write_all(&head)?;
+        self.stream.write_all(&body)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<(FrameKind, Vec<u8>), Error> {
+        self.receive_limited(MAX_FRAME_LENGTH)
+    }
+
+    fn receive_limited(&mut self, max_length: usize) -> Result<(FrameKind, Vec<u8>), Error> {
+        let mut head = [0u8; 4];
+        self.stream.read_exact(&mut head)?;
+        let length = head.iter().fold(0usize, |length, byte| length << 8 | *byte as usize);
+        let overhead = if self.keys.is_some() { 1 + SEQUENCE_LENGTH + TAG_LENGTH } else { 1 };
+        if length < overhead || length > max_length {
+            return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("invalid frame length {}", length))));
+        }
+        let mut body = vec![0u8; length];
+        self.stream.read_exact(&mut body)?;
+        if let Some(ref mut keys) = self.keys {
+            let tag = body.split_off(length - TAG_LENGTH);
+            frame_mac(&keys.receive, &body).verify(&tag).map_err(|_| Error::from(ErrorKind::SyncAuthenticationFailed))?;
+            if body[1..1 + SEQUENCE_LENGTH] != sequence_bytes(keys.received) {
+                return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("expected frame {}", keys.received))));
+            }
+            keys.received += 1;
+            body.drain(1..1 + SEQUENCE_LENGTH);
+        }
+        let kind = FrameKind::from_byte(body[0])?;
+        Ok((kind, body.split_off(1)))
+    }
+
+    fn expect(&mut self, expected: FrameKind) -> Result<Vec<u8>, Error> {
+        self.expect_limited(expected, MAX_FRAME_LENGTH)
+    }
+
+    fn expect_handshake(&mut self) -> Result<Vec<u8>, Error> {
+        self.expect_limited(FrameKind::Handshake, MAX_HANDSHAKE_LENGTH)
+    }
+
+    fn expect_limited(&mut self, expected: FrameKind, max_length: usize) -> Result<Vec<u8>, Error> {
+        let (kind, payload) = self.receive_limited(max_length)?;
+        if kind != expected {
+            return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("expected {:?} but got {:?}", expected, kind))));
+        }
+        Ok(payload)
+    }
+}
+
+/// Synchronizes the repository with the peer on the other end of the stream, which has to run `serve_peer`.
+///
+/// Files the peer changed are pulled first, conflicts are resolved locally as the resolution says.
+/// Then all files the peer misses are pushed, including local versions that won a conflict and conflict copies.
+/// Returns the files that were pushed and pulled and the conflicts left for the user.
+pub fn sync_with_peer<S: Read + Write>(stream: S, repo: &Repository, source: &mut impl FileSource, resolution: ConflictResolution) -> Result<SyncDiff, Error> {
+    let started = now_millis();
+    let mut connection = Connection::new(stream);
+    let peer = authenticate_responder(&mut connection, repo)?;
+
+    let files = repo.list_file_syncs(source)?;
+    let mut reconciliation = Reconciliation::new(&files);
+    let mut request = Some(reconciliation.start());
+    while let Some(message) = request {
+        connection.send(FrameKind::Synchronization, &write_message(&message)?)?;
+        let payload = connection.expect(FrameKind::Synchronization)?;
+        let answer: Synchronization = read_message(&payload)?;
+        request = reconciliation.process_answer(&answer)?;
+    }
+    let diff = reconciliation.finish();
+
+    let mut result = SyncDiff { push: diff.push, ..SyncDiff::default() };
+    let requested: Vec<FileId> = diff.pull.into_iter().chain(diff.conflicts).collect();
+    if !requested.is_empty() {
+        let request = FileRequest { file_ids: requested.iter().map(|id| Cow::from(id.as_bytes().as_ref())).collect() };
+        connection.send(FrameKind::FileRequest, &write_message(&request)?)?;
+        for id in requested {
+            match import_file(repo, source, id, &mut IncomingFile::new(&mut connection, id), resolution)? {
+                Some(ImportResult::Stored(_)) => result.pull.push(id),
+                Some(ImportResult::KeptLocal) => result.push.push(id),
+                Some(ImportResult::ConflictCopy { copy, .. }) => {
+                    result.pull.push(copy.id);
+                    result.push.push(id);
+                    result.push.push(copy.id);
+                }
+                Some(ImportResult::Deferred) => result.conflicts.push(id),
+                None => {}
+            }
+        }
+    }
+
+    for id in &result.push {
+        send_file(&mut connection, repo, source, *id)?;
+    }
+    connection.send(FrameKind::Done, &[])?;
+    repo.record_peer_sync(source, peer, started)?;
+    Ok(result)
+}
+
+/// Answers a peer running `sync_with_peer` until it is done.
+/// Conflicts in the files pushed by the peer are resolved as the resolution says.
+pub fn serve_peer<S: Read + Write>(stream: S, repo: &Repository, source: &mut impl FileSource, resolution: ConflictResolution) -> Result<(), Error> {
+    let started = now_millis();
+    let mut connection = Connection::new(stream);
+    let peer = authenticate_initiator(&mut connection, repo)?;
+
+    let files = repo.list_file_syncs(source)?;
+    let known: HashSet<FileId> = files.iter().map(|file| file.id).collect();
+    loop {
+        let (kind, payload) = connection.receive()?;
+        match kind {
+            FrameKind::Synchronization => {
+                let request: Synchronization = read_message(&payload)?;
+                let answer = answer_synchronization(&files, &request)?;
+                connection.send(FrameKind::Synchronization, &write_message(&answer)?)?;
+            }
+            FrameKind::FileChunk => {
+                let mut incoming = IncomingFile::from_first_chunk(&mut connection, &payload)?;
+                let id = incoming.file_id;
+                import_file(repo, source, id, &mut incoming, resolution)?;
+            }
+            FrameKind::FileRequest => {
+                let request: FileRequest = read_message(&payload)?;
+                for id in &request.file_ids {
+                    let id = FileId::from_bytes(id)?;
+                    if !known.contains(&id) {
+                        return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("requested file {} is not part of the repository", id))));
+                    }
+                    send_file(&mut connection, repo, source, id)?;
+                }
+            }
+            FrameKind::Done => return repo.record_peer_sync(source, peer, started),
+            FrameKind::Handshake => return Err(Error::from(ErrorKind::InvalidSyncMessage("unexpected handshake".into()))),
+        }
+    }
+}
+
+/// Imports a received file, `None` if the local version already contains all its changes.
+fn import_file(repo: &Repository, source: &mut impl FileSource, id: FileId, input: &mut impl Read, resolution: ConflictResolution) -> Result<Option<ImportResult>, Error> {
+    match repo.import_stored_file(source, id, input, resolution) {
+        Ok(result) => Ok(Some(result)),
+        Err(e) => match e.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::StaleFile { .. }) => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+/// Everything exchanged during the handshake, each proof covers all of it.
+struct Transcript {
+    initiator_challenge: Vec<u8>,
+    responder_challenge: Vec<u8>,
+    initiator_device: DeviceId,
+    responder_device: DeviceId,
+}
+
+impl Transcript {
+    /// Proof of knowing the file key, bound to the transcript and the role of the sender.
+    fn proof(&self, repo: &Repository, role: &[u8]) -> Hmac<Sha256> {
+        self.mac(repo, &[], role)
+    }
+
+    /// Key of the frames the given role sends after the handshake.
+    fn frame_key(&self, repo: &Repository, role: &[u8]) -> HashedPw {
+        HashedPw::from(self.mac(repo, FRAME_KEY_INFO, role).result().code().as_slice())
+    }
+
+    fn mac(&self, repo: &Repository, purpose: &[u8], role: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&sync_key(repo)).expect("hmac accepts keys of any length");
+        mac.input(purpose);
+        mac.input(role);
+        mac.input(repo.id.as_bytes());
+        mac.input(&self.initiator_challenge);
+        mac.input(&self.responder_challenge);
+        mac.input(self.initiator_device.as_bytes());
+        mac.input(self.responder_device.as_bytes());
+        mac
+    }
+}
+
+/// The key of the proofs, derived from the file key with HKDF-SHA256 and the repository id as salt.
+/// The double hashed password is stored in clear in the repository file, so it cannot prove anything.
+fn sync_key(repo: &Repository) -> HashedPw {
+    let mut extract = Hmac::<Sha256>::new_varkey(repo.id.as_bytes()).expect("hmac accepts keys of any length");
+    extract.input(&repo.file_pw);
+    let pseudo_random_key = HashedPw::from(extract.result().code().as_slice());
+    let mut expand = Hmac::<Sha256>::new_varkey(&pseudo_random_key).expect("hmac accepts keys of any length");
+    expand.input(SYNC_KEY_INFO);
+    expand.input(&[1]);
+    HashedPw::from(expand.result().code().as_slice())
+}
+
+fn frame_mac(key: &HashedPw, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts keys of any length");
+    mac.input(body);
+    mac
+}
+
+fn sequence_bytes(sequence: u64) -> [u8; SEQUENCE_LENGTH] {
+    let mut bytes = [0u8; SEQUENCE_LENGTH];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = (sequence >> ((SEQUENCE_LENGTH - 1 - index) * 8)) as u8;
+    }
+    bytes
+}
+
+fn check_challenge(challenge: Option<&Cow<[u8]>>) -> Result<Vec<u8>, Error> {
+    match challenge {
+        Some(challenge) if challenge.len() == CHALLENGE_LENGTH => Ok(challenge.to_vec()),
+        _ => Err(Error::from(ErrorKind::InvalidSyncMessage("invalid handshake challenge".into()))),
+    }
+}
+
+fn check_device(device_id: Option<&Cow<[u8]>>) -> Result<DeviceId, Error> {
+    let device_id = device_id.ok_or_else(|| ErrorKind::InvalidSyncMessage("handshake without device".into()))?;
+    Ok(DeviceId::from_bytes(device_id)?)
+}
+
+fn check_proof(mac: Hmac<Sha256>, proof: Option<&Cow<[u8]>>) -> Result<(), Error> {
+    let proof = proof.ok_or(ErrorKind::SyncAuthenticationFailed)?;
+    mac.verify(proof).map_err(|_| Error::from(ErrorKind::SyncAuthenticationFailed))
+}
+
+/// Runs the handshake as initiator and returns the device of the peer.
+/// The own proof is sent first, the one of the responder is checked afterwards.
+fn authenticate_responder<S: Read + Write>(connection: &mut Connection<S>, repo: &Repository) -> Result<DeviceId, Error> {
+    let own_challenge = random_bytes(CHALLENGE_LENGTH)?;
+    let hello = SyncHandshake {
+        repository_id: Some(Cow::from(repo.id.as_bytes().as_ref())),
+        challenge: Some(Cow::from(own_challenge.as_slice())),
+        proof: None,
+        device_id: Some(Cow::from(repo.device_id.as_bytes().as_ref())),
+    };
+    connection.send(FrameKind::Handshake, &write_message(&hello)?)?;
+
+    let payload = connection.expect_handshake()?;
+    let reply: SyncHandshake = read_message(&payload)?;
+    let transcript = Transcript {
+        initiator_challenge: own_challenge,
+        responder_challenge: check_challenge(reply.challenge.as_ref())?,
+        initiator_device: repo.device_id,
+        responder_device: check_device(reply.device_id.as_ref())?,
+    };
+
+    let proof = transcript.proof(repo, INITIATOR).result().code().to_vec();
+    let answer = SyncHandshake { repository_id: None, challenge: None, proof: Some(Cow::from(proof)), device_id: None };
+    connection.send(FrameKind::Handshake, &write_message(&answer)?)?;
+
+    let payload = connection.expect_handshake()?;
+    let confirmation: SyncHandshake = read_message(&payload)?;
+    check_proof(transcript.proof(repo, RESPONDER), confirmation.proof.as_ref())?;
+    connection.authenticate(transcript.frame_key(repo, INITIATOR), transcript.frame_key(repo, RESPONDER));
+    Ok(transcript.responder_device)
+}
+
+/// Runs the handshake as responder and returns the device of the peer.
+/// The own proof is only sent once the initiator proved that it knows the file key.
+fn authenticate_initiator<S: Read + Write>(connection: &mut Connection<S>, repo: &Repository) -> Result<DeviceId, Error> {
+    let payload = connection.expect_handshake()?;
+    let hello: SyncHandshake = read_message(&payload)?;
+    if hello.repository_id.as_ref().map(|id| id.as_ref()) != Some(repo.id.as_bytes().as_ref()) {
+        return Err(Error::from(ErrorKind::SyncAuthenticationFailed));
+    }
+    let transcript = Transcript {
+        initiator_challenge: check_challenge(hello.challenge.as_ref())?,
+        responder_challenge: random_bytes(CHALLENGE_LENGTH)?,
+        initiator_device: check_device(hello.device_id.as_ref())?,
+        responder_device: repo.device_id,
+    };
+
+    let reply = SyncHandshake {
+        repository_id: None,
+        challenge: Some(Cow::from(transcript.responder_challenge.as_slice())),
+        proof: None,
+        device_id: Some(Cow::from(repo.device_id.as_bytes().as_ref())),
+    };
+    connection.send(FrameKind::Handshake, &write_message(&reply)?)?;
+
+    let payload = connection.expect_handshake()?;
+    let answer: SyncHandshake = read_message(&payload)?;
+    check_proof(transcript.proof(repo, INITIATOR), answer.proof.as_ref())?;
+
+    let proof = transcript.proof(repo, RESPONDER).result().code().to_vec();
+    let confirmation = SyncHandshake { repository_id: None, challenge: None, proof: Some(Cow::from(proof)), device_id: None };
+    connection.send(FrameKind::Handshake, &write_message(&confirmation)?)?;
+    connection.authenticate(transcript.frame_key(repo, RESPONDER), transcript.frame_key(repo, INITIATOR));
+    Ok(transcript.initiator_device)
+}
+
+fn send_file<S: Read + Write>(connection: &mut Connection<S>, repo: &Repository, source: &impl FileSource, id: FileId) -> Result<(), Error> {
+    let mut input = repo.export_stored_file(source, id)?;
+    loop {
+        let mut data = Vec::with_capacity(FILE_CHUNK_SIZE);
+        (&mut input).take(FILE_CHUNK_SIZE as u64).read_to_end(&mut data)?;
+        let last = data.len() < FILE_CHUNK_SIZE;
+        let chunk = FileChunk { file_id: Cow::from(id.as_bytes().as_ref()), data: Cow::from(data), last };
+        connection.send(FrameKind::FileChunk, &write_message(&chunk)?)?;
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads the content of the `FileChunk` frames of one file.
+struct IncomingFile<'c, S: Read + Write + 'c> {
+    connection: &'c mut Connection<S>,
+    file_id: FileId,
+    data: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<'c, S: Read + Write + 'c> IncomingFile<'c, S> {
+    fn new(connection: &'c mut Connection<S>, file_id: FileId) -> Self {
+        IncomingFile { connection, file_id, data: Vec::new(), position: 0, finished: false }
+    }
+
+    fn from_first_chunk(connection: &'c mut Connection<S>, payload: &[u8]) -> Result<Self, Error> {
+        let chunk: FileChunk = read_message(payload)?;
+        let mut incoming = IncomingFile::new(connection, FileId::from_bytes(&chunk.file_id)?);
+        incoming.data = chunk.data.to_vec();
+        incoming.finished = chunk.last;
+        Ok(incoming)
+    }
+
+    fn next_chunk(&mut self) -> Result<(), Error> {
+        let payload = self.connection.expect(FrameKind::FileChunk)?;
+        let chunk: FileChunk = read_message(&payload)?;
+        if chunk.file_id.as_ref() != self.file_id.as_bytes().as_ref() {
+            return Err(Error::from(ErrorKind::InvalidSyncMessage(format!("chunk of another file while receiving {}", self.file_id))));
+        }
+        self.data = chunk.data.to_vec();
+        self.position = 0;
+        self.finished = chunk.last;
+        Ok(())
+    }
+}
+
+impl<'c, S: Read + Write + 'c> Read for IncomingFile<'c, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.data.len() {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            self.next_chunk().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.compat()))?;
+        }
+        let len = buf.len().min(self.data.len() - self.position);
+        buf[..len].copy_from_slice(&self.data[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypt::fast_hash_parameters;
+    use files::DirectoryFileSource;
+    use pb::file::{CompressionType, EncryptionType, PasswordHashType};
+    use repository::{create_repository, open_repository};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use tempdir::TempDir;
+
+    fn peers() -> (TempDir, DirectoryFileSource, Repository, TempDir, DirectoryFileSource, Repository) {
+        let (dir_a, dir_b) = (TempDir::new("peer_a").unwrap(), TempDir::new("peer_b").unwrap());
+        let mut source_a = DirectoryFileSource::new(dir_a.path()).unwrap();
+        let mut source_b = DirectoryFileSource::new(dir_b.path()).unwrap();
+        let repo_a = create_repository(&mut source_a, "peer", b"secret", EncryptionType::XChachaPoly1305, PasswordHashType::Argon2i, fast_hash_parameters()).unwrap();
+        let name = repo_a.id.hyphenated().to_string();
+        source_b.store_file(&name, &source_a.get_file_content(&name).unwrap()).unwrap();
+        let repo_b = open_repository(&source_b, repo_a.id, b"secret").unwrap();
+        (dir_a, source_a, repo_a, dir_b, source_b, repo_b)
+    }
+
+    /// Runs `serve_peer` in a thread and `sync_with_peer` against it over a loopback connection.
+    fn sync(repo_a: &Repository, source_a: &mut DirectoryFileSource, repo_b: Repository, mut source_b: DirectoryFileSource, resolution: ConflictResolution) -> (Result<SyncDiff, Error>, Result<(), Error>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_peer(stream, &repo_b, &mut source_b, resolution).map_err(|e| e.to_string())
+        });
+        let result = sync_with_peer(TcpStream::connect(address).unwrap(), repo_a, source_a, resolution);
+        let served = server.join().unwrap().map_err(|e| format_err!("{}", e));
+        (result, served)
+    }
+
+    #[test]
+    fn test_sync_with_peer() {
+        let (_dir_a, mut source_a, repo_a, dir_b, mut source_b, repo_b) = peers();
+        let big_content: Vec<u8> = (0..3 * FILE_CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+        let only_a = repo_a.create_file(&mut source_a, b"a", &big_content, CompressionType::None).unwrap();
+        let only_b = repo_b.create_file(&mut source_b, b"b", b"content b", CompressionType::DeflateZip).unwrap();
+        let shared = repo_a.create_file(&mut source_a, b"shared", b"v1", CompressionType::None).unwrap();
+        let name = shared.id.hyphenated().to_string();
+        source_b.store_file(&name, &source_a.get_file_content(&name).unwrap()).unwrap();
+        let updated = repo_b.update_file(&mut source_b, &shared, b"shared", b"v2").unwrap();
+
+        let path_b = dir_b.path().to_path_buf();
+        let (result, served) = sync(&repo_a, &mut source_a, repo_b.clone(), source_b, ConflictResolution::Defer);
+        served.unwrap();
+        let diff = result.unwrap();
+        assert_eq!(vec![only_a.id], diff.push);
+        let mut pulled = diff.pull.clone();
+        pulled.sort();
+        let mut expected = vec![only_b.id, shared.id];
+        expected.sort();
+        assert_eq!(expected, pulled);
+
+        let source_b = DirectoryFileSource::new(path_b).unwrap();
+        assert_eq!(big_content, repo_b.read_content(&source_b, &only_a).unwrap().as_slice());
+        assert_eq!(b"content b".as_ref(), repo_a.read_content(&source_a, &only_b).unwrap().as_slice());
+        assert_eq!(b"v2".as_ref(), repo_a.read_content(&source_a, &updated).unwrap().as_slice());
+
+        let mut files_a = repo_a.list_file_syncs(&source_a).unwrap();
+        let mut files_b = repo_b.list_file_syncs(&source_b).unwrap();
+        files_a.sort_by_key(|f| f.id);
+        files_b.sort_by_key(|f| f.id);
+        assert_eq!(files_a, files_b);
+    }
+
+    #[test]
+    fn test_concurrent_changes_resolved() {
+        let (_dir_a, mut source_a, repo_a, dir_b, mut source_b, repo_b) = peers();
+        let shared = repo_a.create_file(&mut source_a, b"shared", b"original", CompressionType::None).unwrap();
+        let name = shared.id.hyphenated().to_string();
+        source_b.store_file(&name, &source_a.get_file_content(&name).unwrap()).unwrap();
+        repo_a.update_file(&mut source_a, &shared, b"shared", b"changed on a").unwrap();
+        repo_b.update_file(&mut source_b, &shared, b"shared", b"changed on b").unwrap();
+
+        let (result, served) = sync(&repo_a, &mut source_a, repo_b.clone(), source_b, ConflictResolution::ConflictCopy);
+        served.unwrap();
+        let diff = result.unwrap();
+        assert!(diff.conflicts.is_empty());
+        assert_eq!(2, diff.push.len());
+
+        let source_b = DirectoryFileSource::new(dir_b.path()).unwrap();
+        let mut contents_a: Vec<Vec<u8>> = repo_a.list_files(&source_a).unwrap().iter().map(|f| repo_a.read_content(&source_a, f).unwrap().to_vec()).collect();
+        let mut contents_b: Vec<Vec<u8>> = repo_b.list_files(&source_b).unwrap().iter().map(|f| repo_b.read_content(&source_b, f).unwrap().to_vec()).collect();
+        contents_a.sort();
+        contents_b.sort();
+        assert_eq!(vec![b"changed on a".to_vec(), b"changed on b".to_vec()], contents_a);
+        assert_eq!(contents_a, contents_b);
+    }
+
+    #[test]
+    fn test_deletion_synced() {
+        let (_dir_a, mut source_a, repo_a, dir_b, mut source_b, repo_b) = peers();
+        let file = repo_a.create_file(&mut source_a, b"header", b"content", CompressionType::None).unwrap();
+        let name = file.id.hyphenated().to_string();
+        source_b.store_file(&name, &source_a.get_file_content(&name).unwrap()).unwrap();
+        repo_a.delete_file(&mut source_a, &file).unwrap();
+
+        let (result, served) = sync(&repo_a, &mut source_a, repo_b.clone(), source_b, ConflictResolution::ConflictCopy);
+        served.unwrap();
+        assert_eq!(vec![file.id], result.unwrap().push);
+
+        let source_b = DirectoryFileSource::new(dir_b.path()).unwrap();
+        assert!(repo_b.list_files(&source_b).unwrap().is_empty());
+        assert!(repo_b.list_all_files(&source_b).unwrap()[0].deleted.is_some());
+        assert_eq!(vec![repo_b.device_id], repo_a.known_peers(&source_a).unwrap().iter().map(|p| p.0).collect::<Vec<_>>());
+        assert_eq!(vec![repo_a.device_id], repo_b.known_peers(&source_b).unwrap().iter().map(|p| p.0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_wrong_file_key_rejected() {
+        let (_dir_a, mut source_a, repo_a, _dir_b, source_b, mut repo_b) = peers();
+        repo_b.file_pw = HashedPw::from([7u8; 32].as_ref());
+
+        let (result, served) = sync(&repo_a, &mut source_a, repo_b, source_b, ConflictResolution::Defer);
+        assert!(result.is_err());
+        assert_eq!(ErrorKind::SyncAuthenticationFailed.to_string(), served.unwrap_err().to_string());
+    }
+
+    #[test]
+    fn test_no_proof_before_initiator_proved() {
+        let (_dir_a, _source_a, repo_a, _dir_b, mut source_b, repo_b) = peers();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_peer(stream, &repo_b, &mut source_b, ConflictResolution::Defer).map_err(|e| e.to_string())
+        });
+
+        let mut connection = Connection::new(TcpStream::connect(address).unwrap());
+        let challenge = random_bytes(CHALLENGE_LENGTH).unwrap();
+        let hello = SyncHandshake {
+            repository_id: Some(Cow::from(repo_a.id.as_bytes().as_ref())),
+            challenge: Some(Cow::from(challenge)),
+            proof: None,
+            device_id: Some(Cow::from(repo_a.device_id.as_bytes().as_ref())),
+        };
+        connection.send(FrameKind::Handshake, &write_message(&hello).unwrap()).unwrap();
+        let payload = connection.expect_handshake().unwrap();
+        let reply: SyncHandshake = read_message(&payload).unwrap();
+        assert!(reply.proof.is_none());
+
+        connection.send(FrameKind::Handshake, &vec![0u8; MAX_HANDSHAKE_LENGTH]).unwrap();
+        assert!(server.join().unwrap().unwrap_err().contains("invalid frame length"));
+    }
+
+    #[test]
+    fn test_frames_authenticated() {
+        let (send, receive) = (HashedPw::from([1u8; 32].as_ref()), HashedPw::from([2u8; 32].as_ref()));
+        let mut sender = Connection::new(io::Cursor::new(Vec::new()));
+        sender.authenticate(send.clone(), receive.clone());
+        sender.send(FrameKind::Synchronization, b"first").unwrap();
+        sender.send(FrameKind::Done, &[]).unwrap();
+        let frames = sender.stream.into_inner();
+        let receiver = |data: Vec<u8>| {
+            let mut receiver = Connection::new(io::Cursor::new(data));
+            receiver.authenticate(receive.clone(), send.clone());
+            receiver
+        };
+
+        let mut valid = receiver(frames.clone());
+        assert_eq!(b"first".to_vec(), valid.expect(FrameKind::Synchronization).unwrap());
+        assert!(valid.expect(FrameKind::Done).unwrap().is_empty());
+
+        let mut changed = frames.clone();
+        changed[14] ^= 1;
+        let error = receiver(changed).receive().unwrap_err();
+        assert_eq!(ErrorKind::SyncAuthenticationFailed.to_string(), error.to_string());
+
+        let first_length = 4 + 1 + SEQUENCE_LENGTH + 5 + TAG_LENGTH;
+        let replayed = [&frames[..first_length], &frames[..first_length]].concat();
+        let mut replayed = receiver(replayed);
+        replayed.receive().unwrap();
+        assert!(replayed.receive().unwrap_err().to_string().contains("expected frame 1"));
+    }
+}
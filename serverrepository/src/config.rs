@@ -0,0 +1,33 @@
+use lockout::LockoutConfig;
+use session::SessionConfig;
+use std::fs::File;
+use std::io::{Read, Result as IoResult};
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+pub struct Config {
+    /// Address the REST interface listens on
+    #[serde(default = "default_address")]
+    pub address: String,
+    /// Folders containing the repositories, new ones are created in the first folder
+    pub repository_dirs: Vec<PathBuf>,
+    #[serde(default)]
+    pub session: SessionConfig,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+}
+
+fn default_address() -> String {
+    "127.0.0.1:8000".into()
+}
+
+pub fn read_config(path: &Path) -> IoResult<Config> {
+    if !path.exists() {
+        return Err(::std::io::Error::new(::std::io::ErrorKind::Other, format!("File {:?} does not exist ", path)));
+    }
+    let mut f = File::open(path)?;
+    let mut s = String::new();
+    f.read_to_string(&mut s)?;
+
+    ::toml::from_str(s.as_str()).map_err(|e| ::std::io::Error::new(::std::io::ErrorKind::Other, format!("Could not parse {:?}: {} ", path, e)))
+}
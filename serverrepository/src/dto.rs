@@ -0,0 +1,269 @@
+//! The JSON objects of the REST interface, they keep the names the idnadrev2 UI already uses.
+
+use chrono::{DateTime, Utc};
+use repository::pb::file::EncryptionType as StoredEncryptionType;
+use repository::repository::RepositoryDescriptor as StoredRepositoryDescriptor;
+use rest_in_rust::*;
+use serde_json;
+use std::fmt;
+use uuid::Uuid;
+
+pub type RepoId = Uuid;
+pub type FileId = Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionType {
+    RingChachaPoly1305,
+    RingAESGCM,
+    XChachaPoly1305,
+}
+
+impl From<EncryptionType> for StoredEncryptionType {
+    fn from(enc_type: EncryptionType) -> Self {
+        match enc_type {
+            EncryptionType::RingChachaPoly1305 => StoredEncryptionType::ChachaPoly1305,
+            EncryptionType::RingAESGCM => StoredEncryptionType::AesGcm256,
+            EncryptionType::XChachaPoly1305 => StoredEncryptionType::XChachaPoly1305,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositoryDescriptor {
+    pub id: RepoId,
+    pub name: String,
+}
+
+impl From<StoredRepositoryDescriptor> for RepositoryDescriptor {
+    fn from(descriptor: StoredRepositoryDescriptor) -> Self {
+        RepositoryDescriptor { id: descriptor.id, name: descriptor.name }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateRepository {
+    ///Name of the repository
+    pub name: String,
+    ///Encryption type of the repository, will be used for all files in it
+    pub encryption: EncryptionType,
+    ///Password bytes
+    pub password: Vec<u8>,
+    ///Name of the user the password belongs to
+    pub user_name: String,
+}
+
+impl FromRequest for CreateRepository {
+    fn from_req(req: &mut Request) -> Result<Self, HttpError> {
+        req.body().to_json()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenRepository {
+    ///ID of the repository to open
+    pub id: RepoId,
+    ///Password to use for open
+    pub password: Vec<u8>,
+    ///Username to use for open, empty for repositories created without user name
+    pub user_name: String,
+}
+
+impl FromRequest for OpenRepository {
+    fn from_req(req: &mut Request) -> Result<Self, HttpError> {
+        req.body().to_json()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct AddUser {
+    ///Name of the new user
+    pub user_name: String,
+    ///Password bytes of the new user
+    pub password: Vec<u8>,
+    ///Password bytes of the user who opened the session
+    pub current_password: Vec<u8>,
+}
+
+impl FromRequest for AddUser {
+    fn from_req(req: &mut Request) -> Result<Self, HttpError> {
+        req.body().to_json()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoveUser {
+    ///Password bytes of the user who opened the session
+    pub current_password: Vec<u8>,
+}
+
+impl FromRequest for RemoveUser {
+    fn from_req(req: &mut Request) -> Result<Self, HttpError> {
+        req.body().to_json()
+    }
+}
+
+/// Returned when a repository was created, it is opened right away.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RepositoryDto {
+    pub id: RepoId,
+    pub token: AccessToken,
+}
+
+/// Grants access to an opened repository, sent in the `token` header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AccessToken {
+    pub id: Uuid,
+}
+
+impl AccessToken {
+    pub fn new() -> Self {
+        AccessToken { id: Uuid::new_v4() }
+    }
+
+    pub fn to_header(&self) -> (::http::header::HeaderName, ::http::header::HeaderValue) {
+        use http::header::{HeaderName, HeaderValue};
+
+        let name = HeaderName::from_static("token");
+        let value = HeaderValue::from_str(&self.id.simple().to_string()).unwrap();
+        (name, value)
+    }
+}
+
+impl fmt::Display for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id.simple())
+    }
+}
+
+impl FromRequest for AccessToken {
+    fn from_req(req: &mut Request) -> Result<Self, HttpError> {
+        match req.header_str("token") {
+            Some(token_str) => {
+                match Uuid::parse_str(token_str) {
+                    Ok(id) => Ok(AccessToken { id }),
+                    Err(_) => Err(HttpError::bad_request(format!("Could not parse Uuid {}", token_str)))
+                }
+            }
+            None => Err(HttpError::bad_request("No token set in header"))
+        }
+    }
+}
+
+/// A file as the UI sees it. Everything but the content is stored as JSON in the encrypted header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct File {
+    pub repository: RepoId,
+    pub id: FileId,
+    pub version: u32,
+    pub name: String,
+
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub deleted: Option<DateTime<Utc>>,
+
+    pub file_type: String,
+    pub tags: Vec<String>,
+    pub details: Option<serde_json::Value>,
+
+    pub content: Option<Vec<u8>>,
+}
+
+impl File {
+    pub fn new(repo: &RepoId, name: &str, file_type: &str, content: Option<Vec<u8>>) -> Self {
+        let now = Utc::now();
+        File {
+            repository: *repo,
+            id: Uuid::new_v4(),
+            version: 0,
+            name: name.to_string(),
+
+            created: now,
+            updated: now,
+            deleted: None,
+
+            file_type: file_type.to_string(),
+            tags: Vec::new(),
+            details: None,
+            content,
+        }
+    }
+
+    pub fn from_header(descriptor: FileDescriptor, header: FileHeader, content: Option<Vec<u8>>) -> Self {
+        File {
+            repository: descriptor.repo,
+            id: descriptor.id,
+            version: descriptor.version,
+            name: header.name,
+
+            created: header.created,
+            updated: header.updated,
+            deleted: header.deleted,
+
+            file_type: header.file_type,
+            tags: header.tags,
+            details: header.details,
+            content,
+        }
+    }
+
+    /// Splits the file into the header to store and its content.
+    pub fn split_header_content(self) -> (FileHeader, Option<Vec<u8>>) {
+        let header = FileHeader {
+            name: self.name,
+
+            created: self.created,
+            updated: self.updated,
+            deleted: self.deleted,
+
+            file_type: self.file_type,
+            tags: self.tags,
+            details: self.details,
+        };
+        (header, self.content)
+    }
+}
+
+impl FromRequest for File {
+    fn from_req(req: &mut Request) -> Result<Self, HttpError> {
+        req.body().to_json()
+    }
+}
+
+/// The part of a `File` that is stored in the encrypted header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileHeader {
+    pub name: String,
+
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub deleted: Option<DateTime<Utc>>,
+
+    pub file_type: String,
+    pub tags: Vec<String>,
+    pub details: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileDescriptor {
+    pub repo: RepoId,
+    pub id: FileId,
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Page {
+    pub files: Vec<File>,
+    pub total: Option<u32>,
+    pub offset: u32,
+    pub limit: u32,
+    pub next: Option<String>,
+    pub previous: Option<String>,
+}
@@ -0,0 +1,24 @@
+extern crate chrono;
+#[macro_use]
+extern crate failure;
+extern crate http;
+#[macro_use]
+extern crate log;
+extern crate repository;
+extern crate rest_in_rust;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+extern crate uuid;
+
+#[cfg(test)]
+extern crate tempdir;
+
+pub mod config;
+pub mod dto;
+pub mod lockout;
+pub mod rest;
+pub mod session;
+pub mod state;
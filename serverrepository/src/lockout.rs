@@ -0,0 +1,362 @@
+//! Brute-force protection for opening repositories.
+//!
+//! Failed attempts are tracked per client address and per repository and client address.
+//! Every attempt counts as failure until it succeeded, so parallel guesses of a client run into the backoff as well.
+//! An attempt that failed for another reason than a wrong password is taken back.
+//! After a failure the next attempt has to wait `base_delay` seconds, doubled with every further failure up to `max_delay`.
+//! After `max_failures` the client, or the client for this repository, is locked for `lockout` seconds.
+//! A successful open resets the counters of both.
+//!
+//! Repositories are not locked for all clients: their ids are listed to everyone, so anybody could lock out the owners.
+//! The price is that an attacker with many addresses is only slowed down per address.
+//! Requests without a client address do not share a made up one, they are counted per repository.
+//!
+//! The counters are written to `state_file` on every change, so a restart does not reset them.
+//! Writing them is best effort, an error is logged and does not keep anybody from opening a repository.
+//! Failed attempts and lockouts are logged to the audit target.
+
+use dto::RepoId;
+use failure::Error;
+use serde_json::{from_slice, to_vec};
+use session::AUDIT_TARGET;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The `[lockout]` table of `idnadrev.toml`, durations in seconds.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LockoutConfig {
+    /// Failures after which the client gets locked
+    pub max_failures: u32,
+    /// How long a lock lasts
+    pub lockout: u64,
+    /// Delay after the first failure
+    pub base_delay: u64,
+    /// Upper bound of the delay between two attempts before the lock
+    pub max_delay: u64,
+    /// Where the counters are kept between restarts
+    pub state_file: PathBuf,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            max_failures: 10,
+            lockout: 15 * 60,
+            base_delay: 1,
+            max_delay: 60,
+            state_file: PathBuf::from("lockout.json"),
+        }
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum LockoutError {
+    #[fail(display = "Too many failed attempts to open {}, retry in {} seconds", key, retry_after)]
+    Locked { key: String, retry_after: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Failures {
+    count: u32,
+    /// Seconds since the unix epoch
+    last: u64,
+}
+
+/// Counts the failed attempts to open repositories.
+pub struct AttemptTracker {
+    config: LockoutConfig,
+    /// Keys are `client:<address>` and `repository:<uuid> client:<address>`, or `repository:<uuid>` without address
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+/// An attempt that was allowed, it has to be finished with `AttemptTracker::succeeded`, `AttemptTracker::failed`
+/// or `AttemptTracker::cancelled`. Dropping it keeps it counted as failure.
+#[must_use]
+pub struct Attempt {
+    keys: Vec<String>,
+    previous: Vec<Option<Failures>>,
+}
+
+impl AttemptTracker {
+    /// Loads the counters from the state file, a missing file means no failures so far.
+    pub fn new(config: LockoutConfig) -> Result<Self, Error> {
+        let failures = match File::open(&config.state_file) {
+            Ok(mut file) => {
+                let mut content = Vec::new();
+                file.read_to_end(&mut content)?;
+                from_slice(&content)?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(Error::from(e)),
+        };
+        Ok(AttemptTracker { config, failures: Mutex::new(failures) })
+    }
+
+    /// Starts an attempt of the client to open the repository, fails with `LockoutError::Locked` if it has to wait.
+    pub fn begin(&self, repository: RepoId, client: Option<&str>) -> Result<Attempt, Error> {
+        self.begin_at(repository, client, now_secs())
+    }
+
+    /// Resets the counters of the client.
+    pub fn succeeded(&self, attempt: Attempt) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in &attempt.keys {
+            failures.remove(key);
+        }
+        self.store(&failures);
+    }
+
+    /// Keeps the attempt counted and records it in the audit log, the delay until the next attempt starts now.
+    pub fn failed(&self, attempt: Attempt, reason: &str) {
+        self.failed_at(attempt, reason, now_secs())
+    }
+
+    /// Takes the attempt back, e.g. because it failed for a reason that says nothing about the password.
+    pub fn cancelled(&self, attempt: Attempt) {
+        let mut failures = self.failures.lock().unwrap();
+        for (key, previous) in attempt.keys.into_iter().zip(attempt.previous) {
+            match previous {
+                Some(previous) => failures.insert(key, previous),
+                None => failures.remove(&key),
+            };
+        }
+        self.store(&failures);
+    }
+
+    fn begin_at(&self, repository: RepoId, client: Option<&str>, now: u64) -> Result<Attempt, Error> {
+        let keys = match client {
+            Some(client) => vec![format!("client:{}", client), format!("repository:{} client:{}", repository, client)],
+            None => vec![format!("repository:{}", repository)],
+        };
+        let mut failures = self.failures.lock().unwrap();
+        for key in &keys {
+            if let Some(retry_after) = failures.get(key).and_then(|f| self.retry_after(f, now)) {
+                return Err(Error::from(LockoutError::Locked { key: key.clone(), retry_after }));
+            }
+        }
+
+        let mut previous = Vec::new();
+        for key in &keys {
+            let failure = failures.entry(key.clone()).or_insert(Failures { count: 0, last: now });
+            previous.push(if failure.count == 0 { None } else { Some(*failure) });
+            // a counter starts again once its lock is over, never while attempts are still counted against it
+            if failure.count >= self.config.max_failures && self.retry_after(failure, now).is_none() {
+                failure.count = 0;
+            }
+            failure.count += 1;
+            failure.last = now;
+        }
+        self.forget_expired(&mut failures, now);
+        self.store(&failures);
+        Ok(Attempt { keys, previous })
+    }
+
+    fn failed_at(&self, attempt: Attempt, reason: &str, now: u64) {
+        let mut failures = self.failures.lock().unwrap();
+        for key in attempt.keys {
+            // a success in between removed the counter, the failure still counts
+            let failure = failures.entry(key.clone()).or_insert(Failures { count: 1, last: now });
+            failure.last = now;
+            warn!(target: AUDIT_TARGET, "Failed attempt {} to open {}: {}", failure.count, key, reason);
+            if failure.count >= self.config.max_failures {
+                warn!(target: AUDIT_TARGET, "Locked {} for {} seconds after {} failed attempts", key, self.config.lockout, failure.count);
+            }
+        }
+        self.store(&failures);
+    }
+
+    /// Seconds to wait until the next attempt is allowed, None if it is allowed now.
+    fn retry_after(&self, failures: &Failures, now: u64) -> Option<u64> {
+        if failures.count == 0 {
+            return None;
+        }
+        let wait = if failures.count >= self.config.max_failures {
+            self.config.lockout
+        } else {
+            let exponent = (failures.count - 1).min(63);
+            self.config.base_delay.checked_shl(exponent).unwrap_or(u64::max_value()).min(self.config.max_delay)
+        };
+        let allowed = failures.last.saturating_add(wait);
+        if now < allowed { Some(allowed - now) } else { None }
+    }
+
+    /// Counters that do not delay anything anymore are only kept for `lockout` seconds, so the file does not grow forever.
+    fn forget_expired(&self, failures: &mut HashMap<String, Failures>, now: u64) {
+        let lockout = self.config.lockout;
+        failures.retain(|_, f| now.saturating_sub(f.last) < lockout.max(1));
+    }
+
+    /// Logs if the counters could not be written, the ones in memory still count.
+    fn store(&self, failures: &HashMap<String, Failures>) {
+        if let Err(e) = self.write_state(failures) {
+            warn!("Could not write the failed attempts to {}: {}", self.config.state_file.display(), e);
+        }
+    }
+
+    /// Writes the counters to a temporary file first, so a crash never leaves a truncated state file.
+    fn write_state(&self, failures: &HashMap<String, Failures>) -> Result<(), Error> {
+        let state_file = &self.config.state_file;
+        let mut temp_name = state_file.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+        temp_name.push(".tmp");
+        let temp_file = state_file.with_file_name(temp_name);
+        fs::write(&temp_file, to_vec(failures)?)?;
+        fs::rename(&temp_file, state_file)?;
+        Ok(())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use uuid::Uuid;
+
+    fn tracker(dir: &TempDir) -> AttemptTracker {
+        AttemptTracker::new(LockoutConfig {
+            max_failures: 3,
+            lockout: 100,
+            base_delay: 2,
+            max_delay: 3,
+            state_file: dir.path().join("lockout.json"),
+        }).unwrap()
+    }
+
+    fn retry_after(result: Result<Attempt, Error>) -> u64 {
+        match result.err().unwrap().downcast::<LockoutError>().unwrap() {
+            LockoutError::Locked { retry_after, .. } => retry_after,
+        }
+    }
+
+    #[test]
+    fn test_backoff_and_lockout() {
+        let dir = TempDir::new("lockout").unwrap();
+        let tracker = tracker(&dir);
+        let id = Uuid::new_v4();
+
+        let attempt = tracker.begin_at(id, Some("client"), 1000).unwrap();
+        tracker.failed_at(attempt, "wrong password", 1000);
+        assert_eq!(2, retry_after(tracker.begin_at(id, Some("client"), 1000)));
+
+        let attempt = tracker.begin_at(id, Some("client"), 1002).unwrap();
+        tracker.failed_at(attempt, "wrong password", 1002);
+        assert_eq!(3, retry_after(tracker.begin_at(id, Some("client"), 1002)));
+
+        let attempt = tracker.begin_at(id, Some("client"), 1005).unwrap();
+        tracker.failed_at(attempt, "wrong password", 1005);
+        assert_eq!(100, retry_after(tracker.begin_at(id, Some("client"), 1005)));
+        assert!(tracker.begin_at(id, Some("other client"), 1005).is_ok());
+        assert_eq!(1, retry_after(tracker.begin_at(Uuid::new_v4(), Some("client"), 1104)));
+
+        assert!(tracker.begin_at(id, Some("client"), 1105).is_ok());
+    }
+
+    #[test]
+    fn test_delay_starts_after_failure() {
+        let dir = TempDir::new("lockout").unwrap();
+        let tracker = tracker(&dir);
+        let id = Uuid::new_v4();
+
+        let attempt = tracker.begin_at(id, Some("client"), 1000).unwrap();
+        tracker.failed_at(attempt, "wrong password", 1010);
+        assert_eq!(1, retry_after(tracker.begin_at(id, Some("client"), 1011)));
+    }
+
+    #[test]
+    fn test_success_resets() {
+        let dir = TempDir::new("lockout").unwrap();
+        let tracker = tracker(&dir);
+        let id = Uuid::new_v4();
+
+        let attempt = tracker.begin_at(id, Some("client"), 1000).unwrap();
+        tracker.failed_at(attempt, "wrong password", 1000);
+        let attempt = tracker.begin_at(id, Some("client"), 1002).unwrap();
+        tracker.succeeded(attempt);
+        assert!(tracker.begin_at(id, Some("client"), 1002).is_ok());
+    }
+
+    #[test]
+    fn test_pending_attempt_delays_parallel_ones() {
+        let dir = TempDir::new("lockout").unwrap();
+        let tracker = tracker(&dir);
+        let id = Uuid::new_v4();
+
+        let attempt = tracker.begin_at(id, Some("client"), 1000).unwrap();
+        assert_eq!(2, retry_after(tracker.begin_at(Uuid::new_v4(), Some("client"), 1000)));
+        assert!(tracker.begin_at(id, Some("other client"), 1000).is_ok());
+        tracker.cancelled(attempt);
+        assert!(tracker.begin_at(id, Some("client"), 1000).is_ok());
+    }
+
+    #[test]
+    fn test_without_address_per_repository() {
+        let dir = TempDir::new("lockout").unwrap();
+        let tracker = tracker(&dir);
+        let id = Uuid::new_v4();
+
+        let attempt = tracker.begin_at(id, None, 1000).unwrap();
+        tracker.failed_at(attempt, "wrong password", 1000);
+        assert_eq!(2, retry_after(tracker.begin_at(id, None, 1000)));
+        assert!(tracker.begin_at(Uuid::new_v4(), None, 1000).is_ok());
+        assert!(tracker.begin_at(id, Some("client"), 1000).is_ok());
+    }
+
+    #[test]
+    fn test_parallel_failures_keep_lock() {
+        let dir = TempDir::new("lockout").unwrap();
+        let tracker = AttemptTracker::new(LockoutConfig {
+            max_failures: 3,
+            lockout: 100,
+            base_delay: 0,
+            max_delay: 0,
+            state_file: dir.path().join("lockout.json"),
+        }).unwrap();
+        let id = Uuid::new_v4();
+
+        let attempts: Vec<_> = (0..3).map(|_| tracker.begin_at(id, Some("client"), 1000).unwrap()).collect();
+        assert_eq!(100, retry_after(tracker.begin_at(id, Some("client"), 1000)));
+        for attempt in attempts {
+            tracker.failed_at(attempt, "wrong password", 1001);
+        }
+        assert_eq!(100, retry_after(tracker.begin_at(id, Some("client"), 1001)));
+        assert_eq!(1, retry_after(tracker.begin_at(id, Some("client"), 1100)));
+        assert!(tracker.begin_at(id, Some("client"), 1101).is_ok());
+    }
+
+    #[test]
+    fn test_store_error_is_not_returned() {
+        let dir = TempDir::new("lockout").unwrap();
+        let tracker = AttemptTracker::new(LockoutConfig {
+            state_file: dir.path().join("missing").join("lockout.json"),
+            ..LockoutConfig::default()
+        }).unwrap();
+        let id = Uuid::new_v4();
+
+        let attempt = tracker.begin_at(id, Some("client"), 1000).unwrap();
+        tracker.failed_at(attempt, "wrong password", 1000);
+        assert_eq!(1, retry_after(tracker.begin_at(id, Some("client"), 1000)));
+    }
+
+    #[test]
+    fn test_survives_restart() {
+        let dir = TempDir::new("lockout").unwrap();
+        let id = Uuid::new_v4();
+        {
+            let tracker = tracker(&dir);
+            for second in &[1000, 1002, 1005] {
+                let attempt = tracker.begin_at(id, Some("client"), *second).unwrap();
+                tracker.failed_at(attempt, "wrong password", *second);
+            }
+        }
+        let tracker = tracker(&dir);
+        assert_eq!(50, retry_after(tracker.begin_at(id, Some("client"), 1055)));
+    }
+}
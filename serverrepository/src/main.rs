@@ -1,3 +1,44 @@
+extern crate log4rs;
+#[macro_use]
+extern crate log;
+extern crate rest_in_rust;
+extern crate serverrepository;
+
+use rest_in_rust::*;
+use serverrepository::config;
+use serverrepository::rest;
+use serverrepository::state::ServerState;
+use std::path::Path;
+
 fn main() {
-    println!("Hello, world!");
+    if let Err(e) = log4rs::init_file("config/log4rs.yaml", Default::default()) {
+        eprintln!("Could not initialize logging: {}", e);
+    }
+
+    let config = match config::read_config(Path::new("idnadrev.toml")) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Could not read configuration file: '{}'", e);
+            return;
+        }
+    };
+
+    let state = match ServerState::new(&config.repository_dirs, config.session.clone(), config.lockout.clone()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Could not open repository folders: '{}'", e);
+            return;
+        }
+    };
+    let addr = match config.address.parse() {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Invalid server address '{}': {}", config.address, e);
+            return;
+        }
+    };
+
+    let s = Server::new(addr, rest::router());
+    s.add_state(state);
+    s.start_http();
 }
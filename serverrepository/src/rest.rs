@@ -0,0 +1,376 @@
+//! # REST interface of the repositories
+//!
+//! The routes are the ones of the old server, so the idnadrev2 UI keeps working.
+//! Opening or creating a repository returns a token, which has to be sent in the `token` header for all file operations.
+//! The token expires when it is not used for a while and some time after the repository was opened, see `session`.
+//!
+//! |Path                                           |Description                                 |Body             |Returns                  |
+//! |-----------------------------------------------|:-------------------------------------------|-----------------|-------------------------|
+//! |GET /rest/v1/repo                              |Lists all repositories                      |                 |Vec of `RepositoryDescriptor`|
+//! |POST /rest/v1/repo                             |Creates and opens a repository              |`CreateRepository`|`RepositoryDto`         |
+//! |POST /rest/v1/repo/`<uuid>`                    |Opens a repository                          |`OpenRepository` |`AccessToken`            |
+//! |POST /rest/v1/repo/`<uuid>`/refresh            |Replaces the token with a new one           |                 |`AccessToken`            |
+//! |POST /rest/v1/repo/`<uuid>`/close              |Ends the session of the token               |                 |                         |
+//! |GET /rest/v1/repo/`<uuid>`                     |Lists all files without content             |                 |`Page`                   |
+//! |GET /rest/v1/repo/`<uuid>`/`<type>`            |Lists the files of the type without content |                 |`Page`                   |
+//! |POST /rest/v1/repo/`<uuid>`/file               |Creates a file                              |`File`           |`FileDescriptor`         |
+//! |GET /rest/v1/repo/`<uuid>`/file/`<uuid>`       |Retrieves a file without content            |                 |`File`                   |
+//! |GET /rest/v1/repo/`<uuid>`/file/`<uuid>`/full  |Retrieves a file with content               |                 |`File`                   |
+//! |POST /rest/v1/repo/`<uuid>`/file/`<uuid>`      |Updates a file, the content only if it is set|`File`          |`FileDescriptor`         |
+//! |DELETE /rest/v1/repo/`<uuid>`/file/`<uuid>`    |Deletes a file                              |                 |`FileDescriptor`         |
+//! |GET /rest/v1/user/`<uuid>`                     |Lists the users of a repository             |                 |Vec of user names        |
+//! |POST /rest/v1/user/`<uuid>`                    |Adds a user with their own password         |`AddUser`        |                         |
+//! |DELETE /rest/v1/user/`<uuid>`/`<name>`         |Removes a user and ends their sessions      |`RemoveUser`     |                         |
+//!
+//! Adding and removing users needs the current password of the user who opened the session, 401 UNAUTHORIZED if it is wrong.
+//! The user who created the repository can only remove themselves, others get 403 FORBIDDEN.
+//! Removing a user does not re-encrypt the files.
+//! Updates fail with 409 CONFLICT if the version of the file is not the stored one.
+//! Opening fails with 429 TOO MANY REQUESTS and a `Retry-After` header after failed attempts, see `lockout`.
+
+use dto::*;
+use failure::Error;
+use lockout::LockoutError;
+use http::header;
+use http::StatusCode;
+use repository::error::{is_not_found, ErrorKind};
+use repository::files::DirectoryFileSource;
+use repository::pb::file::CompressionType;
+use repository::repository::Repository;
+use repository::repository::file::RepositoryFile;
+use repository::repository::users;
+use rest_in_rust::*;
+use serde::Serialize;
+use serde_json::{from_slice, to_string, to_vec};
+use state::ServerState;
+use std::io;
+use uuid::Uuid;
+
+/// The development server of the UI.
+const ALLOWED_ORIGIN: &str = "http://localhost:3000";
+
+pub fn router() -> Router {
+    let mut router = Router::new();
+    router.get("/rest/v1/repo", list_repositories);
+    router.post("/rest/v1/repo", create_repository);
+    router.post("/rest/v1/repo/:repo_id", open_repository);
+    router.post("/rest/v1/repo/:repo_id/refresh", refresh_session);
+    router.post("/rest/v1/repo/:repo_id/close", close_repository);
+    router.get("/rest/v1/repo/:repo_id", list_files);
+    router.get("/rest/v1/repo/:repo_id/", list_files);
+    router.get("/rest/v1/repo/:repo_id/:type", list_files);
+    router.get("/rest/v1/repo/:repo_id/:type/", list_files);
+    router.post("/rest/v1/repo/:repo_id/file", create_file);
+    router.get("/rest/v1/repo/:repo_id/file/:file_id", get_file);
+    router.get("/rest/v1/repo/:repo_id/file/:file_id/full", get_file_full);
+    router.post("/rest/v1/repo/:repo_id/file/:file_id", update_file);
+    router.delete("/rest/v1/repo/:repo_id/file/:file_id", delete_file);
+    router.get("/rest/v1/user/:repo_id", list_users);
+    router.post("/rest/v1/user/:repo_id", add_user);
+    router.delete("/rest/v1/user/:repo_id/:user_name", remove_user);
+    router
+}
+
+pub fn list_repositories(req: &mut Request) -> Result<Response, HttpError> {
+    let state = ServerState::from_req_as_ref(req)?;
+
+    match state.list_repositories() {
+        Ok(repositories) => json_response(&repositories),
+        Err(e) => error_response(e),
+    }
+}
+
+pub fn create_repository(req: &mut Request) -> Result<Response, HttpError> {
+    let create_repo = CreateRepository::from_req(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    info!("#create_repository {}", create_repo.name);
+    match state.create_repository(&create_repo.name, &create_repo.user_name, &create_repo.password, create_repo.encryption.into()) {
+        Ok((id, token)) => json_response(&RepositoryDto { id, token }),
+        Err(e) => error_response(e),
+    }
+}
+
+pub fn open_repository(req: &mut Request) -> Result<Response, HttpError> {
+    let open = OpenRepository::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let client = client_address(req);
+    let state = ServerState::from_req_as_ref(req)?;
+
+    info!("#open_repository {} as {} from {}", repo_id, open.user_name, client.as_ref().map_or("an unknown address", String::as_str));
+    match state.open_repository(repo_id, &open.user_name, &open.password, client.as_ref().map(String::as_str)) {
+        Ok(token) => json_response(&token),
+        Err(e) => error_response(e),
+    }
+}
+
+pub fn refresh_session(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    match state.refresh_session(repo_id, &token) {
+        Some(token) => json_response(&token),
+        None => Err(HttpError::unauthorized("Token invalid")),
+    }
+}
+
+pub fn close_repository(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    info!("#close_repository {}", repo_id);
+    if state.close_repository(repo_id, &token) {
+        status_response(StatusCode::OK, String::new())
+    } else {
+        Err(HttpError::unauthorized("Token invalid"))
+    }
+}
+
+pub fn list_files(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let file_type = req.param("type").map(|t| t.to_string());
+    let state = ServerState::from_req_as_ref(req)?;
+
+    let page = state.read_repository(repo_id, &token, |repo, source| {
+        let mut files = Vec::new();
+        for file in repo.list_files(source)? {
+            let file = read_file(repo, source, &file, false)?;
+            let type_matches = match file_type {
+                Some(ref file_type) if file_type != "file" => file.file_type.eq_ignore_ascii_case(file_type),
+                _ => true,
+            };
+            if type_matches {
+                files.push(file);
+            }
+        }
+        files.sort_by(|a, b| b.updated.cmp(&a.updated));
+        let count = files.len() as u32;
+        Ok(Page { files, total: Some(count), offset: 0, limit: count, next: None, previous: None })
+    });
+    repository_response(page)
+}
+
+pub fn create_file(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let file = File::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    info!("#create_file {}", file.name);
+    let (header, content) = file.split_header_content();
+    let header = serialize_header(&header)?;
+    let created = state.write_repository(repo_id, &token, |repo, source| {
+        let file = repo.create_file(source, &header, &content.unwrap_or_default(), CompressionType::DeflateZip)?;
+        Ok(descriptor(&file))
+    });
+    repository_response(created)
+}
+
+pub fn get_file(req: &mut Request) -> Result<Response, HttpError> {
+    read_file_response(req, false)
+}
+
+pub fn get_file_full(req: &mut Request) -> Result<Response, HttpError> {
+    read_file_response(req, true)
+}
+
+pub fn update_file(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let file = File::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let file_id = file_id(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    info!("#update_file {}", file_id);
+    let version = file.version;
+    let (header, content) = file.split_header_content();
+    let header = serialize_header(&header)?;
+    let updated = state.write_repository(repo_id, &token, |repo, source| {
+        let stored = load_file(repo, source, file_id, Some(version))?;
+        let content = match content {
+            Some(content) => content,
+            None => repo.read_content(source, &stored)?.as_slice().to_vec(),
+        };
+        let updated = repo.update_file(source, &stored, &header, &content)?;
+        Ok(descriptor(&updated))
+    });
+    repository_response(updated)
+}
+
+pub fn delete_file(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let file_id = file_id(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    info!("#delete_file {}", file_id);
+    let deleted = state.write_repository(repo_id, &token, |repo, source| {
+        let stored = load_file(repo, source, file_id, None)?;
+        let tombstone = repo.delete_file(source, &stored)?;
+        Ok(descriptor(&tombstone))
+    });
+    repository_response(deleted)
+}
+
+pub fn list_users(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    let users = state.read_repository(repo_id, &token, |repo, source| users::list_users(source, repo.id));
+    repository_response(users)
+}
+
+pub fn add_user(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let add = AddUser::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    info!("#add_user {} to {}", add.user_name, repo_id);
+    match state.add_user(repo_id, &token, &add.current_password, &add.user_name, &add.password) {
+        Ok(Some(())) => status_response(StatusCode::OK, String::new()),
+        Ok(None) => Err(HttpError::unauthorized("Token invalid")),
+        Err(e) => error_response(e),
+    }
+}
+
+pub fn remove_user(req: &mut Request) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let user_name = match req.param("user_name") {
+        Some(user_name) => user_name.to_string(),
+        None => return Err(HttpError::bad_request("Missing route parameter 'user_name'")),
+    };
+    let remove = RemoveUser::from_req(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    info!("#remove_user {} from {}", user_name, repo_id);
+    match state.remove_user(repo_id, &token, &remove.current_password, &user_name) {
+        Ok(Some(())) => status_response(StatusCode::OK, String::new()),
+        Ok(None) => Err(HttpError::unauthorized("Token invalid")),
+        Err(e) => error_response(e),
+    }
+}
+
+fn read_file_response(req: &mut Request, with_content: bool) -> Result<Response, HttpError> {
+    let token = AccessToken::from_req(req)?;
+    let repo_id = repo_id(req)?;
+    let file_id = file_id(req)?;
+    let state = ServerState::from_req_as_ref(req)?;
+
+    let file = state.read_repository(repo_id, &token, |repo, source| {
+        let stored = load_file(repo, source, file_id, None)?;
+        read_file(repo, source, &stored, with_content)
+    });
+    repository_response(file)
+}
+
+/// Loads the file, deleted files are not found. If a version is given, it has to be the stored one.
+fn load_file(repo: &Repository, source: &DirectoryFileSource, file_id: FileId, version: Option<u32>) -> Result<RepositoryFile, Error> {
+    let stored = repo.get_file(source, file_id)?;
+    if stored.deleted.is_some() {
+        let cause = io::Error::new(io::ErrorKind::NotFound, "the file was deleted");
+        return Err(Error::from(ErrorKind::Io { file_name: stored.file_name, cause }));
+    }
+    match version {
+        Some(version) if version != stored.version => {
+            Err(Error::from(ErrorKind::OptimisticLock { file_id, expected_version: version, real_version: stored.version }))
+        }
+        _ => Ok(stored),
+    }
+}
+
+fn read_file(repo: &Repository, source: &DirectoryFileSource, file: &RepositoryFile, with_content: bool) -> Result<File, Error> {
+    let header: FileHeader = from_slice(repo.read_header(source, file)?.as_slice())?;
+    let content = if with_content {
+        Some(repo.read_content(source, file)?.as_slice().to_vec())
+    } else {
+        None
+    };
+    Ok(File::from_header(descriptor(file), header, content))
+}
+
+fn descriptor(file: &RepositoryFile) -> FileDescriptor {
+    FileDescriptor { repo: file.repository_id, id: file.id, version: file.version }
+}
+
+fn serialize_header(header: &FileHeader) -> Result<Vec<u8>, HttpError> {
+    to_vec(header).map_err(|e| HttpError::bad_request(format!("Could not serialize file header: {}", e)))
+}
+
+/// Failed attempts to open a repository are counted per client address, requests without one per repository.
+fn client_address(req: &mut Request) -> Option<String> {
+    req.remote_addr().map(|addr| addr.ip().to_string())
+}
+
+fn repo_id(req: &mut Request) -> Result<RepoId, HttpError> {
+    uuid_param(req, "repo_id")
+}
+
+fn file_id(req: &mut Request) -> Result<FileId, HttpError> {
+    uuid_param(req, "file_id")
+}
+
+fn uuid_param(req: &mut Request, name: &str) -> Result<Uuid, HttpError> {
+    match req.param(name) {
+        Some(id) => Uuid::parse_str(id).map_err(|_| HttpError::bad_request(format!("Could not parse route parameter '{}'", name))),
+        None => Err(HttpError::bad_request(format!("Missing route parameter '{}'", name))),
+    }
+}
+
+/// Answers with the result of an access to an opened repository, `None` means the token was invalid.
+fn repository_response<T: Serialize>(result: Result<Option<T>, Error>) -> Result<Response, HttpError> {
+    match result {
+        Ok(Some(value)) => json_response(&value),
+        Ok(None) => Err(HttpError::unauthorized("Token invalid")),
+        Err(e) => error_response(e),
+    }
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<Response, HttpError> {
+    match to_string(value) {
+        Ok(body) => status_response(StatusCode::OK, body),
+        Err(e) => Err(format!("Could not serialize response: {}", e).into()),
+    }
+}
+
+fn error_response(error: Error) -> Result<Response, HttpError> {
+    if let Some(LockoutError::Locked { retry_after, .. }) = error.downcast_ref::<LockoutError>() {
+        return Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header_str_value(header::ACCESS_CONTROL_ALLOW_ORIGIN, ALLOWED_ORIGIN)?
+            .header_str_value(header::RETRY_AFTER, &retry_after.to_string())?
+            .body(error.to_string())
+            .build();
+    }
+    let status = if is_not_found(&error) {
+        StatusCode::NOT_FOUND
+    } else {
+        match error.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::RepositoryNotFound(_)) => StatusCode::NOT_FOUND,
+            Some(ErrorKind::WrongRepository { .. }) => StatusCode::NOT_FOUND,
+            Some(ErrorKind::UnknownUser(_)) => StatusCode::NOT_FOUND,
+            Some(ErrorKind::InvalidPassword) => StatusCode::UNAUTHORIZED,
+            Some(ErrorKind::PrimaryUser(_)) => StatusCode::FORBIDDEN,
+            Some(ErrorKind::OptimisticLock { .. }) => StatusCode::CONFLICT,
+            Some(ErrorKind::UserExists(_)) | Some(ErrorKind::LastUser(_)) | Some(ErrorKind::KeyRotationPending) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    };
+    if status == StatusCode::INTERNAL_SERVER_ERROR {
+        error!("Request failed: {}", error);
+    }
+    status_response(status, error.to_string())
+}
+
+fn status_response(status: StatusCode, body: String) -> Result<Response, HttpError> {
+    Response::builder()
+        .status(status)
+        .header_str_value(header::ACCESS_CONTROL_ALLOW_ORIGIN, ALLOWED_ORIGIN)?
+        .body(body)
+        .build()
+}
@@ -0,0 +1,325 @@
+//! Sessions granting access to opened repositories.
+//!
+//! Opening a repository starts a session identified by its `AccessToken`, it remembers the user who opened it.
+//! A session ends when it was idle for too long, when its absolute lifetime is over or on logout.
+//! Refreshing replaces the token with a new one, the absolute lifetime still counts from the start of the session.
+//! The key of a repository is kept as long as one of its sessions is alive.
+//! Once the last one ends the key is dropped, which wipes it from memory, and an audit event is logged.
+
+use dto::{AccessToken, RepoId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Logging target of the events an operator might have to audit.
+pub const AUDIT_TARGET: &str = "audit";
+
+/// The `[session]` table of `idnadrev.toml`, all values in seconds.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// A session ends if it was not used for this long
+    pub idle_timeout: u64,
+    /// A session ends this long after the repository was opened, whether it is used or not
+    pub absolute_timeout: u64,
+    /// How often ended sessions are removed in the background
+    pub sweep_interval: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            idle_timeout: 20 * 60,
+            absolute_timeout: 8 * 60 * 60,
+            sweep_interval: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum EndReason {
+    Logout,
+    IdleTimeout,
+    AbsoluteTimeout,
+}
+
+impl fmt::Display for EndReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match *self {
+            EndReason::Logout => "logout",
+            EndReason::IdleTimeout => "idle timeout",
+            EndReason::AbsoluteTimeout => "absolute timeout",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+struct Session {
+    repository: RepoId,
+    user: String,
+    started: Instant,
+    last_access: Instant,
+}
+
+struct Sessions<T> {
+    sessions: HashMap<AccessToken, Session>,
+    opened: HashMap<RepoId, Arc<T>>,
+}
+
+/// Keeps the sessions and the opened repositories they grant access to.
+pub struct SessionStore<T> {
+    config: SessionConfig,
+    inner: Mutex<Sessions<T>>,
+}
+
+impl<T> SessionStore<T> {
+    pub fn new(config: SessionConfig) -> Self {
+        SessionStore { config, inner: Mutex::new(Sessions { sessions: HashMap::new(), opened: HashMap::new() }) }
+    }
+
+    /// Starts a session of the user for the repository.
+    /// If it is already opened by another session, `opened` is dropped and the kept one is shared.
+    pub fn start(&self, id: RepoId, user: &str, opened: T) -> AccessToken {
+        self.start_at(id, user, opened, Instant::now())
+    }
+
+    /// Returns the repository if the token grants access to it and marks the session as used.
+    pub fn access(&self, id: RepoId, token: &AccessToken) -> Option<Arc<T>> {
+        self.access_at(id, token, Instant::now())
+    }
+
+    /// The user who started the session, it is not checked whether the session is still valid, see `access`.
+    pub fn user(&self, id: RepoId, token: &AccessToken) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        match inner.sessions.get(token) {
+            Some(session) if session.repository == id => Some(session.user.clone()),
+            _ => None,
+        }
+    }
+
+    /// Ends the session and starts a new one with the same absolute lifetime, returns None if the token is not valid.
+    pub fn refresh(&self, id: RepoId, token: &AccessToken) -> Option<AccessToken> {
+        self.refresh_at(id, token, Instant::now())
+    }
+
+    /// Ends the session, returns false if the token did not grant access to the repository.
+    pub fn logout(&self, id: RepoId, token: &AccessToken) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.sessions.get(token).map_or(false, |session| session.repository == id) {
+            return false;
+        }
+        inner.end(token, EndReason::Logout);
+        true
+    }
+
+    /// Ends all sessions of the user for the repository, returns how many were ended.
+    pub fn logout_user(&self, id: RepoId, user: &str) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let tokens: Vec<AccessToken> = inner.sessions.iter()
+            .filter(|&(_, session)| session.repository == id && session.user == user)
+            .map(|(token, _)| token.clone())
+            .collect();
+        for token in &tokens {
+            inner.end(token, EndReason::Logout);
+        }
+        tokens.len()
+    }
+
+    /// Removes all sessions that are over, returns how many were removed.
+    pub fn sweep(&self) -> usize {
+        self.sweep_at(Instant::now())
+    }
+
+    pub fn is_opened(&self, id: RepoId) -> bool {
+        self.inner.lock().unwrap().opened.contains_key(&id)
+    }
+
+    fn start_at(&self, id: RepoId, user: &str, opened: T, now: Instant) -> AccessToken {
+        let mut inner = self.inner.lock().unwrap();
+        inner.opened.entry(id).or_insert_with(|| Arc::new(opened));
+        let token = AccessToken::new();
+        inner.sessions.insert(token.clone(), Session { repository: id, user: user.to_string(), started: now, last_access: now });
+        token
+    }
+
+    fn access_at(&self, id: RepoId, token: &AccessToken, now: Instant) -> Option<Arc<T>> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        let ended = match inner.sessions.get_mut(token) {
+            Some(ref session) if session.repository != id => return None,
+            Some(session) => match self.end_reason(session, now) {
+                Some(reason) => reason,
+                None => {
+                    session.last_access = now;
+                    return inner.opened.get(&id).cloned();
+                }
+            },
+            None => return None,
+        };
+        inner.end(token, ended);
+        None
+    }
+
+    fn refresh_at(&self, id: RepoId, token: &AccessToken, now: Instant) -> Option<AccessToken> {
+        self.access_at(id, token, now)?;
+        let mut inner = self.inner.lock().unwrap();
+        let Session { user, started, .. } = inner.sessions.remove(token)?;
+        let refreshed = AccessToken::new();
+        inner.sessions.insert(refreshed.clone(), Session { repository: id, user, started, last_access: now });
+        Some(refreshed)
+    }
+
+    fn sweep_at(&self, now: Instant) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let ended: Vec<(AccessToken, EndReason)> = inner.sessions.iter()
+            .filter_map(|(token, session)| self.end_reason(session, now).map(|reason| (token.clone(), reason)))
+            .collect();
+        for &(ref token, reason) in &ended {
+            inner.end(token, reason);
+        }
+        ended.len()
+    }
+
+    fn end_reason(&self, session: &Session, now: Instant) -> Option<EndReason> {
+        if now.duration_since(session.started) >= Duration::from_secs(self.config.absolute_timeout) {
+            Some(EndReason::AbsoluteTimeout)
+        } else if now.duration_since(session.last_access) >= Duration::from_secs(self.config.idle_timeout) {
+            Some(EndReason::IdleTimeout)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Send + 'static> SessionStore<T> {
+    /// Sweeps the sessions every `sweep_interval` on a background thread, until the store is dropped.
+    pub fn spawn_sweeper(store: &Arc<SessionStore<T>>) {
+        let interval = Duration::from_secs(store.config.sweep_interval.max(1));
+        let store: Weak<SessionStore<T>> = Arc::downgrade(store);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match store.upgrade() {
+                Some(store) => {
+                    let swept = store.sweep();
+                    if swept > 0 {
+                        debug!("Removed {} ended sessions", swept);
+                    }
+                }
+                None => return,
+            }
+        });
+    }
+}
+
+impl<T> Sessions<T> {
+    fn end(&mut self, token: &AccessToken, reason: EndReason) {
+        let id = match self.sessions.remove(token) {
+            Some(session) => session.repository,
+            None => return,
+        };
+        if self.sessions.values().any(|session| session.repository == id) {
+            return;
+        }
+        if self.opened.remove(&id).is_some() {
+            info!(target: AUDIT_TARGET, "Released the key of repository {} from memory after the last session ended by {}", id, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn store() -> SessionStore<&'static str> {
+        SessionStore::new(SessionConfig { idle_timeout: 10, absolute_timeout: 100, sweep_interval: 1 })
+    }
+
+    #[test]
+    fn test_access() {
+        let store = store();
+        let id = Uuid::new_v4();
+        let now = Instant::now();
+        let token = store.start_at(id, "alice", "repo", now);
+
+        assert_eq!(Some(Arc::new("repo")), store.access_at(id, &token, now));
+        assert_eq!(None, store.access_at(Uuid::new_v4(), &token, now));
+        assert_eq!(None, store.access_at(id, &AccessToken::new(), now));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let store = store();
+        let id = Uuid::new_v4();
+        let now = Instant::now();
+        let token = store.start_at(id, "alice", "repo", now);
+
+        assert!(store.access_at(id, &token, now + Duration::from_secs(9)).is_some());
+        assert!(store.access_at(id, &token, now + Duration::from_secs(18)).is_some());
+        assert!(store.access_at(id, &token, now + Duration::from_secs(28)).is_none());
+        assert!(!store.is_opened(id));
+    }
+
+    #[test]
+    fn test_absolute_timeout_survives_refresh() {
+        let store = store();
+        let id = Uuid::new_v4();
+        let now = Instant::now();
+        let mut token = store.start_at(id, "alice", "repo", now);
+
+        for second in (5..100).step_by(5) {
+            token = store.refresh_at(id, &token, now + Duration::from_secs(second)).unwrap();
+        }
+        assert!(store.is_opened(id));
+        assert!(store.access_at(id, &token, now + Duration::from_secs(100)).is_none());
+        assert!(!store.is_opened(id));
+    }
+
+    #[test]
+    fn test_refresh_invalidates_old_token() {
+        let store = store();
+        let id = Uuid::new_v4();
+        let now = Instant::now();
+        let token = store.start_at(id, "alice", "repo", now);
+
+        let refreshed = store.refresh_at(id, &token, now).unwrap();
+        assert!(store.access_at(id, &token, now).is_none());
+        assert!(store.access_at(id, &refreshed, now).is_some());
+        assert!(store.refresh_at(id, &token, now).is_none());
+    }
+
+    #[test]
+    fn test_key_released_with_last_session() {
+        let store = store();
+        let id = Uuid::new_v4();
+        let now = Instant::now();
+        let first = store.start_at(id, "alice", "repo", now);
+        let second = store.start_at(id, "alice", "other", now + Duration::from_secs(5));
+
+        assert_eq!(Some(Arc::new("repo")), store.access_at(id, &second, now + Duration::from_secs(5)));
+        assert!(store.logout(id, &first));
+        assert!(!store.logout(id, &first));
+        assert!(store.is_opened(id));
+
+        assert_eq!(1, store.sweep_at(now + Duration::from_secs(15)));
+        assert!(!store.is_opened(id));
+    }
+
+    #[test]
+    fn test_logout_user() {
+        let store = store();
+        let id = Uuid::new_v4();
+        let now = Instant::now();
+        let first = store.start_at(id, "alice", "repo", now);
+        let second = store.start_at(id, "alice", "repo", now);
+        let other = store.start_at(id, "bob", "repo", now);
+
+        assert_eq!(0, store.logout_user(Uuid::new_v4(), "alice"));
+        assert_eq!(2, store.logout_user(id, "alice"));
+        assert!(store.access_at(id, &first, now).is_none());
+        assert!(store.access_at(id, &second, now).is_none());
+        assert!(store.access_at(id, &other, now).is_some());
+    }
+}
@@ -0,0 +1,189 @@
+//! The repositories served and the ones currently opened.
+
+use dto::{AccessToken, RepoId, RepositoryDescriptor};
+use failure::Error;
+use lockout::{AttemptTracker, LockoutConfig};
+use repository::crypt::default_hash_parameters;
+use repository::error::ErrorKind;
+use repository::files::DirectoryFileSource;
+use repository::pb::file::{EncryptionType, PasswordHashType};
+use repository::repository::{self as repo, Repository};
+use rest_in_rust::*;
+use session::{SessionConfig, SessionStore, AUDIT_TARGET};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// Used for the key slots of all created repositories and added users.
+const HASH_TYPE: PasswordHashType = PasswordHashType::Argon2i;
+
+pub struct ServerState {
+    /// One source per configured repository folder, new repositories are created in the first one.
+    sources: Vec<RwLock<DirectoryFileSource>>,
+    sessions: Arc<SessionStore<OpenedRepository>>,
+    attempts: AttemptTracker,
+}
+
+struct OpenedRepository {
+    repository: Repository,
+    /// Index into `ServerState::sources`
+    source: usize,
+}
+
+impl ServerState {
+    /// Opens the repository folders, loads the failed attempts to open them and starts sweeping ended sessions in the background.
+    pub fn new(folders: &[PathBuf], session_config: SessionConfig, lockout_config: LockoutConfig) -> Result<Self, Error> {
+        let mut sources = Vec::new();
+        for folder in folders {
+            sources.push(RwLock::new(DirectoryFileSource::new(folder.clone())?));
+        }
+        let sessions = Arc::new(SessionStore::new(session_config));
+        SessionStore::spawn_sweeper(&sessions);
+        let attempts = AttemptTracker::new(lockout_config)?;
+        Ok(ServerState { sources, sessions, attempts })
+    }
+
+    pub fn list_repositories(&self) -> Result<Vec<RepositoryDescriptor>, Error> {
+        let mut repositories = Vec::new();
+        for source in &self.sources {
+            let source = source.read().unwrap();
+            repositories.extend(repo::list_repositories(&*source)?.into_iter().map(RepositoryDescriptor::from));
+        }
+        Ok(repositories)
+    }
+
+    /// Creates the repository in the first folder with a key slot for the user and opens it.
+    pub fn create_repository(&self, name: &str, user_name: &str, pw: &[u8], enc_type: EncryptionType) -> Result<(RepoId, AccessToken), Error> {
+        let source = match self.sources.first() {
+            Some(source) => source,
+            None => return Err(format_err!("No repository folder configured")),
+        };
+        let repository = {
+            let mut source = source.write().unwrap();
+            repo::create_repository_as(&mut *source, name, user_name, pw, enc_type, HASH_TYPE, default_hash_parameters(HASH_TYPE))?
+        };
+        let id = repository.id;
+        let token = self.register(repository, 0, user_name);
+        Ok((id, token))
+    }
+
+    /// Opens the repository with the password of the user, the returned token grants access to it until its session ends.
+    /// Fails with `LockoutError::Locked` if there were too many failed attempts from the client.
+    /// Requests without a client address are counted per repository.
+    pub fn open_repository(&self, id: RepoId, user_name: &str, pw: &[u8], client: Option<&str>) -> Result<AccessToken, Error> {
+        let attempt = self.attempts.begin(id, client)?;
+        match self.find_and_open(id, user_name, pw) {
+            Ok(token) => {
+                self.attempts.succeeded(attempt);
+                Ok(token)
+            }
+            Err(e) => {
+                if let Some(ErrorKind::InvalidPassword) = e.downcast_ref::<ErrorKind>() {
+                    self.attempts.failed(attempt, &format!("{} from {}", e, client.unwrap_or("an unknown address")));
+                } else {
+                    self.attempts.cancelled(attempt);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Tries the folders in order. A folder that fails for another reason than a wrong password,
+    /// e.g. because of a damaged file, is logged and skipped, so it cannot hide the repository in the next folders.
+    fn find_and_open(&self, id: RepoId, user_name: &str, pw: &[u8]) -> Result<AccessToken, Error> {
+        for (index, source) in self.sources.iter().enumerate() {
+            let opened = {
+                let source = source.read().unwrap();
+                repo::users::open_repository_as(&*source, id, user_name, pw)
+            };
+            match opened {
+                Ok(repository) => return Ok(self.register(repository, index, user_name)),
+                Err(e) => match e.downcast_ref::<ErrorKind>() {
+                    Some(ErrorKind::InvalidPassword) => return Err(e),
+                    Some(ErrorKind::RepositoryNotFound(_)) => continue,
+                    _ => warn!("Skipping repository folder {} while opening repository {}: {}", index, id, e),
+                }
+            }
+        }
+        Err(Error::from(ErrorKind::RepositoryNotFound(id)))
+    }
+
+    /// Adds a key slot for the user to the repository, returns None if the token does not grant access to it.
+    /// `current_pw` is the password of the user who opened the session.
+    pub fn add_user(&self, id: RepoId, token: &AccessToken, current_pw: &[u8], user_name: &str, pw: &[u8]) -> Result<Option<()>, Error> {
+        let caller = match self.sessions.user(id, token) {
+            Some(caller) => caller,
+            None => return Ok(None),
+        };
+        self.write_repository(id, token, |repository, source| {
+            repo::users::add_user(source, repository, &caller, current_pw, user_name, pw, HASH_TYPE, default_hash_parameters(HASH_TYPE))
+        })
+    }
+
+    /// Removes the key slot of the user and ends all of their sessions, returns None if the token does not grant access to it.
+    /// `current_pw` is the password of the user who opened the session.
+    pub fn remove_user(&self, id: RepoId, token: &AccessToken, current_pw: &[u8], user_name: &str) -> Result<Option<()>, Error> {
+        let caller = match self.sessions.user(id, token) {
+            Some(caller) => caller,
+            None => return Ok(None),
+        };
+        let removed = self.write_repository(id, token, |repository, source| {
+            repo::users::remove_user(source, repository, &caller, current_pw, user_name)
+        })?;
+        if removed.is_some() {
+            let ended = self.sessions.logout_user(id, user_name);
+            info!(target: AUDIT_TARGET, "Removed user {} from repository {} and ended {} of their sessions", user_name, id, ended);
+        }
+        Ok(removed)
+    }
+
+    /// Ends the session of the token, returns false if it did not grant access to the repository.
+    pub fn close_repository(&self, id: RepoId, token: &AccessToken) -> bool {
+        self.sessions.logout(id, token)
+    }
+
+    /// Replaces the token with a new one, returns None if it did not grant access to the repository.
+    pub fn refresh_session(&self, id: RepoId, token: &AccessToken) -> Option<AccessToken> {
+        self.sessions.refresh(id, token)
+    }
+
+    /// Passes the opened repository and its source to `callback`, returns None if the token does not grant access to it.
+    pub fn read_repository<R, F>(&self, id: RepoId, token: &AccessToken, callback: F) -> Result<Option<R>, Error>
+        where F: FnOnce(&Repository, &DirectoryFileSource) -> Result<R, Error> {
+        match self.sessions.access(id, token) {
+            Some(opened) => {
+                let source = self.sources[opened.source].read().unwrap();
+                callback(&opened.repository, &*source).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Like `read_repository` but with the source locked for writing.
+    pub fn write_repository<R, F>(&self, id: RepoId, token: &AccessToken, callback: F) -> Result<Option<R>, Error>
+        where F: FnOnce(&Repository, &mut DirectoryFileSource) -> Result<R, Error> {
+        match self.sessions.access(id, token) {
+            Some(opened) => {
+                let mut source = self.sources[opened.source].write().unwrap();
+                callback(&opened.repository, &mut *source).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn register(&self, mut repository: Repository, source: usize, user_name: &str) -> AccessToken {
+        if let Err(e) = repository.lock_secrets() {
+            warn!("Could not lock the keys of repository {} into memory: {}", repository.id, e);
+        }
+        self.sessions.start(repository.id, user_name, OpenedRepository { repository, source })
+    }
+}
+
+impl<'a> FromRequestAsRef<'a> for ServerState {
+    fn from_req_as_ref(req: &'a mut Request) -> Result<&'a Self, HttpError> {
+        let state: Option<&ServerState> = req.get_state();
+        match state {
+            Some(state) => Ok(state),
+            None => Err("No server state present".into())
+        }
+    }
+}
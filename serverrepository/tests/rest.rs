@@ -0,0 +1,228 @@
+extern crate http;
+extern crate rest_in_rust;
+extern crate serde;
+extern crate serde_json;
+extern crate serverrepository;
+extern crate tempdir;
+
+use http::request::Builder as RequestBuilder;
+use http::{Method, StatusCode, Uri};
+use rest_in_rust::*;
+use serde_json::to_string;
+use serverrepository::dto::*;
+use serverrepository::lockout::LockoutConfig;
+use serverrepository::rest;
+use serverrepository::session::SessionConfig;
+use serverrepository::state::ServerState;
+use std::str::FromStr;
+use tempdir::TempDir;
+
+fn setup() -> (TempDir, ServerTester) {
+    let temp = TempDir::new("rest-test").unwrap();
+    let lockout = LockoutConfig { base_delay: 60, state_file: temp.path().join(".lockout.json"), ..LockoutConfig::default() };
+    let state = ServerState::new(&[temp.path().to_path_buf()], SessionConfig::default(), lockout).unwrap();
+
+    let addr = "127.0.0.1:8091".parse().unwrap();
+    let s = Server::new(addr, rest::router());
+    s.add_state(state);
+    let tester = s.start_testing();
+    (temp, tester)
+}
+
+fn request(method: Method, path: &str, token: Option<&AccessToken>, body: Option<String>) -> ::http::Request<Body> {
+    let mut builder = RequestBuilder::new();
+    builder.uri(Uri::from_str(path).unwrap()).method(method);
+    if let Some(token) = token {
+        let (name, value) = token.to_header();
+        builder.header(name, value);
+    }
+    match body {
+        Some(body) => builder.header(http::header::CONTENT_TYPE, "application/json").body(body.into()).unwrap(),
+        None => builder.body(().into()).unwrap(),
+    }
+}
+
+fn send<B: ::serde::Serialize>(method: Method, path: &str, token: Option<&AccessToken>, body: Option<&B>, server: &ServerTester) -> Response {
+    let body = body.map(|b| to_string(b).unwrap());
+    server.handle(request(method, path, token, body))
+}
+
+fn ok<T: ::serde::de::DeserializeOwned>(response: Response) -> T {
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+    response.body().to_json().unwrap()
+}
+
+fn get<T: ::serde::de::DeserializeOwned>(path: &str, token: Option<&AccessToken>, server: &ServerTester) -> T {
+    ok(send::<()>(Method::GET, path, token, None, server))
+}
+
+fn post<T: ::serde::de::DeserializeOwned, B: ::serde::Serialize>(path: &str, token: Option<&AccessToken>, body: &B, server: &ServerTester) -> T {
+    ok(send(Method::POST, path, token, Some(body), server))
+}
+
+fn create_open_repo() -> (TempDir, ServerTester, RepoId, AccessToken) {
+    let (temp, server) = setup();
+    let vec: Vec<RepositoryDescriptor> = get("/rest/v1/repo", None, &server);
+    assert!(vec.is_empty());
+
+    let cmd = CreateRepository { name: "repo".to_string(), user_name: "none".to_string(), encryption: EncryptionType::RingChachaPoly1305, password: vec![1, 2, 3] };
+    let created: RepositoryDto = post("/rest/v1/repo", None, &cmd, &server);
+    let vec: Vec<RepositoryDescriptor> = get("/rest/v1/repo", None, &server);
+    assert_eq!(vec![RepositoryDescriptor { id: created.id, name: "repo".into() }], vec);
+
+    let cmd = OpenRepository { id: created.id, user_name: "none".to_string(), password: vec![1, 2, 3] };
+    let token: AccessToken = post(&format!("/rest/v1/repo/{}", created.id), None, &cmd, &server);
+    (temp, server, created.id, token)
+}
+
+fn create_file(repo_id: &RepoId, token: &AccessToken, server: &ServerTester) -> FileDescriptor {
+    let mut file = File::new(repo_id, "test", "DOCUMENT", Some(vec![1, 2, 3, 4, 5, 6]));
+    file.tags = vec!["hallo".to_string()];
+    post(&format!("/rest/v1/repo/{}/file", repo_id), Some(token), &file, server)
+}
+
+#[test]
+fn good_case_create_open_close() {
+    let (_temp, server, repo_id, token) = create_open_repo();
+
+    let response = send::<()>(Method::POST, &format!("/rest/v1/repo/{}/close", repo_id), Some(&token), None, &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+
+    let response = send::<()>(Method::GET, &format!("/rest/v1/repo/{}", repo_id), Some(&token), None, &server);
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", response);
+}
+
+#[test]
+fn refresh_replaces_token() {
+    let (_temp, server, repo_id, token) = create_open_repo();
+
+    let refreshed: AccessToken = post::<AccessToken, ()>(&format!("/rest/v1/repo/{}/refresh", repo_id), Some(&token), &(), &server);
+    assert_ne!(token, refreshed);
+
+    let response = send::<()>(Method::GET, &format!("/rest/v1/repo/{}", repo_id), Some(&token), None, &server);
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", response);
+    let page: Page = get(&format!("/rest/v1/repo/{}", repo_id), Some(&refreshed), &server);
+    assert!(page.files.is_empty());
+}
+
+#[test]
+fn open_with_wrong_password() {
+    let (_temp, server, repo_id, _) = create_open_repo();
+
+    let cmd = OpenRepository { id: repo_id, user_name: "none".to_string(), password: vec![3, 2, 1] };
+    let response = send(Method::POST, &format!("/rest/v1/repo/{}", repo_id), None, Some(&cmd), &server);
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", response);
+
+    let cmd = OpenRepository { id: repo_id, user_name: "none".to_string(), password: vec![1, 2, 3] };
+    let response = send(Method::POST, &format!("/rest/v1/repo/{}", repo_id), None, Some(&cmd), &server);
+    assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status(), "{:?}", response);
+    assert!(response.headers().contains_key(http::header::RETRY_AFTER));
+}
+
+#[test]
+fn list_files_invalid_token() {
+    let (_temp, server, repo_id, token) = create_open_repo();
+    create_file(&repo_id, &token, &server);
+
+    let response = send::<()>(Method::GET, &format!("/rest/v1/repo/{}/document", repo_id), Some(&AccessToken::new()), None, &server);
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", response);
+}
+
+#[test]
+fn good_case_create_file_and_list() {
+    let (_temp, server, repo_id, token) = create_open_repo();
+    let descriptor = create_file(&repo_id, &token, &server);
+    assert_eq!(repo_id, descriptor.repo);
+
+    let page: Page = get(&format!("/rest/v1/repo/{}/document/", repo_id), Some(&token), &server);
+    assert_eq!(Some(1), page.total);
+    assert_eq!(0, page.offset);
+    assert_eq!(1, page.limit);
+    assert_eq!(None, page.previous);
+    assert_eq!(None, page.next);
+
+    assert_eq!(1, page.files.len());
+    let ref file = page.files[0];
+    assert_eq!(descriptor.id, file.id);
+    assert_eq!("test", file.name);
+    assert_eq!("DOCUMENT", file.file_type);
+    assert_eq!(vec!["hallo".to_string()], file.tags);
+    assert_eq!(None, file.content);
+
+    let page: Page = get(&format!("/rest/v1/repo/{}/task", repo_id), Some(&token), &server);
+    assert!(page.files.is_empty());
+    let page: Page = get(&format!("/rest/v1/repo/{}", repo_id), Some(&token), &server);
+    assert_eq!(1, page.files.len());
+}
+
+#[test]
+fn read_update_and_delete_file() {
+    let (_temp, server, repo_id, token) = create_open_repo();
+    let descriptor = create_file(&repo_id, &token, &server);
+    let path = format!("/rest/v1/repo/{}/file/{}", repo_id, descriptor.id);
+
+    let file: File = get(&path, Some(&token), &server);
+    assert_eq!(None, file.content);
+    let mut file: File = get(&format!("{}/full", path), Some(&token), &server);
+    assert_eq!(Some(vec![1, 2, 3, 4, 5, 6]), file.content);
+
+    file.name = "renamed".into();
+    file.content = None;
+    let updated: FileDescriptor = post(&path, Some(&token), &file, &server);
+    assert_eq!(descriptor.version + 1, updated.version);
+    let read: File = get(&format!("{}/full", path), Some(&token), &server);
+    assert_eq!("renamed", read.name);
+    assert_eq!(updated.version, read.version);
+    assert_eq!(Some(vec![1, 2, 3, 4, 5, 6]), read.content);
+
+    let response = send(Method::POST, &path, Some(&token), Some(&file), &server);
+    assert_eq!(StatusCode::CONFLICT, response.status(), "{:?}", response);
+
+    let response = send::<()>(Method::DELETE, &path, Some(&token), None, &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+    let response = send::<()>(Method::GET, &path, Some(&token), None, &server);
+    assert_eq!(StatusCode::NOT_FOUND, response.status(), "{:?}", response);
+    let page: Page = get(&format!("/rest/v1/repo/{}", repo_id), Some(&token), &server);
+    assert!(page.files.is_empty());
+}
+
+#[test]
+fn add_and_remove_user() {
+    let (_temp, server, repo_id, token) = create_open_repo();
+    let descriptor = create_file(&repo_id, &token, &server);
+
+    let cmd = AddUser { user_name: "bob".to_string(), password: vec![4, 5, 6], current_password: vec![3, 2, 1] };
+    let response = send(Method::POST, &format!("/rest/v1/user/{}", repo_id), Some(&token), Some(&cmd), &server);
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", response);
+    let cmd = AddUser { user_name: "bob".to_string(), password: vec![4, 5, 6], current_password: vec![1, 2, 3] };
+    let response = send(Method::POST, &format!("/rest/v1/user/{}", repo_id), Some(&token), Some(&cmd), &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+    let response = send(Method::POST, &format!("/rest/v1/user/{}", repo_id), Some(&token), Some(&cmd), &server);
+    assert_eq!(StatusCode::CONFLICT, response.status(), "{:?}", response);
+    let users: Vec<String> = get(&format!("/rest/v1/user/{}", repo_id), Some(&token), &server);
+    assert_eq!(vec!["none".to_string(), "bob".to_string()], users);
+
+    let cmd = OpenRepository { id: repo_id, user_name: "bob".to_string(), password: vec![4, 5, 6] };
+    let bob: AccessToken = post(&format!("/rest/v1/repo/{}", repo_id), None, &cmd, &server);
+    let file: File = get(&format!("/rest/v1/repo/{}/file/{}/full", repo_id, descriptor.id), Some(&bob), &server);
+    assert_eq!(Some(vec![1, 2, 3, 4, 5, 6]), file.content);
+
+    let cmd = RemoveUser { current_password: vec![4, 5, 6] };
+    let response = send(Method::DELETE, &format!("/rest/v1/user/{}/none", repo_id), Some(&bob), Some(&cmd), &server);
+    assert_eq!(StatusCode::FORBIDDEN, response.status(), "{:?}", response);
+    let cmd = RemoveUser { current_password: vec![4, 5, 6] };
+    let response = send(Method::DELETE, &format!("/rest/v1/user/{}/none", repo_id), Some(&token), Some(&cmd), &server);
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", response);
+    let cmd = RemoveUser { current_password: vec![1, 2, 3] };
+    let response = send(Method::DELETE, &format!("/rest/v1/user/{}/none", repo_id), Some(&token), Some(&cmd), &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+    let response = send::<()>(Method::GET, &format!("/rest/v1/user/{}", repo_id), Some(&token), None, &server);
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", response);
+    let cmd = RemoveUser { current_password: vec![4, 5, 6] };
+    let response = send(Method::DELETE, &format!("/rest/v1/user/{}/bob", repo_id), Some(&bob), Some(&cmd), &server);
+    assert_eq!(StatusCode::CONFLICT, response.status(), "{:?}", response);
+
+    let cmd = OpenRepository { id: repo_id, user_name: "none".to_string(), password: vec![1, 2, 3] };
+    let response = send(Method::POST, &format!("/rest/v1/repo/{}", repo_id), None, Some(&cmd), &server);
+    assert_eq!(StatusCode::UNAUTHORIZED, response.status(), "{:?}", response);
+}
@@ -0,0 +1,27 @@
+use std::fs::File;
+use std::io::{Read, Result as IoResult};
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+pub struct Config {
+    /// The build folder of the idnadrev2 UI
+    pub ui_dir: PathBuf,
+    /// Address the UI is served on
+    #[serde(default = "default_ui_address")]
+    pub ui_address: String,
+}
+
+fn default_ui_address() -> String {
+    "127.0.0.1:8080".into()
+}
+
+pub fn read_config(path: &Path) -> IoResult<Config> {
+    if !path.exists() {
+        return Err(::std::io::Error::new(::std::io::ErrorKind::Other, format!("File {:?} does not exist ", path)));
+    }
+    let mut f = File::open(path)?;
+    let mut s = String::new();
+    f.read_to_string(&mut s)?;
+
+    ::toml::from_str(s.as_str()).map_err(|e| ::std::io::Error::new(::std::io::ErrorKind::Other, format!("Could not parse {:?}: {} ", path, e)))
+}
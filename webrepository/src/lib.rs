@@ -0,0 +1,18 @@
+extern crate http;
+#[macro_use]
+extern crate log;
+extern crate notify;
+extern crate rest_in_rust;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate sha1;
+extern crate toml;
+
+#[cfg(test)]
+extern crate tempdir;
+
+pub mod config;
+pub mod manifest;
+pub mod state;
+pub mod ui;
@@ -1,3 +1,45 @@
+extern crate log4rs;
+#[macro_use]
+extern crate log;
+extern crate rest_in_rust;
+extern crate webrepository;
+
+use rest_in_rust::*;
+use std::path::Path;
+use std::time::Duration;
+use webrepository::config;
+use webrepository::state::UiState;
+use webrepository::ui;
+
 fn main() {
-    println!("Hello, world!");
+    if let Err(e) = log4rs::init_file("config/log4rs.yaml", Default::default()) {
+        eprintln!("Could not initialize logging: {}", e);
+    }
+
+    let config = match config::read_config(Path::new("idnadrev.toml")) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Could not read configuration file: '{}'", e);
+            return;
+        }
+    };
+
+    let ui_state = match UiState::new(config.ui_dir.clone(), Duration::from_secs(2)) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Could not watch the UI folder {:?}: {}", config.ui_dir, e);
+            return;
+        }
+    };
+    let addr = match config.ui_address.parse() {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Invalid UI address '{}': {}", config.ui_address, e);
+            return;
+        }
+    };
+
+    let s = Server::new(addr, ui::router());
+    s.add_state(ui_state);
+    s.start_http();
 }
@@ -0,0 +1,94 @@
+//! The offline manifest (`/manifest.appcache`) listing every file of the UI bundle.
+//! It contains a hash over all file contents, so browsers fetch the bundle again whenever a file changed.
+
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Creates the manifest for all files below `root`, client side routes fall back to `/index.html`.
+pub fn create_manifest(root: &Path) -> io::Result<String> {
+    let paths = list_files(root)?;
+    let hash = hash_files(&paths)?;
+
+    let mut manifest = String::new();
+    manifest.push_str("CACHE MANIFEST\n");
+    manifest.push_str("#");
+    manifest.push_str(&hash);
+    manifest.push_str("\nCACHE:\n");
+    for path in &paths {
+        manifest.push_str(&url_path(root, path));
+        manifest.push_str("\n");
+    }
+    manifest.push_str("\nFALLBACK:\n");
+    manifest.push_str("/ /index.html\n");
+
+    manifest.push_str("\nNETWORK:\n*\n");
+    Ok(manifest)
+}
+
+/// Lists all files below the folder, sorted so the hash does not depend on the order the file system returns.
+pub fn list_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in root.read_dir()? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            files.append(&mut list_files(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The url a file below `root` is served under, e.g. `/static/js/main.js`.
+pub fn url_path(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let mut url = String::new();
+    for component in relative.components() {
+        url.push('/');
+        url.push_str(&component.as_os_str().to_string_lossy());
+    }
+    url
+}
+
+/// Hex encoded sha1 of the data.
+pub fn hash(data: &[u8]) -> String {
+    Sha1::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hash_files(paths: &[PathBuf]) -> io::Result<String> {
+    let mut sha1 = Sha1::new();
+    for path in paths {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        sha1.input(&buf);
+    }
+    Ok(sha1.result().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, File};
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_create_manifest() {
+        let temp = TempDir::new("manifest").unwrap();
+        let root = temp.path();
+        create_dir(root.join("subdir")).unwrap();
+        File::create(root.join("file1.txt")).unwrap();
+        File::create(root.join("subdir").join("file2.txt")).unwrap();
+
+        let manifest = create_manifest(root).unwrap();
+        assert!(manifest.starts_with("CACHE MANIFEST\n#"));
+        assert!(manifest.contains("\n/file1.txt\n/subdir/file2.txt\n"), "{}", manifest);
+        assert!(manifest.contains("FALLBACK:\n/ /index.html\n"));
+
+        File::create(root.join("file1.txt")).unwrap().write_all(b"changed").unwrap();
+        assert_ne!(manifest, create_manifest(root).unwrap());
+    }
+}
@@ -0,0 +1,65 @@
+use manifest::create_manifest;
+use notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use rest_in_rust::*;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+pub struct UiState {
+    pub ui_dir: PathBuf,
+    manifest: RwLock<Option<String>>,
+    /// Kept alive to receive the changes of the UI folder
+    _watcher: Mutex<RecommendedWatcher>,
+    changes: Mutex<Receiver<DebouncedEvent>>,
+}
+
+impl UiState {
+    /// Watches the UI folder, changes are noticed after `delay`.
+    pub fn new(ui_dir: PathBuf, delay: Duration) -> Result<Self, ::notify::Error> {
+        let (sender, changes) = channel();
+        let mut watcher = watcher(sender, delay)?;
+        watcher.watch(&ui_dir, RecursiveMode::Recursive)?;
+        Ok(UiState {
+            ui_dir,
+            manifest: RwLock::new(None),
+            _watcher: Mutex::new(watcher),
+            changes: Mutex::new(changes),
+        })
+    }
+
+    /// The offline manifest, it is created again once a file of the UI changed.
+    pub fn manifest(&self) -> io::Result<String> {
+        if self.has_changed() {
+            *self.manifest.write().unwrap() = None;
+        }
+        if let Some(ref manifest) = *self.manifest.read().unwrap() {
+            return Ok(manifest.clone());
+        }
+
+        let manifest = create_manifest(&self.ui_dir)?;
+        *self.manifest.write().unwrap() = Some(manifest.clone());
+        Ok(manifest)
+    }
+
+    fn has_changed(&self) -> bool {
+        let changes = self.changes.lock().unwrap();
+        let mut changed = false;
+        while let Ok(event) = changes.try_recv() {
+            debug!("UI folder changed: {:?}", event);
+            changed = true;
+        }
+        changed
+    }
+}
+
+impl<'a> FromRequestAsRef<'a> for UiState {
+    fn from_req_as_ref(req: &'a mut Request) -> Result<&'a Self, HttpError> {
+        let state: Option<&UiState> = req.get_state();
+        match state {
+            Some(state) => Ok(state),
+            None => Err("No UI state present".into())
+        }
+    }
+}
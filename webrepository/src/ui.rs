@@ -0,0 +1,164 @@
+//! Serves the build folder of the idnadrev2 UI.
+//!
+//! Every response carries an ETag, a request with a matching `If-None-Match` is answered with 304 NOT MODIFIED.
+//! Files below `/static` have the hash of their content in the name, so browsers may cache them forever.
+//! Everything else has to be revalidated, including `index.html` served for a client side route below `/static`.
+//! Unknown paths without a file extension are client side routes of the UI and get `index.html`.
+
+use http::header;
+use http::StatusCode;
+use manifest::hash;
+use rest_in_rust::*;
+use state::UiState;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+const INDEX: &str = "index.html";
+const CACHE_FOREVER: &str = "public, max-age=31536000, immutable";
+const REVALIDATE: &str = "no-cache";
+
+pub fn router() -> Router {
+    let mut router = Router::new();
+    router.get("/manifest.appcache", manifest);
+    router.get("/", index);
+    router.get("/*any", any);
+    router
+}
+
+pub fn manifest(req: &mut Request) -> Result<Response, HttpError> {
+    let if_none_match = if_none_match(req);
+    let ui_state = UiState::from_req_as_ref(req)?;
+
+    match ui_state.manifest() {
+        Ok(manifest) => content_response(manifest.into_bytes(), "text/cache-manifest", REVALIDATE, if_none_match),
+        Err(e) => {
+            error!("Could not create the offline manifest: {}", e);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, format!("Could not create the offline manifest: {}", e))
+        }
+    }
+}
+
+pub fn index(req: &mut Request) -> Result<Response, HttpError> {
+    let if_none_match = if_none_match(req);
+    let ui_state = UiState::from_req_as_ref(req)?;
+
+    file_response(&ui_state.ui_dir.join(INDEX), REVALIDATE, if_none_match)
+}
+
+pub fn any(req: &mut Request) -> Result<Response, HttpError> {
+    let url_path = req.param("any").unwrap_or("").to_string();
+    let if_none_match = if_none_match(req);
+    let ui_state = UiState::from_req_as_ref(req)?;
+
+    match resolve(&ui_state.ui_dir, &url_path) {
+        Some(Resolved::File(path)) => {
+            let cache_control = if url_path.trim_start_matches('/').starts_with("static/") { CACHE_FOREVER } else { REVALIDATE };
+            file_response(&path, cache_control, if_none_match)
+        }
+        Some(Resolved::Route(path)) => file_response(&path, REVALIDATE, if_none_match),
+        None => text_response(StatusCode::NOT_FOUND, format!("{} not found", url_path)),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Resolved {
+    /// A file inside the UI folder
+    File(PathBuf),
+    /// A route of the UI, answered with `index.html`
+    Route(PathBuf),
+}
+
+/// Finds the file for the url path. Paths leaving the UI folder are never resolved,
+/// every segment has to be a plain name and the file found has to be inside the UI folder after resolving links.
+/// A path that does not exist and has no file extension is a route of the UI, it resolves to `index.html`.
+pub fn resolve(ui_dir: &Path, url_path: &str) -> Option<Resolved> {
+    let segments: Vec<&str> = url_path.split('/').filter(|segment| !segment.is_empty()).collect();
+    if !segments.iter().all(|segment| is_plain_name(segment)) {
+        return None;
+    }
+    let path = segments.iter().fold(ui_dir.to_path_buf(), |path, segment| path.join(segment));
+    if path.is_file() {
+        return if is_inside(ui_dir, &path) { Some(Resolved::File(path)) } else { None };
+    }
+    match segments.last() {
+        Some(last) if last.contains('.') => None,
+        _ => Some(Resolved::Route(ui_dir.join(INDEX))),
+    }
+}
+
+/// A single normal path component, no drive or root on any platform.
+fn is_plain_name(segment: &str) -> bool {
+    if segment.contains(':') || segment.contains('\\') {
+        return false;
+    }
+    let mut components = Path::new(segment).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => true,
+        _ => false,
+    }
+}
+
+fn is_inside(ui_dir: &Path, path: &Path) -> bool {
+    match (ui_dir.canonicalize(), path.canonicalize()) {
+        (Ok(ui_dir), Ok(path)) => path.starts_with(ui_dir),
+        _ => false,
+    }
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" => "application/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "appcache" => "text/cache-manifest",
+        "ico" => "image/x-icon",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "eot" => "application/vnd.ms-fontobject",
+        _ => "application/octet-stream",
+    }
+}
+
+fn if_none_match(req: &mut Request) -> Option<String> {
+    req.header_str("if-none-match").map(|value| value.to_string())
+}
+
+fn file_response(path: &Path, cache_control: &str, if_none_match: Option<String>) -> Result<Response, HttpError> {
+    let mut content = Vec::new();
+    if let Err(e) = File::open(path).and_then(|mut file| file.read_to_end(&mut content)) {
+        warn!("Could not read {:?}: {}", path, e);
+        return text_response(StatusCode::NOT_FOUND, format!("Could not read {}", path.display()));
+    }
+    content_response(content, content_type(path), cache_control, if_none_match)
+}
+
+fn content_response(content: Vec<u8>, content_type: &str, cache_control: &str, if_none_match: Option<String>) -> Result<Response, HttpError> {
+    let etag = format!("\"{}\"", hash(&content));
+    let not_modified = if_none_match.map_or(false, |value| value.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"));
+    let (status, body) = if not_modified { (StatusCode::NOT_MODIFIED, Vec::new()) } else { (StatusCode::OK, content) };
+
+    Response::builder()
+        .status(status)
+        .header_str_value(header::CONTENT_TYPE, content_type)?
+        .header_str_value(header::ETAG, &etag)?
+        .header_str_value(header::CACHE_CONTROL, cache_control)?
+        .body(body)
+        .build()
+}
+
+fn text_response(status: StatusCode, body: String) -> Result<Response, HttpError> {
+    Response::builder()
+        .status(status)
+        .header_str_value(header::CONTENT_TYPE, "text/plain; charset=utf-8")?
+        .body(body)
+        .build()
+}
@@ -0,0 +1,120 @@
+extern crate http;
+extern crate rest_in_rust;
+extern crate tempdir;
+extern crate webrepository;
+
+use http::header;
+use http::request::Builder as RequestBuilder;
+use http::{Method, StatusCode, Uri};
+use rest_in_rust::*;
+use std::fs::{create_dir_all, File};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use tempdir::TempDir;
+use webrepository::state::UiState;
+use webrepository::ui;
+
+fn write(path: &Path, content: &[u8]) {
+    File::create(path).unwrap().write_all(content).unwrap();
+}
+
+fn setup() -> (TempDir, ServerTester) {
+    let temp = TempDir::new("ui-test").unwrap();
+    let root = temp.path();
+    create_dir_all(root.join("static").join("js")).unwrap();
+    write(&root.join("index.html"), b"<html></html>");
+    write(&root.join("favicon.ico"), &[0, 0, 1, 0]);
+    write(&root.join("static").join("js").join("main.1234.js"), b"console.log('idnadrev')");
+
+    let state = UiState::new(root.to_path_buf(), Duration::from_millis(100)).unwrap();
+    let addr = "127.0.0.1:8092".parse().unwrap();
+    let s = Server::new(addr, ui::router());
+    s.add_state(state);
+    let tester = s.start_testing();
+    (temp, tester)
+}
+
+fn get(path: &str, if_none_match: Option<&str>, server: &ServerTester) -> Response {
+    let mut builder = RequestBuilder::new();
+    builder.uri(Uri::from_str(path).unwrap()).method(Method::GET);
+    if let Some(etag) = if_none_match {
+        builder.header(header::IF_NONE_MATCH, etag);
+    }
+    server.handle(builder.body(().into()).unwrap())
+}
+
+fn header_value(response: &Response, name: header::HeaderName) -> String {
+    response.headers().get(name).unwrap().to_str().unwrap().to_string()
+}
+
+#[test]
+fn serves_files_with_mime_type_and_cache_headers() {
+    let (_temp, server) = setup();
+
+    let response = get("/static/js/main.1234.js", None, &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+    assert_eq!("application/javascript; charset=utf-8", header_value(&response, header::CONTENT_TYPE));
+    assert!(header_value(&response, header::CACHE_CONTROL).contains("immutable"));
+
+    let response = get("/favicon.ico", None, &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+    assert_eq!("image/x-icon", header_value(&response, header::CONTENT_TYPE));
+    assert_eq!("no-cache", header_value(&response, header::CACHE_CONTROL));
+}
+
+#[test]
+fn etag_answers_not_modified() {
+    let (_temp, server) = setup();
+
+    let response = get("/index.html", None, &server);
+    let etag = header_value(&response, header::ETAG);
+
+    let response = get("/index.html", Some(&etag), &server);
+    assert_eq!(StatusCode::NOT_MODIFIED, response.status(), "{:?}", response);
+    let response = get("/index.html", Some("\"other\""), &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+}
+
+#[test]
+fn client_routes_fall_back_to_index() {
+    let (_temp, server) = setup();
+
+    let index = get("/", None, &server);
+    assert_eq!(StatusCode::OK, index.status(), "{:?}", index);
+    let route = get("/task/4711", None, &server);
+    assert_eq!(StatusCode::OK, route.status(), "{:?}", route);
+    assert_eq!("text/html; charset=utf-8", header_value(&route, header::CONTENT_TYPE));
+    assert_eq!(header_value(&index, header::ETAG), header_value(&route, header::ETAG));
+
+    let response = get("/static/js/missing.js", None, &server);
+    assert_eq!(StatusCode::NOT_FOUND, response.status(), "{:?}", response);
+    let response = get("/../Cargo.toml", None, &server);
+    assert_eq!(StatusCode::NOT_FOUND, response.status(), "{:?}", response);
+    let response = get("/C:/index.html", None, &server);
+    assert_eq!(StatusCode::NOT_FOUND, response.status(), "{:?}", response);
+
+    let route = get("/static/settings", None, &server);
+    assert_eq!(StatusCode::OK, route.status(), "{:?}", route);
+    assert_eq!("no-cache", header_value(&route, header::CACHE_CONTROL));
+}
+
+#[test]
+fn manifest_changes_with_files() {
+    let (temp, server) = setup();
+
+    let response = get("/manifest.appcache", None, &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+    assert_eq!("text/cache-manifest", header_value(&response, header::CONTENT_TYPE));
+    let etag = header_value(&response, header::ETAG);
+    assert_eq!(StatusCode::NOT_MODIFIED, get("/manifest.appcache", Some(&etag), &server).status());
+
+    write(&temp.path().join("static").join("js").join("main.1234.js"), b"console.log('changed')");
+    thread::sleep(Duration::from_secs(1));
+
+    let response = get("/manifest.appcache", Some(&etag), &server);
+    assert_eq!(StatusCode::OK, response.status(), "{:?}", response);
+    assert_ne!(etag, header_value(&response, header::ETAG));
+}